@@ -0,0 +1,137 @@
+use anyhow::{anyhow, Result};
+use libp2p::PeerId;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+/// Where runtime bans are kept so they survive a restart.
+pub const DEFAULT_BANS_PATH: &str = ".hive/banned_peers.json";
+
+/// A change to the access lists, requested through the API.
+#[derive(Debug, Clone, Copy)]
+pub enum AccessChange {
+    Ban(PeerId),
+    Unban(PeerId),
+    Allow(PeerId),
+    Disallow(PeerId),
+}
+
+/// Which peers may connect, send us tasks and receive ours. A denied peer is
+/// refused even when it is also allowed.
+#[derive(Debug)]
+pub struct AccessList {
+    pub allow: Option<BTreeSet<PeerId>>, // None lets everyone in
+    pub deny: BTreeSet<PeerId>, // From the configuration
+    pub banned: BTreeSet<PeerId>, // At runtime; the only list written to disk
+    bans_path: PathBuf,
+}
+
+impl AccessList {
+    /// Combines the configured lists with the bans persisted at `bans_path`.
+    /// An empty `allow` leaves the node open.
+    pub fn load(allow: &[PeerId], deny: &[PeerId], bans_path: &Path) -> Result<Self> {
+        let mut list = Self {
+            allow: (!allow.is_empty()).then(|| allow.iter().copied().collect()),
+            deny: deny.iter().copied().collect(),
+            banned: BTreeSet::new(),
+            bans_path: bans_path.to_path_buf(),
+        };
+        if bans_path.exists() {
+            let banned: Vec<String> = serde_json::from_slice(&std::fs::read(bans_path)?)
+                .map_err(|e| anyhow!("Invalid ban list {}: {}", bans_path.display(), e))?;
+            for peer in banned {
+                list.banned.insert(peer.parse().map_err(|e| anyhow!("Invalid banned peer {}: {}", peer, e))?);
+            }
+        }
+        Ok(list)
+    }
+
+    pub fn permits(&self, peer: &PeerId) -> bool {
+        !self.deny.contains(peer) && !self.banned.contains(peer) && self.allow.as_ref().is_none_or(|allow| allow.contains(peer))
+    }
+
+    /// Applies a change; bans and unbans are written through to disk.
+    /// Peers denied by the configuration stay denied.
+    pub fn apply(&mut self, change: AccessChange) -> Result<()> {
+        match change {
+            AccessChange::Ban(peer) => {
+                self.banned.insert(peer);
+                self.save()
+            }
+            AccessChange::Unban(peer) => {
+                if self.deny.contains(&peer) {
+                    return Err(anyhow!("Peer {} is denied by the configuration", peer));
+                }
+                self.banned.remove(&peer);
+                self.save()
+            }
+            AccessChange::Allow(peer) => {
+                let allow = self.allow.as_mut().ok_or_else(|| anyhow!("No allowlist configured; every peer is already allowed"))?;
+                allow.insert(peer);
+                Ok(())
+            }
+            AccessChange::Disallow(peer) => {
+                let allow = self.allow.as_mut().ok_or_else(|| anyhow!("No allowlist configured; ban the peer instead"))?;
+                allow.remove(&peer);
+                Ok(())
+            }
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        if let Some(parent) = self.bans_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let banned: Vec<String> = self.banned.iter().map(|p| p.to_string()).collect();
+        std::fs::write(&self.bans_path, serde_json::to_vec_pretty(&banned)?)?;
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "allow": self.allow.as_ref().map(|allow| allow.iter().map(|p| p.to_string()).collect::<Vec<_>>()),
+            "deny": self.deny.iter().map(|p| p.to_string()).collect::<Vec<_>>(),
+            "banned": self.banned.iter().map(|p| p.to_string()).collect::<Vec<_>>(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bans_survive_a_reload() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("bans.json");
+        let (friend, foe) = (PeerId::random(), PeerId::random());
+
+        let mut list = AccessList::load(&[], &[], &path)?;
+        assert!(list.permits(&foe));
+        list.apply(AccessChange::Ban(foe))?;
+        assert!(list.apply(AccessChange::Allow(friend)).is_err());
+
+        let list = AccessList::load(&[friend, foe], &[], &path)?;
+        assert!(list.permits(&friend));
+        assert!(!list.permits(&foe));
+        assert!(!list.permits(&PeerId::random()));
+        Ok(())
+    }
+
+    #[test]
+    fn configured_denies_are_not_written_as_bans() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("bans.json");
+        let (configured, banned) = (PeerId::random(), PeerId::random());
+
+        let mut list = AccessList::load(&[], &[configured], &path)?;
+        list.apply(AccessChange::Ban(banned))?;
+        assert!(list.apply(AccessChange::Unban(configured)).is_err());
+        assert!(!list.permits(&configured));
+
+        // Dropping the peer from the configuration lets it back in
+        let list = AccessList::load(&[], &[], &path)?;
+        assert!(list.permits(&configured));
+        assert!(!list.permits(&banned));
+        Ok(())
+    }
+}
@@ -0,0 +1,347 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::StreamExt;
+use hive_core::Cid;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use crate::s3::{S3Config, S3Store};
+use crate::storage::{shard_key, write_atomic, ContentReader, CHUNK_SIZE};
+
+/// Where a node keeps its content, chosen in the config file, e.g.
+/// `{"backend": "fs", "root": ".hive/storage"}`, `{"backend": "memory"}` or
+/// `{"backend": "s3", "endpoint": "http://127.0.0.1:9000", "bucket": "hive"}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum StorageBackend {
+    Fs {
+        #[serde(default = "default_root")]
+        root: PathBuf,
+    },
+    Memory, // Gone when the process exits; for tests and ephemeral nodes
+    S3(S3Config),
+}
+
+fn default_root() -> PathBuf {
+    PathBuf::from(".hive/storage")
+}
+
+impl Default for StorageBackend {
+    fn default() -> Self {
+        Self::Fs { root: default_root() }
+    }
+}
+
+impl StorageBackend {
+    pub async fn open(&self) -> Result<Arc<dyn BlobStore>> {
+        Ok(match self {
+            Self::Fs { root } => Arc::new(FsStore::open(root).await?),
+            Self::Memory => Arc::new(MemoryStore::default()),
+            Self::S3(config) => Arc::new(S3Store::open(config.clone()).await?),
+        })
+    }
+}
+
+/// Size and last use of a stored blob.
+#[derive(Debug, Clone, Copy)]
+pub struct BlobMeta {
+    pub len: u64,
+    pub modified: SystemTime, // Bumped by `touch`, for least-recently-used eviction
+}
+
+/// Flat key-value store that `Storage` keeps its blobs in. Keys are
+/// `/`-separated relative paths such as `chunks/ab/<cid>` or `pins.json`.
+/// A write replaces the whole blob at once, so readers never see half of one.
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    /// Where the blobs live, for messages.
+    fn describe(&self) -> String;
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Up to `length` bytes of a blob starting at `offset`.
+    async fn get_range(&self, key: &str, offset: u64, length: u64) -> Result<Option<Vec<u8>>>;
+
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<()>;
+
+    /// Stores everything `reader` yields. Backends that cannot stream an
+    /// upload collect it in memory first.
+    async fn put_reader(&self, key: &str, mut reader: ContentReader) -> Result<()> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await?;
+        self.put(key, data).await
+    }
+
+    /// Removes a blob; one that does not exist is not an error.
+    async fn delete(&self, key: &str) -> Result<()>;
+
+    async fn stat(&self, key: &str) -> Result<Option<BlobMeta>>;
+
+    /// Every blob whose key starts with `prefix`, in no particular order.
+    async fn list(&self, prefix: &str) -> Result<Vec<(String, BlobMeta)>>;
+
+    /// Marks a blob as used just now. Best effort: a read-only store still
+    /// serves reads.
+    async fn touch(&self, key: &str);
+
+    /// Makes finished writes survive a crash.
+    async fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Streams up to `length` bytes of a blob from `offset`, fetching one chunk's
+/// worth at a time. `None` if the blob does not exist.
+pub async fn range_reader(store: Arc<dyn BlobStore>, key: &str, offset: u64, length: u64) -> Result<Option<ContentReader>> {
+    let Some(meta) = store.stat(key).await? else {
+        return Ok(None);
+    };
+    let end = meta.len.min(offset.saturating_add(length));
+    let key = key.to_string();
+    let pieces = (offset..end).step_by(CHUNK_SIZE as usize);
+    let stream = futures::stream::iter(pieces).then(move |start| {
+        let (store, key) = (store.clone(), key.clone());
+        async move {
+            let piece = store.get_range(&key, start, CHUNK_SIZE.min(end - start)).await.map_err(std::io::Error::other)?;
+            let piece = piece.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("{} is missing", key)))?;
+            Ok::<_, std::io::Error>(bytes::Bytes::from(piece))
+        }
+    });
+    Ok(Some(Box::new(tokio_util::io::StreamReader::new(Box::pin(stream)))))
+}
+
+/// Blobs as files under a root directory, the key being the relative path.
+pub struct FsStore {
+    root: PathBuf,
+}
+
+impl FsStore {
+    /// Opens the store at `root`, creating it if needed, and moves files left
+    /// in the flat layout used before sharding into their shards.
+    pub async fn open(root: impl AsRef<Path>) -> Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        for dir in ["chunks", "manifests"] {
+            fs::create_dir_all(root.join(dir)).await?;
+            let mut entries = fs::read_dir(root.join(dir)).await?;
+            while let Some(file) = entries.next_entry().await? {
+                let Ok(cid) = file.file_name().to_string_lossy().parse::<Cid>() else {
+                    continue;
+                };
+                if file.file_type().await?.is_file() {
+                    let target = root.join(shard_key(dir, &cid));
+                    if let Some(parent) = target.parent() {
+                        fs::create_dir_all(parent).await?;
+                    }
+                    fs::rename(file.path(), target).await?;
+                }
+            }
+        }
+        Ok(Self { root })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+/// `Ok(None)` for a file that does not exist.
+fn found<T>(result: std::io::Result<T>) -> Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[async_trait]
+impl BlobStore for FsStore {
+    fn describe(&self) -> String {
+        self.root.display().to_string()
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        found(fs::read(self.path(key)).await)
+    }
+
+    async fn get_range(&self, key: &str, offset: u64, length: u64) -> Result<Option<Vec<u8>>> {
+        let Some(mut file) = found(fs::File::open(self.path(key)).await)? else {
+            return Ok(None);
+        };
+        file.seek(std::io::SeekFrom::Start(offset)).await?;
+        let mut data = Vec::new();
+        file.take(length).read_to_end(&mut data).await?;
+        Ok(Some(data))
+    }
+
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<()> {
+        write_atomic(&self.path(key), &data).await
+    }
+
+    /// Streams into a temporary file and renames it into place, like `write_atomic`.
+    async fn put_reader(&self, key: &str, mut reader: ContentReader) -> Result<()> {
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let tmp = path.with_extension(format!("tmp-{}", uuid::Uuid::new_v4()));
+        let mut file = fs::File::create(&tmp).await?;
+        let written = async {
+            tokio::io::copy(&mut reader, &mut file).await?;
+            file.flush().await
+        }
+        .await;
+        drop(file);
+        if let Err(e) = written {
+            let _ = fs::remove_file(&tmp).await;
+            return Err(e.into());
+        }
+        fs::rename(&tmp, path).await?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        found(fs::remove_file(self.path(key)).await)?;
+        Ok(())
+    }
+
+    async fn stat(&self, key: &str) -> Result<Option<BlobMeta>> {
+        let Some(meta) = found(fs::metadata(self.path(key)).await)? else {
+            return Ok(None);
+        };
+        Ok(meta.is_file().then_some(BlobMeta { len: meta.len(), modified: meta.modified()? }))
+    }
+
+    /// Walks the whole tree; temporary files of writes in progress are skipped.
+    async fn list(&self, prefix: &str) -> Result<Vec<(String, BlobMeta)>> {
+        let mut blobs = Vec::new();
+        let mut dirs = vec![(self.root.clone(), String::new())];
+        while let Some((dir, dir_key)) = dirs.pop() {
+            let mut entries = fs::read_dir(&dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let key = format!("{}{}", dir_key, name);
+                let meta = entry.metadata().await?;
+                if meta.is_dir() {
+                    dirs.push((entry.path(), format!("{}/", key)));
+                } else if meta.is_file() && !name.contains(".tmp-") && key.starts_with(prefix) {
+                    blobs.push((key, BlobMeta { len: meta.len(), modified: meta.modified()? }));
+                }
+            }
+        }
+        Ok(blobs)
+    }
+
+    async fn touch(&self, key: &str) {
+        let path = self.path(key);
+        let _ = tokio::task::spawn_blocking(move || std::fs::File::options().write(true).open(path)?.set_modified(SystemTime::now())).await;
+    }
+
+    /// Syncs every directory, so that renamed-in blobs survive a crash.
+    async fn flush(&self) -> Result<()> {
+        let mut dirs = vec![self.root.clone()];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = fs::read_dir(&dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                if entry.file_type().await?.is_dir() {
+                    dirs.push(entry.path());
+                }
+            }
+            fs::File::open(dir).await?.sync_all().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Blobs in a map, lost when the process exits.
+#[derive(Default)]
+pub struct MemoryStore {
+    blobs: Mutex<BTreeMap<String, (Vec<u8>, SystemTime)>>,
+}
+
+#[async_trait]
+impl BlobStore for MemoryStore {
+    fn describe(&self) -> String {
+        "in-memory storage".to_string()
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.blobs.lock().unwrap().get(key).map(|(data, _)| data.clone()))
+    }
+
+    async fn get_range(&self, key: &str, offset: u64, length: u64) -> Result<Option<Vec<u8>>> {
+        Ok(self.blobs.lock().unwrap().get(key).map(|(data, _)| {
+            let start = (offset as usize).min(data.len());
+            let end = (offset.saturating_add(length) as usize).min(data.len());
+            data[start..end].to_vec()
+        }))
+    }
+
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<()> {
+        self.blobs.lock().unwrap().insert(key.to_string(), (data, SystemTime::now()));
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.blobs.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    async fn stat(&self, key: &str) -> Result<Option<BlobMeta>> {
+        Ok(self.blobs.lock().unwrap().get(key).map(|(data, modified)| BlobMeta { len: data.len() as u64, modified: *modified }))
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<(String, BlobMeta)>> {
+        let blobs = self.blobs.lock().unwrap();
+        Ok(blobs
+            .range(prefix.to_string()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, (data, modified))| (key.clone(), BlobMeta { len: data.len() as u64, modified: *modified }))
+            .collect())
+    }
+
+    async fn touch(&self, key: &str) {
+        if let Some((_, modified)) = self.blobs.lock().unwrap().get_mut(key) {
+            *modified = SystemTime::now();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn backends_agree_on_reads_writes_and_listing() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let stores: Vec<Arc<dyn BlobStore>> = vec![Arc::new(FsStore::open(dir.path()).await?), Arc::new(MemoryStore::default())];
+        for store in stores {
+            let data: Vec<u8> = (0..(CHUNK_SIZE + 10) as usize).map(|i| (i % 251) as u8).collect();
+            store.put("chunks/ab/x", data.clone()).await?;
+            store.put("pins.json", b"{}".to_vec()).await?;
+            assert_eq!(store.get("chunks/ab/x").await?, Some(data.clone()));
+            assert_eq!(store.get_range("chunks/ab/x", 5, 10).await?, Some(data[5..15].to_vec()));
+            assert_eq!(store.get_range("chunks/ab/x", CHUNK_SIZE, 100).await?, Some(data[CHUNK_SIZE as usize..].to_vec()));
+            assert_eq!(store.stat("chunks/ab/x").await?.map(|m| m.len), Some(data.len() as u64));
+
+            let mut streamed = Vec::new();
+            range_reader(store.clone(), "chunks/ab/x", 3, u64::MAX).await?.unwrap().read_to_end(&mut streamed).await?;
+            assert_eq!(streamed, data[3..]);
+            store.put_reader("chunks/cd/y", Box::new(&b"streamed"[..])).await?;
+            assert_eq!(store.get("chunks/cd/y").await?, Some(b"streamed".to_vec()));
+
+            let mut keys: Vec<String> = store.list("chunks/").await?.into_iter().map(|(key, _)| key).collect();
+            keys.sort();
+            assert_eq!(keys, ["chunks/ab/x", "chunks/cd/y"]);
+            store.delete("chunks/ab/x").await?;
+            store.delete("chunks/ab/x").await?;
+            assert_eq!(store.get("chunks/ab/x").await?, None);
+            assert!(store.stat("chunks/ab/x").await?.is_none());
+            assert!(range_reader(store.clone(), "chunks/ab/x", 0, 1).await?.is_none());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,159 @@
+use anyhow::{anyhow, Result};
+use hive_core::NodeCapability;
+use libp2p::identity::{Keypair, PublicKey};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A capability record signed by the node it describes, so it can be relayed
+/// by anyone without being forged. The signature also covers when it was
+/// issued, so a relayed old record cannot replace a newer one.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SignedCapability {
+    pub record: Vec<u8>, // JSON-encoded `NodeCapability`
+    pub issued_at: u64, // Milliseconds since the Unix epoch, by the signer's clock
+    pub public_key: Vec<u8>, // Protobuf-encoded signer key
+    pub signature: Vec<u8>,
+}
+
+impl SignedCapability {
+    pub fn sign(keypair: &Keypair, capability: &NodeCapability) -> Result<Self> {
+        let record = serde_json::to_vec(capability)?;
+        let issued_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+        let signature = keypair.sign(&signed_bytes(issued_at, &record))?;
+        Ok(Self {
+            record,
+            issued_at,
+            public_key: keypair.public().encode_protobuf(),
+            signature,
+        })
+    }
+
+    /// Checks the signature and returns the signer with the record.
+    pub fn verify(&self) -> Result<(PeerId, NodeCapability)> {
+        let public_key = PublicKey::try_decode_protobuf(&self.public_key)?;
+        if !public_key.verify(&signed_bytes(self.issued_at, &self.record), &self.signature) {
+            return Err(anyhow!("Bad capability signature"));
+        }
+        Ok((public_key.to_peer_id(), serde_json::from_slice(&self.record)?))
+    }
+
+    pub fn issued_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.issued_at)
+    }
+}
+
+/// The issue time, big-endian, followed by the record.
+fn signed_bytes(issued_at: u64, record: &[u8]) -> Vec<u8> {
+    [&issued_at.to_be_bytes()[..], record].concat()
+}
+
+/// Probes this machine. Anything that cannot be read on this platform is left
+/// at zero or `None` rather than guessed.
+pub fn probe(models_dir: &Path) -> NodeCapability {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").unwrap_or_default();
+    let accelerators = compiled_accelerators();
+    NodeCapability {
+        device_type: if accelerators.iter().any(|a| a == "cuda") { "gpu_server" } else { "cpu_server" }.to_string(),
+        available_vram: 0, // No portable way to ask the driver yet
+        flops_score: 0.0, // Not benchmarked yet
+        can_run_docker: Path::new("/var/run/docker.sock").exists(),
+        cpu_cores: std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        total_ram: meminfo_bytes(&meminfo, "MemTotal"),
+        available_ram: meminfo_bytes(&meminfo, "MemAvailable"),
+        memory_limit: cgroup_memory_limit(),
+        cpu_limit: cgroup_cpu_limit(),
+        accelerators,
+        models: list_models(models_dir),
+    }
+}
+
+fn compiled_accelerators() -> Vec<String> {
+    let mut accelerators = Vec::new();
+    if cfg!(feature = "cuda") {
+        accelerators.push("cuda".to_string());
+    }
+    if cfg!(feature = "accelerate") {
+        accelerators.push("accelerate".to_string());
+    }
+    if cfg!(feature = "mkl") {
+        accelerators.push("mkl".to_string());
+    }
+    accelerators
+}
+
+/// Reads a `Key:   1234 kB` line from /proc/meminfo.
+fn meminfo_bytes(meminfo: &str, key: &str) -> u64 {
+    meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix(':'))
+        .and_then(|rest| rest.split_whitespace().next()?.parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+        .unwrap_or(0)
+}
+
+/// cgroup v2 `memory.max`, falling back to v1 `memory.limit_in_bytes`.
+fn cgroup_memory_limit() -> Option<u64> {
+    let raw = std::fs::read_to_string("/sys/fs/cgroup/memory.max")
+        .or_else(|_| std::fs::read_to_string("/sys/fs/cgroup/memory/memory.limit_in_bytes"))
+        .ok()?;
+    // "max" means unlimited; v1 reports unlimited as a huge page-aligned number
+    raw.trim().parse::<u64>().ok().filter(|limit| *limit < u64::MAX / 2)
+}
+
+/// cgroup v2 `cpu.max` ("<quota> <period>"), falling back to v1 cfs files.
+fn cgroup_cpu_limit() -> Option<f32> {
+    let (quota, period) = match std::fs::read_to_string("/sys/fs/cgroup/cpu.max") {
+        Ok(raw) => {
+            let mut parts = raw.split_whitespace();
+            (parts.next()?.to_string(), parts.next()?.to_string())
+        }
+        Err(_) => (
+            std::fs::read_to_string("/sys/fs/cgroup/cpu/cpu.cfs_quota_us").ok()?,
+            std::fs::read_to_string("/sys/fs/cgroup/cpu/cpu.cfs_period_us").ok()?,
+        ),
+    };
+    // "max" (v2) and -1 (v1) both mean no quota
+    let quota = quota.trim().parse::<f32>().ok().filter(|q| *q > 0.0)?;
+    let period = period.trim().parse::<f32>().ok().filter(|p| *p > 0.0)?;
+    Some(quota / period)
+}
+
+/// Model files in `models_dir`; tokenizers and other JSON sidecars are skipped.
+fn list_models(models_dir: &Path) -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir(models_dir) else {
+        return Vec::new();
+    };
+    let mut models: Vec<String> = entries
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().map(|t| t.is_file()).unwrap_or(false))
+        .map(|e| e.file_name().to_string_lossy().to_string())
+        .filter(|name| !name.ends_with(".json"))
+        .collect();
+    models.sort();
+    models
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tampered_capability_is_rejected() -> Result<()> {
+        let keypair = Keypair::generate_ed25519();
+        let mut signed = SignedCapability::sign(&keypair, &probe(Path::new("missing")))?;
+        assert_eq!(signed.verify()?.0, keypair.public().to_peer_id());
+
+        let mut forged: NodeCapability = serde_json::from_slice(&signed.record)?;
+        forged.cpu_cores += 64;
+        signed.record = serde_json::to_vec(&forged)?;
+        assert!(signed.verify().is_err());
+
+        // Backdating (or postdating) a record breaks the signature too
+        let mut signed = SignedCapability::sign(&keypair, &probe(Path::new("missing")))?;
+        signed.issued_at += 1;
+        assert!(signed.verify().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,77 @@
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::path::Path;
+use crate::blobstore::StorageBackend;
+
+/// Optional agent settings file. CLI flags are merged on top of it.
+pub const DEFAULT_CONFIG_PATH: &str = ".hive/config.json";
+
+/// Settings read from the agent config file. Every entry is optional.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct AgentConfig {
+    /// Multiaddrs ending in `/p2p/<PeerId>` to join the DHT through
+    pub bootstrap: Vec<String>,
+    pub mdns: Option<bool>,
+    /// Seconds between Kademlia random walks
+    pub random_walk_secs: Option<u64>,
+    /// Named hives to join
+    pub hives: Vec<String>,
+    /// Path to a pre-shared swarm key; makes the hive private
+    pub swarm_key: Option<String>,
+    /// Multiaddrs to listen on, e.g. "/ip6/::/tcp/4001" or "/ip4/0.0.0.0/udp/4001/quic-v1"
+    pub listen: Vec<String>,
+    /// Multiaddrs to announce to peers in addition to the listen addresses
+    pub external: Vec<String>,
+    /// Serve relay reservations for peers behind NAT
+    pub relay_server: Option<bool>,
+    /// Relays to reserve a slot on, each ending in /p2p/<PeerId>
+    pub relays: Vec<String>,
+    /// PeerIds allowed to connect; empty lets everyone in
+    pub allow: Vec<String>,
+    /// PeerIds never to connect to, in addition to runtime bans
+    pub deny: Vec<String>,
+    /// Established connections in total and per peer
+    pub max_connections: Option<u32>,
+    pub max_connections_per_peer: Option<u32>,
+    /// Where content is kept: a directory (the default, ".hive/storage"),
+    /// memory, or an S3-compatible bucket
+    pub storage: Option<StorageBackend>,
+    /// Bytes of content to store before unpinned content is collected
+    pub storage_quota: Option<u64>,
+    /// Key file that unlocks encrypted storage; without one, the passphrase
+    /// is read from HIVE_STORAGE_PASSPHRASE
+    pub storage_key_file: Option<String>,
+}
+
+impl AgentConfig {
+    /// Loads the config file, or the defaults if it does not exist.
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let data = std::fs::read(path)?;
+        let config: Self = serde_json::from_slice(&data).map_err(|e| anyhow!("Invalid config {}: {}", path.display(), e))?;
+        // A zero interval would make the walk timer panic once the node runs
+        if config.random_walk_secs == Some(0) {
+            return Err(anyhow!("Invalid config {}: random_walk_secs must be at least 1", path.display()));
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_random_walk_interval_is_rejected() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("config.json");
+        std::fs::write(&path, r#"{ "random_walk_secs": 0 }"#)?;
+        assert!(AgentConfig::load(&path).is_err());
+        std::fs::write(&path, r#"{ "random_walk_secs": 5 }"#)?;
+        assert_eq!(AgentConfig::load(&path)?.random_walk_secs, Some(5));
+        Ok(())
+    }
+}
@@ -0,0 +1,351 @@
+use anyhow::{anyhow, Result};
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use serde::{Deserialize, Serialize};
+use futures::StreamExt;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use crate::blobstore::BlobStore;
+use crate::storage::ContentReader;
+
+/// Environment variable holding the storage passphrase. Passphrases are kept
+/// out of config files and command lines, which end up in logs and history.
+pub const PASSPHRASE_ENV: &str = "HIVE_STORAGE_PASSPHRASE";
+/// The passphrase `storage rotate-key` moves the keyring to.
+pub const NEW_PASSPHRASE_ENV: &str = "HIVE_STORAGE_NEW_PASSPHRASE";
+
+/// Plaintext bytes per sealed segment; a full chunk is four of them.
+const SEGMENT_SIZE: u64 = 64 * 1024;
+const MAGIC: &[u8; 4] = b"HVE1";
+const HEADER_LEN: u64 = 4 + 4 + 7; // Magic, data key id, nonce prefix
+const TAG_LEN: u64 = 16;
+const KEYRING: &str = "keyring.json";
+
+/// Where the secret protecting an encrypted store comes from.
+#[derive(Clone)]
+pub enum KeySource {
+    Passphrase(String),
+    File(PathBuf), // Read as raw bytes; 32 random ones are plenty
+}
+
+impl KeySource {
+    /// The key file if one is configured, otherwise the passphrase in the
+    /// environment variable `env`, if set.
+    pub fn configured(key_file: Option<&str>, env: &str) -> Option<Self> {
+        match key_file {
+            Some(path) => Some(Self::File(PathBuf::from(path))),
+            None => std::env::var(env).ok().filter(|p| !p.is_empty()).map(Self::Passphrase),
+        }
+    }
+
+    /// Derives the key that seals the data keys, with Argon2id so that a
+    /// stolen keyring does not make passphrases cheap to guess.
+    fn derive(&self, salt: &[u8]) -> Result<ChaCha20Poly1305> {
+        let secret = match self {
+            Self::Passphrase(passphrase) => passphrase.as_bytes().to_vec(),
+            Self::File(path) => std::fs::read(path).map_err(|e| anyhow!("Cannot read key file {}: {}", path.display(), e))?,
+        };
+        if secret.is_empty() {
+            return Err(anyhow!("The storage key is empty"));
+        }
+        let mut key = [0; 32];
+        Argon2::default()
+            .hash_password_into(&secret, salt, &mut key)
+            .map_err(|e| anyhow!("Key derivation failed: {}", e))?;
+        Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+    }
+}
+
+/// Stored form of the keyring.
+#[derive(Serialize, Deserialize)]
+struct KeyringFile {
+    salt: String, // Hex Argon2id salt
+    current: u32, // Data key new blobs are sealed with
+    keys: BTreeMap<u32, String>, // Hex nonce and sealed data key, by id
+    migrating: bool, // `storage encrypt` has not finished; some blobs may be plaintext
+}
+
+/// The data keys of an encrypted store, kept in `keyring.json` and sealed
+/// with the key derived from the passphrase or key file. Changing the
+/// passphrase re-seals only the keyring; rotating the data key re-seals
+/// every blob.
+pub struct Keyring {
+    store: Arc<dyn BlobStore>,
+    file: KeyringFile,
+    wrapping: ChaCha20Poly1305,
+}
+
+impl Keyring {
+    /// Whether the blobs in `store` are encrypted, or are being.
+    pub async fn exists(store: &dyn BlobStore) -> Result<bool> {
+        Ok(store.stat(KEYRING).await?.is_some())
+    }
+
+    /// Opens the keyring kept in `store`; `None` if the store is not
+    /// encrypted. A wrong passphrase or key file is an error.
+    pub async fn open(store: &Arc<dyn BlobStore>, source: &KeySource) -> Result<Option<Self>> {
+        let Some(data) = store.get(KEYRING).await? else {
+            return Ok(None);
+        };
+        let file: KeyringFile = serde_json::from_slice(&data)?;
+        let wrapping = source.derive(&hex::decode(&file.salt)?)?;
+        let keyring = Self { store: store.clone(), file, wrapping };
+        keyring.data_keys()?;
+        Ok(Some(keyring))
+    }
+
+    /// Creates the keyring of a store with one fresh data key.
+    pub async fn create(store: &Arc<dyn BlobStore>, source: &KeySource, migrating: bool) -> Result<Self> {
+        let salt: [u8; 16] = rand::random();
+        let mut keyring = Self {
+            store: store.clone(),
+            file: KeyringFile { salt: hex::encode(salt), current: 0, keys: BTreeMap::new(), migrating },
+            wrapping: source.derive(&salt)?,
+        };
+        keyring.add_key().await?;
+        Ok(keyring)
+    }
+
+    pub fn migrating(&self) -> bool {
+        self.file.migrating
+    }
+
+    pub async fn set_migrating(&mut self, migrating: bool) -> Result<()> {
+        self.file.migrating = migrating;
+        self.save().await
+    }
+
+    /// Adds a data key and makes it the one new blobs are sealed with.
+    pub async fn add_key(&mut self) -> Result<u32> {
+        let id = self.file.keys.keys().next_back().map_or(1, |id| id + 1);
+        let key: [u8; 32] = rand::random();
+        self.file.keys.insert(id, self.wrap(&key)?);
+        self.file.current = id;
+        self.save().await?;
+        Ok(id)
+    }
+
+    /// Drops every data key but the current one. Only safe once no blob is
+    /// sealed with them any more.
+    pub async fn retire_old_keys(&mut self) -> Result<()> {
+        let current = self.file.current;
+        self.file.keys.retain(|id, _| *id == current);
+        self.save().await
+    }
+
+    /// Re-seals the data keys for a new passphrase or key file.
+    pub async fn change_secret(&mut self, source: &KeySource) -> Result<()> {
+        let keys = self.data_keys()?;
+        let salt: [u8; 16] = rand::random();
+        self.wrapping = source.derive(&salt)?;
+        self.file.salt = hex::encode(salt);
+        self.file.keys = keys.iter().map(|(id, key)| Ok((*id, self.wrap(key)?))).collect::<Result<_>>()?;
+        self.save().await
+    }
+
+    /// The unlocked data keys, for sealing and opening blobs.
+    pub fn cipher(&self) -> Result<Cipher> {
+        let keys = self.data_keys()?.into_iter().map(|(id, key)| (id, ChaCha20Poly1305::new(Key::from_slice(&key)))).collect();
+        Ok(Cipher { current: self.file.current, keys })
+    }
+
+    fn wrap(&self, key: &[u8; 32]) -> Result<String> {
+        let nonce: [u8; 12] = rand::random();
+        let sealed = self.wrapping.encrypt(Nonce::from_slice(&nonce), &key[..]).map_err(|_| anyhow!("Sealing a data key failed"))?;
+        Ok(hex::encode([&nonce[..], &sealed].concat()))
+    }
+
+    fn data_keys(&self) -> Result<BTreeMap<u32, [u8; 32]>> {
+        let mut keys = BTreeMap::new();
+        for (id, sealed) in &self.file.keys {
+            let sealed = hex::decode(sealed)?;
+            if sealed.len() < 12 {
+                return Err(anyhow!("Data key {} in {} is truncated", id, KEYRING));
+            }
+            let key = self
+                .wrapping
+                .decrypt(Nonce::from_slice(&sealed[..12]), &sealed[12..])
+                .map_err(|_| anyhow!("Wrong storage passphrase or key file"))?;
+            keys.insert(*id, key.try_into().map_err(|_| anyhow!("Data key {} has the wrong length", id))?);
+        }
+        Ok(keys)
+    }
+
+    async fn save(&self) -> Result<()> {
+        self.store.put(KEYRING, serde_json::to_vec_pretty(&self.file)?).await
+    }
+}
+
+/// Unlocked data keys. A blob is sealed in segments of `SEGMENT_SIZE`
+/// plaintext bytes with a tag each, so that a range can be read without
+/// decrypting the whole blob. The header names the data key and is bound to
+/// every segment; the nonce marks the last segment, so truncation shows.
+pub struct Cipher {
+    current: u32,
+    keys: BTreeMap<u32, ChaCha20Poly1305>,
+}
+
+/// Segments a blob of `len` plaintext bytes is sealed in; empty blobs get one.
+fn segments(len: u64) -> u64 {
+    len.div_ceil(SEGMENT_SIZE).max(1)
+}
+
+impl Cipher {
+    /// The data key new blobs are sealed with.
+    pub fn current(&self) -> u32 {
+        self.current
+    }
+
+    /// Plaintext length of a sealed file of `sealed_len` bytes.
+    pub fn plaintext_len(sealed_len: u64) -> u64 {
+        let body = sealed_len.saturating_sub(HEADER_LEN);
+        body.saturating_sub(body.div_ceil(SEGMENT_SIZE + TAG_LEN).max(1) * TAG_LEN)
+    }
+
+    /// The data key a sealed header names, if it is one.
+    pub fn key_id(header: &[u8]) -> Option<u32> {
+        (header.len() >= HEADER_LEN as usize && header.starts_with(MAGIC)).then(|| u32::from_le_bytes(header[4..8].try_into().unwrap()))
+    }
+
+    fn new_header(&self) -> Vec<u8> {
+        let prefix: [u8; 7] = rand::random();
+        [&MAGIC[..], &self.current.to_le_bytes(), &prefix].concat()
+    }
+
+    fn key_for(&self, header: &[u8]) -> Result<&ChaCha20Poly1305> {
+        let id = Self::key_id(header).ok_or_else(|| anyhow!("Not a sealed blob"))?;
+        self.keys.get(&id).ok_or_else(|| anyhow!("Blob is sealed with data key {}, which the keyring does not hold", id))
+    }
+
+    fn nonce(header: &[u8], index: u64, last: bool) -> Result<Nonce> {
+        let index = u32::try_from(index).map_err(|_| anyhow!("Blob too large to seal"))?;
+        let mut nonce = [0; 12];
+        nonce[..7].copy_from_slice(&header[8..HEADER_LEN as usize]);
+        nonce[7] = last as u8;
+        nonce[8..].copy_from_slice(&index.to_be_bytes());
+        Ok(*Nonce::from_slice(&nonce))
+    }
+
+    fn seal_segment(&self, header: &[u8], index: u64, last: bool, plaintext: &[u8]) -> Result<Vec<u8>> {
+        self.key_for(header)?
+            .encrypt(&Self::nonce(header, index, last)?, Payload { msg: plaintext, aad: header })
+            .map_err(|_| anyhow!("Sealing failed"))
+    }
+
+    fn open_segment(&self, header: &[u8], index: u64, last: bool, sealed: &[u8]) -> Result<Vec<u8>> {
+        self.key_for(header)?
+            .decrypt(&Self::nonce(header, index, last)?, Payload { msg: sealed, aad: header })
+            .map_err(|_| anyhow!("Sealed data failed authentication"))
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let header = self.new_header();
+        let count = segments(plaintext.len() as u64);
+        let mut sealed = header.clone();
+        for index in 0..count {
+            let start = (index * SEGMENT_SIZE) as usize;
+            let end = (start + SEGMENT_SIZE as usize).min(plaintext.len());
+            sealed.extend(self.seal_segment(&header, index, index + 1 == count, &plaintext[start..end])?);
+        }
+        Ok(sealed)
+    }
+
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        let header = sealed.get(..HEADER_LEN as usize).ok_or_else(|| anyhow!("Not a sealed blob"))?;
+        let body = &sealed[HEADER_LEN as usize..];
+        if body.is_empty() {
+            return Err(anyhow!("Sealed blob is truncated"));
+        }
+        let count = segments(Self::plaintext_len(sealed.len() as u64));
+        let mut plaintext = Vec::with_capacity(body.len());
+        for (index, segment) in body.chunks((SEGMENT_SIZE + TAG_LEN) as usize).enumerate() {
+            let index = index as u64;
+            plaintext.extend(self.open_segment(header, index, index + 1 == count, segment)?);
+        }
+        Ok(plaintext)
+    }
+
+    /// Whether the blob at `key` is sealed with a key this cipher holds.
+    /// Only the first segment is checked.
+    pub async fn is_sealed(&self, store: &dyn BlobStore, key: &str) -> Result<bool> {
+        let Some(meta) = store.stat(key).await? else {
+            return Ok(false);
+        };
+        let head = store.get_range(key, 0, HEADER_LEN + SEGMENT_SIZE + TAG_LEN).await?.unwrap_or_default();
+        if head.len() < (HEADER_LEN + TAG_LEN) as usize {
+            return Ok(false);
+        }
+        let last = segments(Self::plaintext_len(meta.len)) == 1;
+        let (header, segment) = head.split_at(HEADER_LEN as usize);
+        Ok(self.open_segment(header, 0, last, segment).is_ok())
+    }
+
+    /// Seals everything `reader` yields into the blob at `key`, replacing it
+    /// in one write, one segment in memory at a time.
+    pub async fn seal_reader(self: &Arc<Self>, reader: ContentReader, store: &dyn BlobStore, key: &str) -> Result<()> {
+        let header = self.new_header();
+        let cipher = self.clone();
+        let first = bytes::Bytes::from(header.clone());
+        let sealed = futures::stream::try_unfold((reader, None, 0, false), move |(mut reader, pending, index, done)| {
+            let cipher = cipher.clone();
+            let header = header.clone();
+            async move {
+                if done {
+                    return Ok(None);
+                }
+                let segment = match pending {
+                    Some(segment) => segment,
+                    None => read_segment(&mut reader).await?,
+                };
+                // Read ahead, since the last segment has to be known when sealing it
+                let next = if segment.len() as u64 == SEGMENT_SIZE { read_segment(&mut reader).await? } else { Vec::new() };
+                let last = next.is_empty();
+                let sealed = cipher.seal_segment(&header, index, last, &segment).map_err(std::io::Error::other)?;
+                Ok::<_, std::io::Error>(Some((bytes::Bytes::from(sealed), (reader, Some(next), index + 1, last))))
+            }
+        });
+        let stream = futures::stream::once(async move { Ok(first) }).chain(sealed);
+        store.put_reader(key, Box::new(tokio_util::io::StreamReader::new(Box::pin(stream)))).await
+    }
+
+    /// Streams up to `length` plaintext bytes of the sealed blob at `key`
+    /// from `offset`, decrypting only the segments that cover the range. A
+    /// segment that fails authentication is an `InvalidData` read error.
+    pub async fn range_reader(self: &Arc<Self>, store: Arc<dyn BlobStore>, key: &str, offset: u64, length: u64) -> Result<ContentReader> {
+        let sealed_len = store.stat(key).await?.ok_or_else(|| anyhow!("{} is missing", key))?.len;
+        let header = store.get_range(key, 0, HEADER_LEN).await?.unwrap_or_default();
+        self.key_for(&header)?;
+
+        let size = Self::plaintext_len(sealed_len);
+        let count = segments(size);
+        let end = size.min(offset.saturating_add(length));
+        let indices = if offset < end { offset / SEGMENT_SIZE..(end - 1) / SEGMENT_SIZE + 1 } else { 0..0 };
+        let cipher = self.clone();
+        let key = key.to_string();
+        let stream = futures::stream::iter(indices).then(move |index| {
+            let (cipher, store, key, header) = (cipher.clone(), store.clone(), key.clone(), header.clone());
+            async move {
+                let start = HEADER_LEN + index * (SEGMENT_SIZE + TAG_LEN);
+                let sealed = store.get_range(&key, start, SEGMENT_SIZE + TAG_LEN).await.map_err(std::io::Error::other)?;
+                let sealed = sealed.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("{} is missing", key)))?;
+                let plaintext = cipher
+                    .open_segment(&header, index, index + 1 == count, &sealed)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+                let segment_start = index * SEGMENT_SIZE;
+                let from = offset.saturating_sub(segment_start) as usize;
+                let to = ((end - segment_start) as usize).min(plaintext.len());
+                Ok::<_, std::io::Error>(bytes::Bytes::from(plaintext).slice(from..to))
+            }
+        });
+        Ok(Box::new(tokio_util::io::StreamReader::new(Box::pin(stream))))
+    }
+}
+
+async fn read_segment(reader: &mut (impl AsyncRead + Unpin)) -> std::io::Result<Vec<u8>> {
+    let mut segment = Vec::with_capacity(SEGMENT_SIZE as usize);
+    reader.take(SEGMENT_SIZE).read_to_end(&mut segment).await?;
+    Ok(segment)
+}
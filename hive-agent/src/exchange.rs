@@ -0,0 +1,161 @@
+use anyhow::{anyhow, Result};
+use futures::StreamExt;
+use hive_core::{Chunk, Cid, Manifest};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+use tracing::info;
+use crate::node::NodeCommand;
+use crate::scheduler::Scheduler;
+use crate::storage::Storage;
+
+/// Chunk requests in flight at once.
+const PARALLEL_READS: usize = 4;
+
+/// A question to another node about content it stores. CIDs are typed, so a
+/// request naming an invalid one fails to decode and is never served.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum BlockRequest {
+    /// Do you hold this CID, and how large is it?
+    Have { cid: Cid },
+    /// How is this CID split into chunks?
+    Manifest { cid: Cid },
+    /// Send the chunk with this hash.
+    Chunk { hash: Cid },
+    /// Do you keep a replica of this CID, and at what factor?
+    Replica { cid: Cid },
+    /// Fetch this CID and pin it as one of `factor` replicas. `size` is
+    /// only a hint; the quota is checked against the manifest.
+    Replicate { cid: Cid, size: u64, factor: u32 },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum BlockResponse {
+    Size(u64),
+    Manifest(Manifest),
+    Chunk(Vec<u8>), // At most `storage::CHUNK_SIZE` bytes, well under the CBOR codec's limit
+    Replica { factor: u32 },
+    Accepted, // The replica is being fetched
+    Refused(String),
+    NotFound,
+}
+
+/// Answers a block request from local storage.
+pub async fn serve(storage: &Storage, request: BlockRequest) -> BlockResponse {
+    let result = match request {
+        BlockRequest::Have { cid } => storage.size(&cid).await.map(|size| size.map(BlockResponse::Size)),
+        BlockRequest::Manifest { cid } => storage.manifest(&cid).await.map(|m| m.map(BlockResponse::Manifest)),
+        BlockRequest::Chunk { hash } => storage.chunk(&hash).await.map(|data| data.map(BlockResponse::Chunk)),
+        // Answered by the node's `Replicator`, which knows about fetches in flight
+        BlockRequest::Replica { .. } | BlockRequest::Replicate { .. } => Ok(None),
+    };
+    result.ok().flatten().unwrap_or(BlockResponse::NotFound)
+}
+
+/// Sends one block request to `peer` and waits for the answer.
+pub async fn request(commands: &mpsc::Sender<NodeCommand>, peer: PeerId, request: BlockRequest) -> Result<BlockResponse> {
+    let (reply, rx) = oneshot::channel();
+    commands
+        .send(NodeCommand::Block { peer, request, reply })
+        .await
+        .map_err(|_| anyhow!("P2P loop closed"))?;
+    rx.await.map_err(|_| anyhow!("P2P loop closed"))?.map_err(anyhow::Error::msg)
+}
+
+/// Returns the content behind `cid`, from local storage if it is there and
+/// otherwise fetched from the connected peers that hold it.
+pub async fn get(storage: &Storage, commands: &mpsc::Sender<NodeCommand>, scheduler: &Arc<Mutex<Scheduler>>, cid: &Cid) -> Result<Vec<u8>> {
+    if let Some(data) = storage.retrieve(cid).await? {
+        return Ok(data);
+    }
+    fetch(storage, commands, scheduler, cid).await?;
+    storage.retrieve(cid).await?.ok_or_else(|| anyhow!("{} vanished from storage after fetching", cid))
+}
+
+/// The connected peers holding a CID, and its manifest as one of them sent it.
+pub struct Source {
+    holders: Vec<PeerId>,
+    pub manifest: Manifest,
+}
+
+/// Fetches `cid` into local storage; see `fetch_from`.
+pub async fn fetch(storage: &Storage, commands: &mpsc::Sender<NodeCommand>, scheduler: &Arc<Mutex<Scheduler>>, cid: &Cid) -> Result<()> {
+    let source = locate(commands, scheduler, cid).await?;
+    fetch_from(storage, commands, cid, &source).await
+}
+
+/// Asks every connected peer whether it holds `cid` and fetches the
+/// manifest from the holders, checked against the CID.
+pub async fn locate(commands: &mpsc::Sender<NodeCommand>, scheduler: &Arc<Mutex<Scheduler>>, cid: &Cid) -> Result<Source> {
+    let peers: Vec<PeerId> = scheduler.lock().unwrap().peers.keys().copied().collect();
+    let answers = futures::future::join_all(peers.iter().map(|peer| request(commands, *peer, BlockRequest::Have { cid: cid.clone() }))).await;
+    let holders: Vec<PeerId> = peers
+        .into_iter()
+        .zip(answers)
+        .filter_map(|(peer, answer)| matches!(answer, Ok(BlockResponse::Size(_))).then_some(peer))
+        .collect();
+    if holders.is_empty() {
+        return Err(anyhow!("No connected peer holds {}", cid));
+    }
+
+    let manifest = fetch_manifest(commands, &holders, cid).await?;
+    Ok(Source { holders, manifest })
+}
+
+/// Pulls the chunks of `cid` that are not stored yet from the holders in
+/// `source`, in parallel, checking each against its hash. Chunks already
+/// stored are skipped, so an interrupted fetch resumes where it stopped.
+pub async fn fetch_from(storage: &Storage, commands: &mpsc::Sender<NodeCommand>, cid: &Cid, source: &Source) -> Result<()> {
+    let Source { holders, manifest } = source;
+    let mut missing = Vec::new();
+    for hash in &manifest.chunks {
+        if !storage.has_chunk(hash).await? {
+            missing.push(hash.clone());
+        }
+    }
+    missing.sort();
+    missing.dedup();
+    info!("Fetching {} ({} bytes): {} of {} chunks from {} peer(s)", cid, manifest.size, missing.len(), manifest.chunks.len(), holders.len());
+
+    let results = futures::stream::iter(missing.into_iter().enumerate())
+        .map(|(i, hash)| {
+            async move {
+                for attempt in 0..holders.len() {
+                    let peer = holders[(i + attempt) % holders.len()];
+                    match request(commands, peer, BlockRequest::Chunk { hash: hash.clone() }).await {
+                        Ok(BlockResponse::Chunk(data)) => {
+                            let chunk = Chunk { data, hash: hash.clone() };
+                            match storage.put_chunk(&chunk).await {
+                                Ok(()) => return Ok(()),
+                                Err(e) => info!("Chunk {} from {} rejected: {}", hash, peer, e),
+                            }
+                        }
+                        Ok(_) => info!("Peer {} no longer has chunk {}", peer, hash),
+                        Err(e) => info!("Fetching chunk {} from {} failed: {}", hash, peer, e),
+                    }
+                }
+                Err(anyhow!("No holder could send chunk {} of {}", hash, cid))
+            }
+        })
+        .buffer_unordered(PARALLEL_READS)
+        .collect::<Vec<Result<()>>>()
+        .await;
+    results.into_iter().collect::<Result<()>>()?;
+
+    storage.put_manifest(manifest).await?;
+    Ok(())
+}
+
+/// The first manifest a holder sends whose Merkle root is `cid`.
+async fn fetch_manifest(commands: &mpsc::Sender<NodeCommand>, holders: &[PeerId], cid: &Cid) -> Result<Manifest> {
+    for peer in holders {
+        match request(commands, *peer, BlockRequest::Manifest { cid: cid.clone() }).await {
+            Ok(BlockResponse::Manifest(manifest)) if manifest.cid() == *cid && manifest.validate().is_ok() => return Ok(manifest),
+            Ok(BlockResponse::Manifest(_)) => info!("Peer {} sent a manifest that does not match {}", peer, cid),
+            Ok(_) => {}
+            Err(e) => info!("Fetching the manifest of {} from {} failed: {}", cid, peer, e),
+        }
+    }
+    Err(anyhow!("No holder sent a valid manifest for {}", cid))
+}
@@ -0,0 +1,806 @@
+//! Loopback test harness: several agents in one process, each with its own
+//! in-memory storage and model directory, wired together by dialling instead
+//! of mDNS, running a tiny synthetic GGUF model on the CPU.
+
+use anyhow::{anyhow, Result};
+use candle_core::quantized::{gguf_file, GgmlDType, QTensor};
+use candle_core::{Device, Tensor};
+use libp2p::Multiaddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use crate::blobstore::StorageBackend;
+use crate::inference::InferenceEngine;
+use crate::node::{Node, NodeConfig, NodeHandle};
+
+pub const MODEL_NAME: &str = "synthetic.gguf";
+pub const VOCAB: usize = 32;
+pub const EMBD: usize = 16;
+pub const HEADS: usize = 4;
+pub const KV_HEADS: usize = 2;
+pub const FFN: usize = 32;
+pub const BLOCKS: usize = 2;
+pub const PROMPT: &str = "t1 t2 t3 t4 t5";
+
+// Deterministic, small weights so that runs are reproducible without a RNG.
+fn weight(rows: usize, cols: usize, seed: usize) -> Result<QTensor> {
+    let data: Vec<f32> = (0..rows * cols)
+        .map(|i| (((i * 7919 + seed * 104729) % 1000) as f32 / 1000.0 - 0.5) * 0.2)
+        .collect();
+    let t = Tensor::from_vec(data, (rows, cols), &Device::Cpu)?;
+    Ok(QTensor::quantize(&t, GgmlDType::F32)?)
+}
+
+fn ones(len: usize) -> Result<QTensor> {
+    let t = Tensor::ones(len, candle_core::DType::F32, &Device::Cpu)?;
+    Ok(QTensor::quantize(&t, GgmlDType::F32)?)
+}
+
+/// Writes a 2-block llama-architecture GGUF with F32 tensors.
+pub fn write_synthetic_gguf(path: &Path) -> Result<()> {
+    write_synthetic_gguf_variant(path, 0)
+}
+
+/// Same architecture, but a non-zero `variant` gives different weights.
+/// Used to stand in for a worker running the wrong (or a tampered) model.
+pub fn write_synthetic_gguf_variant(path: &Path, variant: usize) -> Result<()> {
+    let head_dim = EMBD / HEADS;
+    let weight = |rows, cols, seed: usize| weight(rows, cols, seed + 997 * variant);
+    let mut tensors: Vec<(String, QTensor)> = vec![
+        ("token_embd.weight".to_string(), weight(VOCAB, EMBD, 1)?),
+        ("output_norm.weight".to_string(), ones(EMBD)?),
+        ("output.weight".to_string(), weight(VOCAB, EMBD, 2)?),
+    ];
+    for b in 0..BLOCKS {
+        let seed = 10 * (b + 1);
+        let p = format!("blk.{b}");
+        tensors.push((format!("{p}.attn_q.weight"), weight(HEADS * head_dim, EMBD, seed)?));
+        tensors.push((format!("{p}.attn_k.weight"), weight(KV_HEADS * head_dim, EMBD, seed + 1)?));
+        tensors.push((format!("{p}.attn_v.weight"), weight(KV_HEADS * head_dim, EMBD, seed + 2)?));
+        tensors.push((format!("{p}.attn_output.weight"), weight(EMBD, HEADS * head_dim, seed + 3)?));
+        tensors.push((format!("{p}.ffn_gate.weight"), weight(FFN, EMBD, seed + 4)?));
+        tensors.push((format!("{p}.ffn_up.weight"), weight(FFN, EMBD, seed + 5)?));
+        tensors.push((format!("{p}.ffn_down.weight"), weight(EMBD, FFN, seed + 6)?));
+        tensors.push((format!("{p}.attn_norm.weight"), ones(EMBD)?));
+        tensors.push((format!("{p}.ffn_norm.weight"), ones(EMBD)?));
+    }
+
+    let metadata = [
+        ("general.architecture", gguf_file::Value::String("llama".to_string())),
+        ("llama.attention.head_count", gguf_file::Value::U32(HEADS as u32)),
+        ("llama.attention.head_count_kv", gguf_file::Value::U32(KV_HEADS as u32)),
+        ("llama.block_count", gguf_file::Value::U32(BLOCKS as u32)),
+        ("llama.embedding_length", gguf_file::Value::U32(EMBD as u32)),
+        ("llama.feed_forward_length", gguf_file::Value::U32(FFN as u32)),
+        ("llama.context_length", gguf_file::Value::U32(256)),
+        ("llama.rope.dimension_count", gguf_file::Value::U32(head_dim as u32)),
+        ("llama.attention.layer_norm_rms_epsilon", gguf_file::Value::F32(1e-5)),
+    ];
+    let metadata: Vec<(&str, &gguf_file::Value)> = metadata.iter().map(|(k, v)| (*k, v)).collect();
+    let tensors: Vec<(&str, &QTensor)> = tensors.iter().map(|(k, v)| (k.as_str(), v)).collect();
+
+    let mut file = std::fs::File::create(path)?;
+    gguf_file::write(&mut file, &metadata, &tensors)?;
+    Ok(())
+}
+
+/// Writes a whitespace word-level tokenizer with tokens `t1`..`t31`.
+pub fn write_synthetic_tokenizer(path: &Path) -> Result<()> {
+    let mut vocab = serde_json::Map::new();
+    vocab.insert("[UNK]".to_string(), 0.into());
+    for i in 1..VOCAB {
+        vocab.insert(format!("t{i}"), i.into());
+    }
+    let tokenizer = serde_json::json!({
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": [],
+        "normalizer": null,
+        "pre_tokenizer": { "type": "Whitespace" },
+        "post_processor": null,
+        "decoder": null,
+        "model": { "type": "WordLevel", "vocab": vocab, "unk_token": "[UNK]" }
+    });
+    std::fs::write(path, serde_json::to_vec(&tokenizer)?)?;
+    Ok(())
+}
+
+/// Writes the synthetic model and its tokenizer into `models_dir`, named the
+/// way the task handler looks them up.
+pub fn install_synthetic_model(models_dir: &Path) -> Result<PathBuf> {
+    std::fs::create_dir_all(models_dir)?;
+    let model = models_dir.join(MODEL_NAME);
+    write_synthetic_gguf(&model)?;
+    write_synthetic_tokenizer(&models_dir.join(format!("{MODEL_NAME}.tokenizer.json")))?;
+    Ok(model)
+}
+
+/// What a single node generates for `PROMPT` with the model at `model`,
+/// the reference distributed runs are compared against.
+pub async fn local_output(model: &Path) -> Result<String> {
+    let tokenizer = format!("{}.tokenizer.json", model.display());
+    let model = model.to_string_lossy().to_string();
+    tokio::task::spawn_blocking(move || InferenceEngine::load(&model, &tokenizer, None, None)?.generate(PROMPT, 50)).await?
+}
+
+pub fn loopback() -> Multiaddr {
+    "/ip4/127.0.0.1/tcp/0".parse().unwrap()
+}
+
+/// Config for an isolated node under `dir`: loopback only, no mDNS, no HTTP API.
+pub fn node_config(dir: &Path) -> NodeConfig {
+    NodeConfig {
+        storage: StorageBackend::Memory,
+        models_dir: dir.join("models"),
+        listen_addrs: vec![loopback()],
+        mdns: false,
+        api_port: None,
+        capability_interval: Duration::from_millis(500),
+        replication_interval: Duration::from_millis(500),
+        bans_path: dir.join("banned_peers.json"),
+        ..NodeConfig::default()
+    }
+}
+
+/// A port nothing listens on right now, for servers that need a fixed one.
+pub fn free_port() -> Result<u16> {
+    Ok(std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?.port())
+}
+
+/// POSTs `body` to the dashboard API on `port` and returns the JSON reply,
+/// retrying while the server is still starting up.
+pub async fn api_post(port: u16, path: &str, body: serde_json::Value) -> Result<serde_json::Value> {
+    Ok(api_post_with(port, path, body, &[]).await?.1)
+}
+
+/// Like `api_post`, with extra request headers; also returns the status code.
+pub async fn api_post_with(port: u16, path: &str, body: serde_json::Value, headers: &[(&str, &str)]) -> Result<(u16, serde_json::Value)> {
+    api_send(reqwest::Method::POST, port, path, body, headers).await
+}
+
+/// Like `api_post_with`, for any method.
+pub async fn api_send(method: reqwest::Method, port: u16, path: &str, body: serde_json::Value, headers: &[(&str, &str)]) -> Result<(u16, serde_json::Value)> {
+    let url = format!("http://127.0.0.1:{port}{path}");
+    let client = reqwest::Client::new();
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+    loop {
+        let request = client.request(method.clone(), &url).json(&body);
+        let request = headers.iter().fold(request, |request, (name, value)| request.header(*name, *value));
+        match request.send().await {
+            Ok(response) => return Ok((response.status().as_u16(), response.json().await?)),
+            Err(e) if e.is_connect() && tokio::time::Instant::now() < deadline => tokio::time::sleep(Duration::from_millis(100)).await,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Polls `cond` until it holds or `timeout` passes.
+pub async fn wait_until(timeout: Duration, mut cond: impl FnMut() -> bool) -> Result<()> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    while !cond() {
+        if tokio::time::Instant::now() > deadline {
+            return Err(anyhow!("condition not met within {:?}", timeout));
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    Ok(())
+}
+
+pub async fn spawn_node(config: NodeConfig) -> Result<NodeHandle> {
+    let node = Node::new(config, libp2p::identity::Keypair::generate_ed25519()).await?;
+    let handle = node.handle();
+    tokio::spawn(node.run());
+    wait_until(Duration::from_secs(5), || !handle.listen_addrs.lock().unwrap().is_empty()).await?;
+    Ok(handle)
+}
+
+/// Dials `to` from `from` using its first listen address.
+pub async fn connect(from: &NodeHandle, to: &NodeHandle) -> Result<()> {
+    let addr = to.listen_addrs.lock().unwrap()[0].clone();
+    from.dial(addr).await
+}
+
+/// N agents on loopback, all connected to each other.
+pub struct TestHive {
+    pub nodes: Vec<NodeHandle>,
+    pub dir: tempfile::TempDir,
+}
+
+impl TestHive {
+    pub async fn start(n: usize) -> Result<Self> {
+        Self::start_with(n, |_, config| config).await
+    }
+
+    /// Like `start`, with `configure` adjusting the config of node `i`.
+    pub async fn start_with(n: usize, configure: impl Fn(usize, NodeConfig) -> NodeConfig) -> Result<Self> {
+        let dir = tempfile::tempdir()?;
+        let mut nodes = Vec::with_capacity(n);
+        for i in 0..n {
+            let node_dir = dir.path().join(format!("node{i}"));
+            install_synthetic_model(&node_dir.join("models"))?;
+            nodes.push(spawn_node(configure(i, node_config(&node_dir))).await?);
+        }
+        for i in 0..n {
+            for j in 0..i {
+                connect(&nodes[i], &nodes[j]).await?;
+            }
+        }
+        // Ready once every node has seen all others subscribe to the hive topic
+        for node in &nodes {
+            wait_until(Duration::from_secs(10), || {
+                let scheduler = node.scheduler.lock().unwrap();
+                scheduler.peers.len() == n - 1
+                    && scheduler.peers.values().all(|p| p.topics.iter().any(|t| t == "hive-main"))
+            })
+            .await?;
+        }
+        Ok(Self { nodes, dir })
+    }
+
+    pub fn models_dir(&self, i: usize) -> PathBuf {
+        self.dir.path().join(format!("node{i}")).join("models")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::{AccessChange, AccessList};
+    use crate::message::{Message, TaskError};
+    use crate::model::sharded_llama::ModelWeights;
+    use crate::tensor_parallel;
+    use crate::verification::{self, MismatchPolicy, TaskSpec, VerificationConfig};
+
+    const TOLERANCE: f32 = 1e-4;
+
+    fn load_weights(path: &Path, layer_range: Option<(usize, usize)>) -> Result<ModelWeights> {
+        let mut file = std::fs::File::open(path)?;
+        let content = gguf_file::Content::read(&mut file)?;
+        Ok(ModelWeights::from_gguf(content, &mut file, &Device::Cpu, layer_range, None)?)
+    }
+
+    #[test]
+    fn pipeline_shards_match_single_node() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let model = install_synthetic_model(dir.path())?;
+        let input = Tensor::new(&[[1u32, 2, 3, 4, 5]], &Device::Cpu)?;
+
+        let full = load_weights(&model, None)?.forward(&input, 0)?;
+
+        let hidden = load_weights(&model, Some((0, 1)))?.forward(&input, 0)?;
+        let sharded = load_weights(&model, Some((1, BLOCKS)))?.forward(&hidden, 0)?;
+
+        let full = full.flatten_all()?.to_vec1::<f32>()?;
+        let sharded = sharded.flatten_all()?.to_vec1::<f32>()?;
+        assert!(tensor_parallel::max_abs_diff(&full, &sharded)? < TOLERANCE);
+        Ok(())
+    }
+
+    #[test]
+    fn tensor_split_matches_single_node() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let model = install_synthetic_model(dir.path())?;
+        let tokenizer = format!("{}.tokenizer.json", model.display());
+
+        let diff = tensor_parallel::validate(&model.to_string_lossy(), &tokenizer, PROMPT, 2)?;
+        assert!(diff < TOLERANCE, "max logit difference {diff}");
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn tensor_parallel_task_over_loopback_matches_single_node() -> Result<()> {
+        let hive = TestHive::start(3).await?;
+
+        let model = hive.models_dir(0).join(MODEL_NAME);
+        let tokenizer = format!("{}.tokenizer.json", model.display());
+        let model = model.to_string_lossy().to_string();
+        let expected = tokio::task::spawn_blocking(move || {
+            InferenceEngine::load(&model, &tokenizer, None, None)?.generate(PROMPT, 50)
+        })
+        .await??;
+
+        // Node 0 submits, nodes 1 and 2 each hold half of every layer
+        let ranks = [hive.nodes[1].peer_id, hive.nodes[2].peer_id];
+        let task_id = uuid::Uuid::new_v4().to_string();
+        let output = tensor_parallel::dispatch(&hive.nodes[0].commands, &ranks, task_id, spec())
+            .await
+            .map_err(anyhow::Error::msg)?;
+
+        assert_eq!(output, expected);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn capability_records_reach_peers() -> Result<()> {
+        let hive = TestHive::start(2).await?;
+        let (local, remote) = (&hive.nodes[0], hive.nodes[1].peer_id);
+        wait_until(Duration::from_secs(10), || local.scheduler.lock().unwrap().capabilities.contains_key(&remote)).await?;
+
+        let scheduler = local.scheduler.lock().unwrap();
+        let record = &scheduler.capabilities[&remote];
+        assert!(record.capability.cpu_cores >= 1);
+        assert_eq!(record.capability.models, vec![MODEL_NAME.to_string()]);
+        assert!(scheduler.capabilities.contains_key(&local.peer_id));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn forged_gossip_lowers_the_sender_score() -> Result<()> {
+        let hive = TestHive::start(2).await?;
+        let (local, remote) = (&hive.nodes[0], &hive.nodes[1]);
+        wait_until(Duration::from_secs(10), || local.scheduler.lock().unwrap().capabilities.contains_key(&remote.peer_id)).await?;
+
+        // A record signed by someone else, published under the remote's name
+        let stranger = libp2p::identity::Keypair::generate_ed25519();
+        let signed = crate::capability::SignedCapability::sign(&stranger, &Default::default())?;
+        remote.p2p_sender.send(Message::Capability { signed }).await?;
+
+        let score = |node: &NodeHandle| node.scheduler.lock().unwrap().peers[&remote.peer_id].gossip_score;
+        wait_until(Duration::from_secs(10), || score(local).is_some_and(|s| s < 0.0)).await?;
+        assert!(!local.scheduler.lock().unwrap().capabilities.contains_key(&stranger.public().to_peer_id()));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn banned_peer_is_disconnected_and_refused() -> Result<()> {
+        let hive = TestHive::start(2).await?;
+        let (local, remote) = (&hive.nodes[0], &hive.nodes[1]);
+        local.update_access(AccessChange::Ban(remote.peer_id)).await?;
+        wait_until(Duration::from_secs(10), || !local.scheduler.lock().unwrap().peers.contains_key(&remote.peer_id)).await?;
+
+        connect(remote, local).await?;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(!local.scheduler.lock().unwrap().peers.contains_key(&remote.peer_id));
+        assert!(matches!(local.send_task(remote.peer_id, task("banned")).await, Err(TaskError::Denied)));
+
+        // The ban outlives the node
+        let bans = AccessList::load(&[], &[], &hive.dir.path().join("node0").join("banned_peers.json"))?;
+        assert!(!bans.permits(&remote.peer_id));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn content_is_fetched_from_several_holders() -> Result<()> {
+        let hive = TestHive::start(3).await?;
+        // Several chunks, so both holders get asked
+        let data: Vec<u8> = (0..3 * crate::storage::CHUNK_SIZE as usize + 1000).map(|i| (i % 251) as u8).collect();
+        let cid = hive.nodes[0].storage.store(&data).await?;
+        hive.nodes[1].storage.store(&data).await?;
+
+        let fetcher = &hive.nodes[2];
+        assert_eq!(fetcher.get(&cid).await?, data);
+        assert_eq!(fetcher.storage.retrieve(&cid).await?, Some(data));
+
+        let missing: hive_core::Cid = "0".repeat(64).parse()?;
+        assert!(fetcher.get(&missing).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn lost_replicas_are_replaced() -> Result<()> {
+        // Only nodes with a quota take replicas
+        let quota = 16 * crate::storage::CHUNK_SIZE;
+        let hive = TestHive::start_with(4, |_, config| NodeConfig { storage_quota: Some(quota), ..config }).await?;
+        let data: Vec<u8> = (0..crate::storage::CHUNK_SIZE as usize + 1000).map(|i| (i % 251) as u8).collect();
+        let origin = &hive.nodes[0];
+        let cid = origin.storage.store(&data).await?;
+
+        // Polls the replication status as seen from the origin
+        let cid = &cid;
+        let holders_become = |expected: usize| async move {
+            let deadline = tokio::time::Instant::now() + Duration::from_secs(20);
+            loop {
+                let status = origin.replicator.status(cid).await?;
+                if status.holders.len() == expected && status.missing() == 0 {
+                    return Ok::<_, anyhow::Error>(status.holders);
+                }
+                if tokio::time::Instant::now() > deadline {
+                    return Err(anyhow!("{} holders instead of {}", status.holders.len(), expected));
+                }
+                tokio::time::sleep(Duration::from_millis(200)).await;
+            }
+        };
+
+        origin.replicator.set_factor(cid, 3).await?;
+        let holders = holders_become(3).await?;
+        assert!(holders.contains(&origin.peer_id));
+        let replica = hive.nodes.iter().find(|n| n.peer_id != origin.peer_id && holders.contains(&n.peer_id)).unwrap();
+        // Replicas count as held while they are being fetched
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+        while replica.storage.replication().await?.get(cid) != Some(&3) {
+            assert!(tokio::time::Instant::now() < deadline, "replica never stored");
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(replica.storage.retrieve(cid).await? == Some(data));
+
+        // The spare node takes over from a holder that leaves
+        replica.drain().await?;
+        let holders = holders_become(3).await?;
+        assert!(!holders.contains(&replica.peer_id));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn private_hive_rejects_nodes_without_key() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let psk: libp2p::pnet::PreSharedKey = crate::p2p::generate_swarm_key().parse()?;
+        let private = |name: &str| NodeConfig { psk: Some(psk), ..node_config(&dir.path().join(name)) };
+        let a = spawn_node(private("a")).await?;
+        let b = spawn_node(private("b")).await?;
+        let outsider = spawn_node(node_config(&dir.path().join("outsider"))).await?;
+
+        connect(&outsider, &a).await?;
+        connect(&b, &a).await?;
+        wait_until(Duration::from_secs(10), || a.scheduler.lock().unwrap().peers.contains_key(&b.peer_id)).await?;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(!a.scheduler.lock().unwrap().peers.contains_key(&outsider.peer_id));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn nodes_join_several_named_hives() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let hives = |names: &[&str], node: &str| NodeConfig {
+            hives: names.iter().map(|n| n.to_string()).collect(),
+            ..node_config(&dir.path().join(node))
+        };
+        let a = spawn_node(hives(&["lab", "prod"], "a")).await?;
+        let b = spawn_node(hives(&["prod"], "b")).await?;
+        connect(&b, &a).await?;
+
+        let prod = crate::p2p::hive_topic("prod").to_string();
+        wait_until(Duration::from_secs(10), || a.scheduler.lock().unwrap().peers_in_hive(&prod) == vec![b.peer_id]).await?;
+        let lab = crate::p2p::hive_topic("lab").to_string();
+        assert!(a.scheduler.lock().unwrap().peers_in_hive(&lab).is_empty());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn task_runs_over_quic() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let quic = |name: &str| NodeConfig {
+            listen_addrs: vec!["/ip4/127.0.0.1/udp/0/quic-v1".parse().unwrap()],
+            ..node_config(&dir.path().join(name))
+        };
+        let submitter = spawn_node(quic("submitter")).await?;
+        install_synthetic_model(&dir.path().join("worker").join("models"))?;
+        let worker = spawn_node(quic("worker")).await?;
+        connect(&submitter, &worker).await?;
+        wait_until(Duration::from_secs(10), || submitter.scheduler.lock().unwrap().peers.contains_key(&worker.peer_id)).await?;
+
+        let address = submitter.scheduler.lock().unwrap().peers[&worker.peer_id].address[0].clone();
+        assert!(address.iter().any(|p| matches!(p, libp2p::multiaddr::Protocol::QuicV1)));
+        let output = submitter.send_task(worker.peer_id, task("quic")).await.map_err(anyhow::Error::msg)?;
+        assert!(!output.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn peer_behind_relay_is_reachable_through_circuit() -> Result<()> {
+        use libp2p::multiaddr::Protocol;
+        let dir = tempfile::tempdir()?;
+        // Relays only serve reservations on an address they know to be reachable
+        let port = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+        let public: Multiaddr = format!("/ip4/127.0.0.1/tcp/{port}").parse()?;
+        let relay = spawn_node(NodeConfig {
+            relay_server: true,
+            listen_addrs: vec![public.clone()],
+            external_addrs: vec![public.clone()],
+            ..node_config(&dir.path().join("relay"))
+        })
+        .await?;
+        let relay_addr = public.with(Protocol::P2p(relay.peer_id));
+
+        let hidden = spawn_node(NodeConfig {
+            relays: vec![relay_addr.clone()],
+            ..node_config(&dir.path().join("hidden"))
+        })
+        .await?;
+        // The circuit address shows up once the reservation is accepted
+        wait_until(Duration::from_secs(10), || {
+            hidden.listen_addrs.lock().unwrap().iter().any(|a| a.iter().any(|p| p == Protocol::P2pCircuit))
+        })
+        .await?;
+
+        let dialer = spawn_node(node_config(&dir.path().join("dialer"))).await?;
+        dialer.dial(relay_addr.with(Protocol::P2pCircuit).with(Protocol::P2p(hidden.peer_id))).await?;
+        wait_until(Duration::from_secs(10), || dialer.scheduler.lock().unwrap().peers.contains_key(&hidden.peer_id)).await?;
+        Ok(())
+    }
+
+    fn task(task_id: &str) -> Message {
+        Message::TaskRequest {
+            task_id: task_id.to_string(),
+            prompt: PROMPT.to_string(),
+            model_name: MODEL_NAME.to_string(),
+            download_url: None,
+            model: None,
+            layer_range: None,
+            tp_ranks: None,
+            seed: None,
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn addressed_task_runs_only_on_chosen_peer() -> Result<()> {
+        let hive = TestHive::start(3).await?;
+        // Node 2 would answer differently if it ran the task
+        write_synthetic_gguf_variant(&hive.models_dir(2).join(MODEL_NAME), 1)?;
+
+        let model = hive.models_dir(0).join(MODEL_NAME);
+        let tokenizer = format!("{}.tokenizer.json", model.display());
+        let model = model.to_string_lossy().to_string();
+        let expected = tokio::task::spawn_blocking(move || {
+            InferenceEngine::load(&model, &tokenizer, None, None)?.generate(PROMPT, 50)
+        })
+        .await??;
+
+        let output = hive.nodes[0]
+            .send_task(hive.nodes[1].peer_id, task("addressed"))
+            .await
+            .map_err(anyhow::Error::msg)?;
+        assert_eq!(output, expected);
+
+        // The same model, named by a path that leaves the models directory
+        let mut request = task("traversal");
+        if let Message::TaskRequest { model_name, .. } = &mut request {
+            *model_name = format!("../models/{MODEL_NAME}");
+        }
+        let reply = hive.nodes[0].send_task(hive.nodes[1].peer_id, request).await;
+        assert!(matches!(&reply, Err(TaskError::Failed(e)) if e.contains("Invalid model name")), "{:?}", reply);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn task_model_is_fetched_by_cid() -> Result<()> {
+        let hive = TestHive::start(2).await?;
+        let model = hive.nodes[0].registry.import(&hive.models_dir(0).join(MODEL_NAME), Some("synthetic"), None).await?;
+        // The worker has nothing under the name, so it has to go by CID
+        std::fs::remove_file(hive.models_dir(1).join(MODEL_NAME))?;
+        std::fs::remove_file(hive.models_dir(1).join(format!("{MODEL_NAME}.tokenizer.json")))?;
+
+        let mut request = task("by-cid");
+        if let Message::TaskRequest { model: task_model, .. } = &mut request {
+            *task_model = Some(model.model_ref());
+        }
+        let output = hive.nodes[0].send_task(hive.nodes[1].peer_id, request).await.map_err(anyhow::Error::msg)?;
+        assert!(!output.is_empty());
+        let fetched = hive.nodes[1].registry.resolve(&model.cid.to_string()).await.expect("registered on fetch");
+        assert_eq!(fetched.metadata, model.metadata);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn saturated_worker_answers_busy() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let submitter = spawn_node(node_config(&dir.path().join("submitter"))).await?;
+        let worker = spawn_node(NodeConfig {
+            max_concurrent_tasks: 0,
+            ..node_config(&dir.path().join("worker"))
+        })
+        .await?;
+        connect(&submitter, &worker).await?;
+        wait_until(Duration::from_secs(10), || submitter.scheduler.lock().unwrap().peers.contains_key(&worker.peer_id)).await?;
+
+        let reply = submitter.send_task(worker.peer_id, task("busy")).await;
+        assert_eq!(reply, Err(TaskError::Busy));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn drained_worker_answers_before_leaving() -> Result<()> {
+        let hive = TestHive::start(2).await?;
+        let (submitter, worker) = (hive.nodes[0].clone(), hive.nodes[1].clone());
+        let in_flight = tokio::spawn(async move { submitter.send_task(worker.peer_id, task("draining")).await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        hive.nodes[1].drain().await?;
+
+        // Finished or refused, but never dropped on the floor
+        let reply = in_flight.await?;
+        assert!(matches!(reply, Ok(_) | Err(TaskError::Draining)), "{:?}", reply);
+        let submitter = &hive.nodes[0];
+        wait_until(Duration::from_secs(10), || !submitter.scheduler.lock().unwrap().peers.contains_key(&hive.nodes[1].peer_id)).await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn idle_node_announces_leaving_before_it_goes() -> Result<()> {
+        let hive = TestHive::start(2).await?;
+        let (observer, leaving) = (&hive.nodes[0], hive.nodes[1].clone());
+        let id = leaving.peer_id;
+        wait_until(Duration::from_secs(10), || observer.scheduler.lock().unwrap().peers.get(&id).is_some_and(|p| !p.topics.is_empty())).await?;
+
+        // No task in flight, so only the grace period keeps the connection up
+        let drained = tokio::spawn(async move { leaving.drain().await });
+        wait_until(Duration::from_secs(5), || observer.scheduler.lock().unwrap().peers.get(&id).is_some_and(|p| p.status == "leaving")).await?;
+        drained.await??;
+        Ok(())
+    }
+
+    fn spec() -> TaskSpec {
+        TaskSpec {
+            prompt: PROMPT.to_string(),
+            model_name: MODEL_NAME.to_string(),
+            download_url: None,
+            model: None,
+        }
+    }
+
+    fn verify_with(replicas: usize, policy: MismatchPolicy) -> VerificationConfig {
+        VerificationConfig {
+            replicas,
+            policy,
+            max_attempts: 1,
+            timeout: Duration::from_secs(60),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn verified_task_flags_minority_worker() -> Result<()> {
+        let hive = TestHive::start(4).await?;
+        // Node 3 answers with a different model
+        write_synthetic_gguf_variant(&hive.models_dir(3).join(MODEL_NAME), 1)?;
+
+        let submitter = &hive.nodes[0];
+        let output = verification::run_verified(
+            &submitter.scheduler,
+            &submitter.commands,
+            "verify-majority",
+            spec(),
+            verify_with(3, MismatchPolicy::Majority),
+        )
+        .await
+        .map_err(anyhow::Error::msg)?;
+        assert!(!output.is_empty());
+
+        let scheduler = submitter.scheduler.lock().unwrap();
+        assert_eq!(scheduler.flags(&hive.nodes[1].peer_id), 0);
+        assert_eq!(scheduler.flags(&hive.nodes[2].peer_id), 0);
+        assert_eq!(scheduler.flags(&hive.nodes[3].peer_id), 1);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn failed_replica_is_replaced_without_flagging() -> Result<()> {
+        let hive = TestHive::start(4).await?;
+        // The worker picked first cannot run the task at all
+        let first = (1..4).min_by_key(|&i| hive.nodes[i].peer_id).unwrap();
+        std::fs::remove_file(hive.models_dir(first).join(MODEL_NAME))?;
+
+        let submitter = &hive.nodes[0];
+        let output = verification::run_verified(
+            &submitter.scheduler,
+            &submitter.commands,
+            "verify-replaced",
+            spec(),
+            verify_with(2, MismatchPolicy::Fail),
+        )
+        .await
+        .map_err(anyhow::Error::msg)?;
+        assert_eq!(output, local_output(&hive.models_dir(0).join(MODEL_NAME)).await?);
+        let mut scheduler = submitter.scheduler.lock().unwrap();
+        assert!(hive.nodes.iter().all(|n| scheduler.flags(&n.peer_id) == 0));
+
+        // Flags are kept by PeerId, so a peer cannot shed them by reconnecting
+        let peer = hive.nodes[first].peer_id;
+        scheduler.flag_peer(&peer);
+        scheduler.remove_peer(&peer);
+        assert_eq!(scheduler.flags(&peer), 1);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn verified_task_fails_without_majority() -> Result<()> {
+        let hive = TestHive::start(3).await?;
+        write_synthetic_gguf_variant(&hive.models_dir(2).join(MODEL_NAME), 1)?;
+
+        let submitter = &hive.nodes[0];
+        let result = verification::run_verified(
+            &submitter.scheduler,
+            &submitter.commands,
+            "verify-fail",
+            spec(),
+            verify_with(2, MismatchPolicy::Fail),
+        )
+        .await;
+        assert!(result.is_err());
+
+        // With two replicas there is no way to tell who is wrong, so nobody is flagged
+        let scheduler = submitter.scheduler.lock().unwrap();
+        assert!(scheduler.peers.keys().all(|p| scheduler.flags(p) == 0));
+        Ok(())
+    }
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn peers_report_latency_and_identity() -> Result<()> {
+        let hive = TestHive::start(2).await?;
+        let node = &hive.nodes[0];
+        wait_until(Duration::from_secs(10), || {
+            let scheduler = node.scheduler.lock().unwrap();
+            let peer = &scheduler.peers[&hive.nodes[1].peer_id];
+            peer.latency.is_some() && peer.agent_version.is_some()
+        })
+        .await?;
+
+        let scheduler = node.scheduler.lock().unwrap();
+        let peer = &scheduler.peers[&hive.nodes[1].peer_id];
+        assert!(peer.agent_version.as_deref().unwrap().starts_with("hive-agent/"));
+        assert!(peer.protocols.iter().any(|p| p == crate::p2p::KAD_PROTOCOL.as_ref()));
+        assert_eq!(scheduler.get_available_peer(), Some(hive.nodes[1].peer_id));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn peers_discover_each_other_through_bootstrap_node() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let boot = spawn_node(node_config(&dir.path().join("boot"))).await?;
+        let boot_addr = boot.listen_addrs.lock().unwrap()[0]
+            .clone()
+            .with(libp2p::multiaddr::Protocol::P2p(boot.peer_id));
+
+        let joining = |name: &str| NodeConfig {
+            bootstrap: vec![boot_addr.clone()],
+            random_walk_interval: Duration::from_millis(500),
+            ..node_config(&dir.path().join(name))
+        };
+        let b = spawn_node(joining("b")).await?;
+        wait_until(Duration::from_secs(10), || boot.scheduler.lock().unwrap().peers.contains_key(&b.peer_id)).await?;
+        let c = spawn_node(joining("c")).await?;
+
+        // Neither dialed the other; the DHT has to introduce them
+        wait_until(Duration::from_secs(20), || {
+            b.scheduler.lock().unwrap().peers.contains_key(&c.peer_id)
+                && c.scheduler.lock().unwrap().peers.contains_key(&b.peer_id)
+        })
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn matrix_product_is_tiled_across_peers() -> Result<()> {
+        use crate::compute::ComputeEngine;
+        use crate::matmul::Progress;
+
+        let dir = tempfile::tempdir()?;
+        let config = |name: &str, slots: usize| NodeConfig {
+            // Probing the hardware for capability records is slow; keep it out of the way
+            capability_interval: Duration::from_secs(60),
+            max_concurrent_tasks: slots,
+            ..node_config(&dir.path().join(name))
+        };
+        let submitter = spawn_node(config("submitter", 1)).await?;
+        let mut workers = Vec::new();
+        for (name, slots) in [("worker1", 1), ("worker2", 1), ("busy", 0)] {
+            let worker = spawn_node(config(name, slots)).await?;
+            connect(&submitter, &worker).await?;
+            workers.push(worker);
+        }
+        wait_until(Duration::from_secs(10), || {
+            let scheduler = submitter.scheduler.lock().unwrap();
+            workers.iter().all(|w| scheduler.peers.get(&w.peer_id).is_some_and(|p| p.accepts_work()))
+        })
+        .await?;
+
+        let a = ComputeEngine::generate_matrix(5, 7);
+        let b = ComputeEngine::generate_matrix(7, 3);
+        let cid_a = submitter.storage.store(&ComputeEngine::serialize_matrix(&a)?).await?;
+        let cid_b = submitter.storage.store(&ComputeEngine::serialize_matrix(&b)?).await?;
+
+        let last = std::sync::Mutex::new(Progress::default());
+        let cid = submitter.multiply(&cid_a, &cid_b, 2, 1, |p| *last.lock().unwrap() = p).await?;
+        let product = ComputeEngine::deserialize_matrix(&submitter.storage.retrieve(&cid).await?.unwrap())?;
+        let expected = a.dot(&b);
+        assert_eq!(product.dim(), (5, 3));
+        assert!(product.iter().zip(&expected).all(|(x, y)| (x - y).abs() < TOLERANCE));
+
+        // 3 x 2 output tiles, each the sum of 4 products; the busy peer's share is redone elsewhere
+        let last = *last.lock().unwrap();
+        assert_eq!((last.done, last.total), (24, 24));
+        assert!(last.retries > 0);
+
+        // Checked: both workers compute every tile product and agree on all of them
+        let checked = submitter.multiply(&cid_a, &cid_b, 2, 2, |_| {}).await?;
+        assert_eq!(checked, cid);
+        assert!(submitter.multiply(&cid_a, &cid_b, 2, 3, |_| {}).await.is_err(), "only two peers can take work");
+        Ok(())
+    }
+}
@@ -1,31 +1,58 @@
 use axum::{
-    extract::{State, Json, Multipart, DefaultBodyLimit},
-    routing::{get, post},
+    body::Body,
+    extract::{multipart::Field, ConnectInfo, State, Json, Multipart, DefaultBodyLimit, Path, Query, Request},
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
     Router,
 };
 use serde_json::{json, Value};
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 use tower_http::cors::CorsLayer;
 use crate::inference::InferenceEngine;
-use crate::scheduler::Scheduler;
-use crate::message::Message;
+use crate::scheduler::{Reachability, Scheduler};
+use crate::message::{Message, MessageStats, TaskError};
 use crate::backend::llama_cpp::LlamaCppBackend;
-use std::io::Write;
-use std::collections::HashMap;
-use tokio::sync::{mpsc, oneshot};
+use crate::access::{AccessChange, AccessList};
+use crate::node::{self, NodeCommand};
+use crate::p2p;
+use crate::registry::{ModelInfo, ModelRegistry};
+use crate::replication::{ReplicationStatus, Replicator};
+use crate::storage::Storage;
+use hive_core::Cid;
+use crate::tensor_parallel::{self, ParallelMode};
+use crate::verification::{self, TaskSpec, VerificationConfig};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use libp2p::{multiaddr::Protocol, PeerId};
 
 
 #[derive(Clone)]
 pub struct AppState {
+    pub peer_id: PeerId,
     pub inference_engine: Arc<Mutex<Option<InferenceEngine>>>,
     pub scheduler: Arc<Mutex<Scheduler>>,
     pub p2p_sender: mpsc::Sender<Message>,
-    pub pending_requests: Arc<Mutex<HashMap<String, oneshot::Sender<Result<String, String>>>>>,
+    pub commands: mpsc::Sender<NodeCommand>,
     pub llama_server_port: Option<u16>,
     pub server_process: Arc<Mutex<Option<std::process::Child>>>,
     pub current_config: Arc<Mutex<Option<ServerConfig>>>,
+    pub parallel_mode: ParallelMode,
+    pub verification: Option<VerificationConfig>,
+    pub message_stats: Arc<MessageStats>,
+    pub access: Arc<Mutex<AccessList>>,
+    pub storage: Arc<Storage>,
+    pub registry: ModelRegistry,
+    pub replicator: Replicator,
+    pub admin_token: Option<String>,
 }
 
+/// Environment variable holding the token that admin routes require. Without
+/// one, they only answer local tools.
+pub const ADMIN_TOKEN_ENV: &str = "HIVE_ADMIN_TOKEN";
+
 #[derive(Clone, PartialEq)]
 pub struct ServerConfig {
     pub model_path: String,
@@ -34,13 +61,24 @@ pub struct ServerConfig {
 }
 
 pub async fn start_server(
+    peer_id: PeerId,
     inference_engine: Arc<Mutex<Option<InferenceEngine>>>,
     scheduler: Arc<Mutex<Scheduler>>,
     p2p_sender: mpsc::Sender<Message>,
-    pending_requests: Arc<Mutex<HashMap<String, oneshot::Sender<Result<String, String>>>>>,
+    commands: mpsc::Sender<NodeCommand>,
     config: Option<ServerConfig>,
+    parallel_mode: ParallelMode,
+    verification: Option<VerificationConfig>,
+    message_stats: Arc<MessageStats>,
+    access: Arc<Mutex<AccessList>>,
+    server_process: Arc<Mutex<Option<std::process::Child>>>, // Owned by the node, which stops it on shutdown
+    storage: Arc<Storage>,
+    registry: ModelRegistry,
+    replicator: Replicator,
+    admin_token: Option<String>,
+    port: u16,
 ) {
-    let mut server_process = None;
+    let mut server_port = None;
 
     if let Some(cfg) = &config {
         println!("Initializing persistent llama-server...");
@@ -50,29 +88,52 @@ pub async fn start_server(
             Ok(child) => {
                 println!("llama-server started on port {}", port);
                 server_port = Some(port);
-                server_process = Some(child);
+                *server_process.lock().unwrap() = Some(child);
             },
             Err(e) => eprintln!("Failed to start llama-server: {}", e),
         }
     }
 
     let state = AppState { 
+        peer_id,
         inference_engine, 
         scheduler, 
         p2p_sender, 
-        pending_requests,
+        commands,
         llama_server_port: server_port,
-        server_process: Arc::new(Mutex::new(server_process)),
+        server_process,
         current_config: Arc::new(Mutex::new(config)),
+        parallel_mode,
+        verification,
+        message_stats,
+        access,
+        storage,
+        registry,
+        replicator,
+        admin_token,
     };
 
     // Create models directory if it doesn't exist
     let _ = std::fs::create_dir_all("models");
 
+    // Routes that change who the node talks to, what it stores for the hive,
+    // or shut it down
+    let admin = Router::new()
+        .route("/api/access", post(update_access))
+        .route("/api/admin/drain", post(drain))
+        .route("/api/admin/flags", post(clear_flags))
+        .route("/api/replication/{cid}", put(set_replication))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_admin));
+
     let app = Router::new()
         .route("/api/status", get(get_status))
         .route("/api/models", get(list_models))
         .route("/api/peers", get(list_peers))
+        .route("/api/capabilities", get(list_capabilities))
+        .route("/api/access", get(get_access))
+        .merge(admin)
+        .route("/api/content/{cid}", get(get_content))
+        .route("/api/replication/{cid}", get(get_replication))
         .route("/api/inference", post(run_inference))
         .route("/api/upload", post(upload_model))
         .nest_service("/models", tower_http::services::ServeDir::new("models"))
@@ -80,30 +141,82 @@ pub async fn start_server(
         .layer(CorsLayer::permissive())
         .with_state(state);
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    println!("Dashboard API listening on http://0.0.0.0:3000");
-    axum::serve(listener, app).await.unwrap();
+    let listener = tokio::net::TcpListener::bind(("0.0.0.0", port)).await.unwrap();
+    println!("Dashboard API listening on http://0.0.0.0:{}", port);
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
+}
+
+/// Lets a request through to an admin route if it carries the admin token
+/// as a bearer token. Without a configured token, only requests from this
+/// machine that no browser made are let through: browsers always send an
+/// Origin with cross-site requests, so no web page can drain the node.
+async fn require_admin(State(state): State<AppState>, ConnectInfo(addr): ConnectInfo<SocketAddr>, request: Request, next: Next) -> Response {
+    let headers = request.headers();
+    let authorized = match &state.admin_token {
+        Some(token) => headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes())),
+        None => addr.ip().is_loopback() && !headers.contains_key(header::ORIGIN),
+    };
+    if !authorized {
+        let error = match state.admin_token {
+            Some(_) => "Admin routes need the admin token".to_string(),
+            None => format!("Admin routes only answer local tools unless {} is set", ADMIN_TOKEN_ENV),
+        };
+        return (StatusCode::UNAUTHORIZED, Json(json!({ "error": error }))).into_response();
+    }
+    next.run(request).await
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
 }
 
 async fn get_status(State(state): State<AppState>) -> Json<Value> {
-    let peers = state.scheduler.lock().unwrap().peers.len();
+    let (peers, role) = {
+        let scheduler = state.scheduler.lock().unwrap();
+        (scheduler.peers.len(), scheduler.capabilities.get(&state.peer_id).map(|r| r.capability.device_type.clone()))
+    };
     Json(json!({
-        "node_id": "local-node",
-        "role": "Queen",
+        "node_id": state.peer_id.to_string(),
+        "role": role, // This node's device type, as peers list it; null until probed
         "peers": peers,
-        "status": "active"
+        "status": "active",
+        "protocol_version": crate::message::PROTOCOL_VERSION,
+        "messages": state.message_stats.to_json()
     }))
 }
 
-async fn list_peers(State(state): State<AppState>) -> Json<Value> {
+#[derive(serde::Deserialize)]
+struct PeerQuery {
+    hive: Option<String>, // Only peers that joined this hive
+}
+
+async fn list_peers(State(state): State<AppState>, Query(query): Query<PeerQuery>) -> Json<Value> {
     let scheduler = state.scheduler.lock().unwrap();
-    let peers: Vec<Value> = scheduler.peers.values().map(|p| {
+    let hive_topic = query.hive.as_deref().map(|name| p2p::hive_topic(name).to_string());
+    let peers: Vec<Value> = scheduler.peers.values().filter(|p| match &hive_topic {
+        Some(topic) => p.topics.contains(topic),
+        None => true,
+    }).map(|p| {
         json!({
             "id": p.id.to_string(),
             "address": p.address.iter().map(|a| a.to_string()).collect::<Vec<_>>().join(", "), // Join multiple addrs
-            "role": "Drone",
-            "latency": 5, // Mock latency
-            "status": p.status
+            // Device type from the peer's capability record; null until one arrives
+            "role": scheduler.capabilities.get(&p.id).map(|r| r.capability.device_type.clone()),
+            "latency": p.latency.map(|rtt| rtt.as_secs_f64() * 1000.0), // ms, null until the first ping
+            "gossip_score": p.gossip_score,
+            "status": p.status,
+            "flags": scheduler.flags(&p.id),
+            "hives": p.topics.iter().filter_map(|t| p2p::hive_name(t)).collect::<Vec<_>>(),
+            // Only circuit addresses known: every connection goes through a relay
+            "relayed": p.address.iter().all(|a| a.iter().any(|p| p == Protocol::P2pCircuit)),
+            "agent_version": p.agent_version,
+            "compatible": p.is_compatible(),
+            "protocols": p.protocols,
+            "observed_addr": p.observed_addr.as_ref().map(|a| a.to_string())
         })
     }).collect();
 
@@ -113,8 +226,15 @@ async fn list_peers(State(state): State<AppState>) -> Json<Value> {
     let total_mem = 0;
     let used_mem = 0;
     
+    let nat = match &scheduler.reachability {
+        Reachability::Unknown => json!({ "status": "unknown" }),
+        Reachability::Public(addr) => json!({ "status": "public", "address": addr.to_string() }),
+        Reachability::Private => json!({ "status": "private" }),
+    };
+
     Json(json!({ 
         "peers": peers,
+        "nat": nat,
         "metrics": {
             "cpu_usage": cpu_usage,
             "total_mem": total_mem,
@@ -124,23 +244,215 @@ async fn list_peers(State(state): State<AppState>) -> Json<Value> {
     }))
 }
 
-async fn list_models() -> Json<Value> {
-    let mut models = Vec::new();
+#[derive(serde::Deserialize)]
+struct CapabilityQuery {
+    max_age: Option<u64>, // Seconds; older records are left out
+}
+
+async fn list_capabilities(State(state): State<AppState>, Query(query): Query<CapabilityQuery>) -> Json<Value> {
+    let scheduler = state.scheduler.lock().unwrap();
+    let max_age = std::time::Duration::from_secs(query.max_age.unwrap_or(u64::MAX));
+    let records: Vec<Value> = scheduler.fresh_capabilities(max_age).map(|(peer_id, record)| {
+        json!({
+            "id": peer_id.to_string(),
+            "local": *peer_id == state.peer_id,
+            "age_secs": record.received.elapsed().map(|age| age.as_secs()).unwrap_or(0),
+            "capability": record.capability
+        })
+    }).collect();
+    Json(json!({ "capabilities": records }))
+}
+
+async fn get_access(State(state): State<AppState>) -> Json<Value> {
+    Json(state.access.lock().unwrap().to_json())
+}
+
+#[derive(serde::Deserialize)]
+struct AccessRequest {
+    action: String, // "ban", "unban", "allow" or "disallow"
+    peer: String,
+}
+
+async fn update_access(State(state): State<AppState>, Json(payload): Json<AccessRequest>) -> Json<Value> {
+    let peer: PeerId = match payload.peer.parse() {
+        Ok(peer) => peer,
+        Err(e) => return Json(json!({ "error": format!("Invalid PeerId {}: {}", payload.peer, e) })),
+    };
+    let change = match payload.action.as_str() {
+        "ban" => AccessChange::Ban(peer),
+        "unban" => AccessChange::Unban(peer),
+        "allow" => AccessChange::Allow(peer),
+        "disallow" => AccessChange::Disallow(peer),
+        other => return Json(json!({ "error": format!("Unknown access action {}", other) })),
+    };
+    match node::update_access(&state.commands, change).await {
+        Ok(()) => Json(state.access.lock().unwrap().to_json()),
+        Err(e) => Json(json!({ "error": e.to_string() })),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct ClearFlagsRequest {
+    peer: String,
+}
+
+/// Forgets a peer's verification flags, e.g. once it runs a fixed build.
+async fn clear_flags(State(state): State<AppState>, Json(payload): Json<ClearFlagsRequest>) -> Json<Value> {
+    match payload.peer.parse::<PeerId>() {
+        Ok(peer) => Json(json!({ "peer": payload.peer, "cleared": state.scheduler.lock().unwrap().clear_flags(&peer) })),
+        Err(e) => Json(json!({ "error": format!("Invalid PeerId {}: {}", payload.peer, e) })),
+    }
+}
+
+/// Starts a drain and returns at once; the node stops when its tasks are done.
+async fn drain(State(state): State<AppState>) -> Json<Value> {
+    let (done, _) = tokio::sync::oneshot::channel();
+    match state.commands.send(NodeCommand::Drain { done }).await {
+        Ok(()) => Json(json!({ "status": "draining" })),
+        Err(_) => Json(json!({ "error": "Node already stopped" })),
+    }
+}
+
+/// Registered models with their metadata, plus model files in `models/`
+/// that were never imported and so cannot be sent to peers by CID.
+async fn list_models(State(state): State<AppState>) -> Json<Value> {
+    let models = state.registry.list().await;
+    let mut unregistered = Vec::new();
     if let Ok(entries) = std::fs::read_dir("models") {
-        for entry in entries {
-            if let Ok(entry) = entry {
-                if let Ok(file_name) = entry.file_name().into_string() {
-                    if file_name.ends_with(".gguf") || file_name.ends_with(".bin") {
-                        models.push(file_name);
-                    }
+        for entry in entries.flatten() {
+            if let Ok(file_name) = entry.file_name().into_string() {
+                let is_model = file_name.ends_with(".gguf") || file_name.ends_with(".bin");
+                // Materialised copies are named by CID
+                let materialised = file_name.split('.').next().is_some_and(|stem| stem.parse::<Cid>().is_ok());
+                if is_model && !materialised && !models.iter().any(|m| m.file_name == file_name) {
+                    unregistered.push(file_name);
                 }
             }
         }
     }
-    Json(json!({ "models": models }))
+    let models: Vec<Value> = models
+        .iter()
+        .map(|m| {
+            let mut value = json!(m);
+            value["has_tokenizer"] = json!(m.has_tokenizer());
+            value
+        })
+        .collect();
+    Json(json!({ "models": models, "unregistered": unregistered }))
+}
+
+/// Streams a multipart field to `path` through a temporary file, so large
+/// models never sit in memory and a failed upload leaves nothing behind.
+async fn save_field(mut field: Field<'_>, path: &std::path::Path) -> anyhow::Result<()> {
+    let tmp = path.with_extension("part");
+    let mut file = tokio::fs::File::create(&tmp).await?;
+    let copied = async {
+        while let Some(chunk) = field.chunk().await? {
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        anyhow::Ok(())
+    }
+    .await;
+    if copied.is_err() {
+        let _ = tokio::fs::remove_file(&tmp).await;
+        return copied;
+    }
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+/// Serves stored content by CID, honouring a single `Range: bytes=` header.
+async fn get_content(State(state): State<AppState>, Path(cid): Path<String>, headers: HeaderMap) -> Response {
+    let cid: Cid = match cid.parse() {
+        Ok(cid) => cid,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+    let size = match state.storage.size(&cid).await {
+        Ok(Some(size)) => size,
+        Ok(None) => return (StatusCode::NOT_FOUND, "Not in local storage").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+    let range = headers.get(header::RANGE).and_then(|v| v.to_str().ok()).map(|v| parse_range(v, size));
+    let (status, start, end) = match range {
+        None => (StatusCode::OK, 0, size),
+        Some(Some((start, end))) => (StatusCode::PARTIAL_CONTENT, start, end),
+        Some(None) => {
+            return (StatusCode::RANGE_NOT_SATISFIABLE, [(header::CONTENT_RANGE, format!("bytes */{}", size))]).into_response();
+        }
+    };
+    let reader = match state.storage.range_reader(&cid, start, end - start).await {
+        Ok(Some(reader)) => reader,
+        Ok(None) => return (StatusCode::NOT_FOUND, "Not in local storage").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+    let mut response = Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(header::CONTENT_LENGTH, end - start)
+        .header(header::ACCEPT_RANGES, "bytes");
+    if status == StatusCode::PARTIAL_CONTENT {
+        response = response.header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end - 1, size));
+    }
+    response
+        .body(Body::from_stream(tokio_util::io::ReaderStream::new(reader)))
+        .unwrap_or_else(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())
+}
+
+#[derive(serde::Deserialize)]
+struct ReplicationRequest {
+    factor: u32,
+}
+
+fn replication_json(status: &ReplicationStatus) -> Value {
+    json!({
+        "cid": status.cid,
+        "factor": status.factor,
+        "holders": status.holders.iter().map(|p| p.to_string()).collect::<Vec<_>>(),
+        "missing": status.missing(),
+    })
 }
 
-async fn upload_model(mut multipart: Multipart) -> Json<Value> {
+/// Which nodes keep a replica of a CID, asked live of the connected peers.
+async fn get_replication(State(state): State<AppState>, Path(cid): Path<String>) -> Response {
+    let cid: Cid = match cid.parse() {
+        Ok(cid) => cid,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+    match state.replicator.status(&cid).await {
+        Ok(status) => Json(replication_json(&status)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// Sets the replication factor of locally stored content; 0 clears it.
+async fn set_replication(State(state): State<AppState>, Path(cid): Path<String>, Json(req): Json<ReplicationRequest>) -> Response {
+    let cid: Cid = match cid.parse() {
+        Ok(cid) => cid,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+    if !matches!(state.storage.size(&cid).await, Ok(Some(_))) {
+        return (StatusCode::NOT_FOUND, "Not in local storage").into_response();
+    }
+    match state.replicator.set_factor(&cid, req.factor).await {
+        Ok(status) => Json(replication_json(&status)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// Parses `bytes=a-b`, `bytes=a-` or `bytes=-n` into a half-open range within
+/// `size`; `None` if it cannot be satisfied.
+fn parse_range(value: &str, size: u64) -> Option<(u64, u64)> {
+    let (start, end) = value.strip_prefix("bytes=")?.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => (size.saturating_sub(suffix.parse().ok()?), size),
+        (start, "") => (start.parse().ok()?, size),
+        (start, end) => (start.parse().ok()?, end.parse::<u64>().ok()?.saturating_add(1).min(size)),
+    };
+    (start < end).then_some((start, end))
+}
+
+async fn upload_model(State(state): State<AppState>, mut multipart: Multipart) -> Json<Value> {
     let mut repo_id: Option<String> = None;
     let mut file_name: Option<String> = None;
     
@@ -164,33 +476,38 @@ async fn upload_model(mut multipart: Multipart) -> Json<Value> {
                     }
                 }
                 "model" => {
-                    if let Some(fname) = field.file_name() {
-                        let fname = fname.to_string();
-                        let path = format!("models/{}", fname);
-                        file_name = Some(fname.clone());
-                        
-                        // Read bytes
-                         let data = match field.bytes().await {
-                            Ok(bytes) => bytes,
-                            Err(e) => return Json(json!({ "error": format!("Failed to read bytes: {}", e) })),
-                        };
-
-                        if let Ok(mut file) = std::fs::File::create(&path) {
-                            if file.write_all(&data).is_err() {
-                                 return Json(json!({ "error": "Failed to write model file" }));
-                            }
-                            println!("Uploaded model: {}", path);
-                        }
+                    // Only the last path component, so uploads cannot escape models/
+                    let Some(fname) = field.file_name().and_then(|f| std::path::Path::new(f).file_name()) else {
+                        continue;
+                    };
+                    let fname = fname.to_string_lossy().to_string();
+                    let path = std::path::Path::new("models").join(&fname);
+                    file_name = Some(fname.clone());
+
+                    if let Err(e) = save_field(field, &path).await {
+                        return Json(json!({ "error": format!("Failed to save model: {}", e) }));
                     }
+                    println!("Uploaded model: {}", path.display());
                 }
                 _ => {}
             }
         }
     }
 
+    let Some(fname) = file_name else {
+        return Json(json!({ "error": "No model file found in request" }));
+    };
+    let path = std::path::Path::new("models").join(&fname);
+    let info = match state.registry.import(&path, Some(&fname), None).await {
+        Ok(info) => info,
+        Err(e) => return Json(json!({ "error": format!("Failed to register model: {}", e) })),
+    };
+
     // After processing fields, if we have both model name and repo_id, fetch tokenizer
-    if let (Some(model_name), Some(repo)) = (file_name.clone(), repo_id) {
+    if let (Some(model_name), Some(repo)) = (Some(fname.clone()), repo_id) {
         println!("Attempting to auto-download tokenizer from {}", repo);
+        let registry = state.registry.clone();
+        let cid = info.cid.clone();
         tokio::spawn(async move {
             let url = format!("https://huggingface.co/{}/resolve/main/tokenizer.json", repo);
             let target_path = format!("models/{}.tokenizer.json", model_name);
@@ -199,9 +516,14 @@ async fn upload_model(mut multipart: Multipart) -> Json<Value> {
                 Ok(resp) => {
                     if resp.status().is_success() {
                         if let Ok(bytes) = resp.bytes().await {
-                            if let Ok(mut file) = std::fs::File::create(&target_path) {
-                                let _ = file.write_all(&bytes);
-                                println!("Downloaded tokenizer to {}", target_path);
+                            match tokio::fs::write(&target_path, &bytes).await {
+                                Ok(()) => {
+                                    println!("Downloaded tokenizer to {}", target_path);
+                                    if let Err(e) = registry.attach_tokenizer(&cid, std::path::Path::new(&target_path)).await {
+                                        println!("Failed to register tokenizer: {}", e);
+                                    }
+                                }
+                                Err(e) => println!("Failed to save tokenizer to {}: {}", target_path, e),
                             }
                         }
                     } else {
@@ -214,84 +536,13 @@ async fn upload_model(mut multipart: Multipart) -> Json<Value> {
         });
     }
 
-    if let Some(fname) = file_name {
-        Json(json!({ "status": "uploaded", "filename": fname }))
-    } else {
-        Json(json!({ "error": "No model file found in request" }))
-    }
-}
-
-#[derive(serde::Deserialize)]
-struct InferenceRequest {
-    model_path: Option<String>,
-    tokenizer_path: Option<String>,
-    prompt: String,
+    Json(json!({ "status": "uploaded", "filename": fname, "cid": info.cid, "model": info }))
 }
 
-async fn run_inference(
-    State(state): State<AppState>,
-    Json(payload): Json<InferenceRequest>,
-) -> Json<Value> {
-    let model_path_raw = payload.model_path.unwrap_or_else(|| "models/tinyllama-1.1b-chat-v1.0.Q4_K_S.gguf".to_string());
-    let model_path = if std::path::Path::new(&model_path_raw).exists() {
-        model_path_raw.clone()
-    } else {
-        format!("models/{}", model_path_raw)
-    };
-    // Check for specific tokenizer
-    let specific_tokenizer = format!("{}.tokenizer.json", model_path);
-    let tokenizer_path = if std::path::Path::new(&specific_tokenizer).exists() {
-        specific_tokenizer
-    } else {
-        payload.tokenizer_path.unwrap_or_else(|| "tokenizer.json".to_string())
-    };
-    
-    let prompt_raw = payload.prompt;
-    
-    // Simple Llama 2 Chat Template
-    let prompt = prompt_raw;
-
-    println!("Received inference request: {}", prompt);
-    println!("Using tokenizer: {}", tokenizer_path);
-
-
-
-    // Dynamic Discovery: Check for peers in the swarm
-    let peers = {
-        let scheduler = state.scheduler.lock().unwrap();
-        scheduler.peers.clone()
-    };
-    
-    // Find the first peer with a valid IP4 address
-    let mut worker_rpc_url = None;
-    
-    for (peer_id, info) in peers {
-        for addr in info.address {
-            // Extract IP from Multiaddr (e.g., /ip4/192.168.1.10/tcp/1234)
-            // We need to parse it string-wise or use Multiaddr methods
-            let addr_str = addr.to_string();
-            if addr_str.contains("/ip4/") && !addr_str.contains("127.0.0.1") {
-                // Parse out the IP. Hacky string parsing for now.
-                // Format is usually /ip4/<ip>/tcp/<port>
-                let parts: Vec<&str> = addr_str.split('/').collect();
-                if parts.len() >= 3 && parts[1] == "ip4" {
-                    let ip = parts[2];
-                    // Assume default worker port 50052
-                    worker_rpc_url = Some(format!("{}:50052", ip));
-                    println!("Discovered Peer {} at {}. Using RPC: {}", peer_id, ip, worker_rpc_url.as_ref().unwrap());
-                    break;
-                }
-            }
-        }
-        if worker_rpc_url.is_some() {
-            break;
-        }
-    }
-
-    // HOT SWAP LOGIC
-    // Check if we need to switch the model
-    let desired_model = model_path.clone();
-    
+/// Restarts llama-server with `desired_model` unless it already serves it.
+/// Holds the config and process locks throughout and waits for the new
+/// server to come up, so callers run it with `spawn_blocking`.
+fn switch_model(state: &AppState, desired_model: &str, worker_rpc_url: Option<String>) -> Result<(), String> {
     // For now, we reuse the RPC endpoint from the startup config if available, 
     // or from dynamic discovery if it's a new peer.
     // If we have a current config, use its RPC endpoint, otherwise use the discovered one.
@@ -342,15 +593,15 @@ async fn run_inference(
         // We need an RPC endpoint. 
         // If we are hot-swapping, we assume we want to keep using the same worker?
         if target_rpc.is_empty() {
-             return Json(json!({ "error": "Cannot hot-swap: No RPC endpoint known." }));
+             return Err("Cannot hot-swap: No RPC endpoint known.".to_string());
         }
 
-        match LlamaCppBackend::start_server(&desired_model, port, &target_rpc, target_ngl) {
+        match LlamaCppBackend::start_server(desired_model, port, &target_rpc, target_ngl) {
              Ok(child) => {
                  println!("New llama-server started on port {}", port);
                  *server_process_guard = Some(child);
                  *current_config_guard = Some(ServerConfig {
-                     model_path: desired_model.clone(),
+                     model_path: desired_model.to_string(),
                      rpc_endpoint: target_rpc,
                      ngl: target_ngl,
                  });
@@ -363,14 +614,142 @@ async fn run_inference(
                  std::thread::sleep(std::time::Duration::from_secs(2));
              },
              Err(e) => {
-                 return Json(json!({ "error": format!("Failed to start new model server: {}", e) }));
+                 return Err(format!("Failed to start new model server: {}", e));
              }
         }
     }
+    Ok(())
+}
+
+/// Describes a task for peers: the registered model by CID when there is one,
+/// and the model file by name and download URL for peers that predate CIDs.
+fn task_spec(prompt: &str, model_path: &str, registered: Option<&ModelInfo>) -> TaskSpec {
+    let my_local_ip = local_ip_address::local_ip().map(|ip| ip.to_string()).unwrap_or("127.0.0.1".to_string());
+    let model_filename = std::path::Path::new(model_path).file_name().unwrap_or_default().to_string_lossy().to_string();
+    let download_url = format!("http://{}:3000/models/{}", my_local_ip, model_filename);
+    TaskSpec {
+        prompt: prompt.to_string(),
+        model_name: model_filename,
+        download_url: Some(download_url),
+        model: registered.map(ModelInfo::model_ref),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct InferenceRequest {
+    model_path: Option<String>,
+    tokenizer_path: Option<String>,
+    prompt: String,
+    verify_replicas: Option<usize>, // Opt-in redundant execution for this request
+}
+
+async fn run_inference(
+    State(state): State<AppState>,
+    Json(payload): Json<InferenceRequest>,
+) -> Json<Value> {
+    let model_path_raw = payload.model_path.unwrap_or_else(|| "models/tinyllama-1.1b-chat-v1.0.Q4_K_S.gguf".to_string());
+    // A CID or alias picks a registered model; anything else is a path
+    let registered = state.registry.resolve(&model_path_raw).await;
+    let (registered_path, registered_tokenizer) = match &registered {
+        Some(info) => match state.registry.paths(info).await {
+            Ok((model, tokenizer)) => (Some(model.to_string_lossy().to_string()), tokenizer.map(|t| t.to_string_lossy().to_string())),
+            Err(e) => return Json(json!({ "error": format!("Model {} unavailable: {}", info.cid, e) })),
+        },
+        None => (None, None),
+    };
+    let model_path = if let Some(path) = registered_path {
+        path
+    } else if std::path::Path::new(&model_path_raw).exists() {
+        model_path_raw.clone()
+    } else {
+        format!("models/{}", model_path_raw)
+    };
+    // Check for specific tokenizer
+    let specific_tokenizer = format!("{}.tokenizer.json", model_path);
+    let tokenizer_path = if let Some(tokenizer) = registered_tokenizer {
+        tokenizer
+    } else if std::path::Path::new(&specific_tokenizer).exists() {
+        specific_tokenizer
+    } else {
+        payload.tokenizer_path.unwrap_or_else(|| "tokenizer.json".to_string())
+    };
+    
+    let prompt_raw = payload.prompt;
+    
+    // Simple Llama 2 Chat Template
+    let prompt = prompt_raw;
+
+    println!("Received inference request: {}", prompt);
+    println!("Using tokenizer: {}", tokenizer_path);
+
+    // Verified tasks run on several peers and are compared before answering
+    let verification = match payload.verify_replicas {
+        Some(replicas) => Some(VerificationConfig { replicas, ..state.verification.unwrap_or_default() }),
+        None => state.verification,
+    };
+    if let Some(config) = verification {
+        let spec = task_spec(&prompt, &model_path, registered.as_ref());
+        let task_id = uuid::Uuid::new_v4().to_string();
+        return match verification::run_verified(&state.scheduler, &state.commands, &task_id, spec, config).await {
+            Ok(result) => Json(json!({ "result": result })),
+            Err(e) => Json(json!({ "error": format!("Verification failed: {}", e) })),
+        };
+    }
+
+    // Tensor mode splits every layer across all peers that take work
+    if state.parallel_mode == ParallelMode::Tensor {
+        let mut ranks: Vec<PeerId> = state.scheduler.lock().unwrap().peers.values().filter(|p| p.accepts_work()).map(|p| p.id).collect();
+        ranks.sort();
+        println!("Splitting the task across {} peers...", ranks.len());
+        let spec = task_spec(&prompt, &model_path, registered.as_ref());
+        let task_id = uuid::Uuid::new_v4().to_string();
+        return match tensor_parallel::dispatch(&state.commands, &ranks, task_id, spec).await {
+            Ok(result) => Json(json!({ "result": result })),
+            Err(TaskError::Timeout) => Json(json!({ "error": "Distributed inference timed out" })),
+            Err(e) => Json(json!({ "error": format!("Remote Error: {}", e) })),
+        };
+    }
+
+
+    // Dynamic Discovery: Check for peers in the swarm
+    let peers = {
+        let scheduler = state.scheduler.lock().unwrap();
+        scheduler.peers.clone()
+    };
+    
+    // Find the first peer with a valid IP4 address
+    let mut worker_rpc_url = None;
+    
+    for (peer_id, info) in peers {
+        for addr in info.address {
+            // Extract IP from Multiaddr (e.g., /ip4/192.168.1.10/tcp/1234)
+            // We need to parse it string-wise or use Multiaddr methods
+            let addr_str = addr.to_string();
+            if addr_str.contains("/ip4/") && !addr_str.contains("127.0.0.1") {
+                // Parse out the IP. Hacky string parsing for now.
+                // Format is usually /ip4/<ip>/tcp/<port>
+                let parts: Vec<&str> = addr_str.split('/').collect();
+                if parts.len() >= 3 && parts[1] == "ip4" {
+                    let ip = parts[2];
+                    // Assume default worker port 50052
+                    worker_rpc_url = Some(format!("{}:50052", ip));
+                    println!("Discovered Peer {} at {}. Using RPC: {}", peer_id, ip, worker_rpc_url.as_ref().unwrap());
+                    break;
+                }
+            }
+        }
+        if worker_rpc_url.is_some() {
+            break;
+        }
+    }
 
-    // Release locks before long-running operation
-    drop(current_config_guard);
-    drop(server_process_guard);
+    // HOT SWAP LOGIC
+    let (switch_state, switch_path) = (state.clone(), model_path.clone());
+    match tokio::task::spawn_blocking(move || switch_model(&switch_state, &switch_path, worker_rpc_url)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => return Json(json!({ "error": e })),
+        Err(_) => return Json(json!({ "error": "Internal server error (task panic)" })),
+    }
 
     // Now call the server
     if let Some(port) = state.llama_server_port { // This port comes from initial setup, 8081.
@@ -407,95 +786,73 @@ async fn run_inference(
     }
     
     // Fallback if somehow port is missing but we shouldn't get here easily with the new logic
-    return Json(json!({ "error": "Server configuration state invalid." }));
-
-    // Fallback to Local Inference if no peers found
-    println!("No suitable peers found. Running locally.");
-    
-    let peer_count = state.scheduler.lock().unwrap().peers.len(); // Re-check for other logic if needed, but we already tried.
-    
-    if false { // Disable the old "Broadcasting task" block since we handled it above via RPC
+    Json(json!({ "error": "Server configuration state invalid." }))
+}
 
-        // Distributed Inference
-        println!("Broadcasting task to {} peers...", peer_count);
-        let task_id = uuid::Uuid::new_v4().to_string();
-        let (tx, rx) = oneshot::channel();
-        
-        {
-            state.pending_requests.lock().unwrap().insert(task_id.clone(), tx);
-        }
-        
-        let my_local_ip = local_ip_address::local_ip().map(|ip| ip.to_string()).unwrap_or("127.0.0.1".to_string());
-        let model_filename = std::path::Path::new(&model_path).file_name().unwrap_or_default().to_string_lossy().to_string();
-        let download_url = format!("http://{}:3000/models/{}", my_local_ip, model_filename);
-
-        let msg = Message::TaskRequest {
-            task_id: task_id.clone(),
-            prompt: prompt,
-            model_name: model_filename, 
-            download_url: Some(download_url),
-            layer_range: None, // Default to full load for now (Replication)
-        };
-        
-        if let Err(e) = state.p2p_sender.send(msg).await {
-            return Json(json!({ "error": format!("Failed to send to P2P loop: {}", e) }));
-        }
-        
-        // Wait for response with timeout
-        match tokio::time::timeout(std::time::Duration::from_secs(1200), rx).await {
-            Ok(Ok(Ok(result))) => Json(json!({ "result": result })),
-            Ok(Ok(Err(e))) => Json(json!({ "error": format!("Remote Error: {}", e) })),
-            Ok(Err(_)) => Json(json!({ "error": "Internal channel closed" })),
-            Err(_) => {
-                // Remove from pending on timeout
-                state.pending_requests.lock().unwrap().remove(&task_id);
-                println!("Task {} timed out after 1200s", task_id);
-                Json(json!({ "error": "Distributed inference timed out (1200s limit exceeded)" }))
-            }
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::{self, TestHive, MODEL_NAME, PROMPT};
+    use crate::node::NodeConfig;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn inference_request_is_verified_on_peers() -> anyhow::Result<()> {
+        let port = harness::free_port()?;
+        let hive = TestHive::start_with(3, |i, config| NodeConfig { api_port: (i == 0).then_some(port), ..config }).await?;
+        let expected = harness::local_output(&hive.models_dir(0).join(MODEL_NAME)).await?;
+
+        let request = json!({ "model_path": MODEL_NAME, "prompt": PROMPT, "verify_replicas": 2 });
+        let reply = harness::api_post(port, "/api/inference", request).await?;
+        assert_eq!(reply["result"], expected, "{reply}");
+        Ok(())
+    }
 
-    } else {
-        // Local Inference (Fallback)
-        println!("No peers found. Running locally.");
-        
-        let inference_result = tokio::time::timeout(std::time::Duration::from_secs(300), tokio::task::spawn_blocking(move || {
-            let mut engine_lock = state.inference_engine.lock().unwrap();
-            
-            let should_reload = if let Some(engine) = engine_lock.as_ref() {
-                engine.model_path != model_path
-            } else {
-                true
-            };
-
-            if should_reload {
-                println!("Loading model: {}", model_path);
-                match InferenceEngine::load(&model_path, &tokenizer_path, None) {
-                    Ok(new_engine) => {
-                        *engine_lock = Some(new_engine);
-                    },
-                    Err(e) => {
-                        return Err(format!("Failed to load model: {}", e));
-                    }
-                }
-            } else {
-                 println!("Using cached model: {}", model_path);
-            }
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn tensor_mode_request_is_split_across_peers() -> anyhow::Result<()> {
+        let port = harness::free_port()?;
+        let hive = TestHive::start_with(3, |i, config| match i {
+            0 => NodeConfig { api_port: Some(port), parallel_mode: ParallelMode::Tensor, ..config },
+            _ => config,
+        })
+        .await?;
+        let expected = harness::local_output(&hive.models_dir(0).join(MODEL_NAME)).await?;
 
-            if let Some(engine) = engine_lock.as_mut() {
-                match engine.generate(&prompt, 20) { // Reduced to 20 tokens for speed
-                    Ok(result) => Ok(result),
-                    Err(e) => Err(format!("Inference failed: {}", e)),
-                }
-            } else {
-                Err("Engine not initialized".to_string())
-            }
-        })).await;
+        let reply = harness::api_post(port, "/api/inference", json!({ "model_path": MODEL_NAME, "prompt": PROMPT })).await?;
+        assert_eq!(reply["result"], expected, "{reply}");
+        Ok(())
+    }
 
-        match inference_result {
-            Ok(Ok(Ok(result))) => Json(json!({ "result": result })),
-            Ok(Ok(Err(e))) => Json(json!({ "error": e })),
-            Ok(Err(_join_err)) => Json(json!({ "error": "Internal server error (task panic)" })),
-            Err(_elapsed) => Json(json!({ "error": "Inference timed out (engine too slow or stuck)" })),
-        }
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn admin_routes_need_a_local_tool_or_the_token() -> anyhow::Result<()> {
+        let (open, guarded) = (harness::free_port()?, harness::free_port()?);
+        let _hive = TestHive::start_with(2, |i, config| match i {
+            0 => NodeConfig { api_port: Some(open), ..config },
+            _ => NodeConfig { api_port: Some(guarded), admin_token: Some("secret".to_string()), ..config },
+        })
+        .await?;
+        let ban = json!({ "peer": PeerId::random().to_string(), "action": "ban" });
+
+        // Without a token: local tools only, never a web page
+        let (status, _) = harness::api_post_with(open, "/api/access", ban.clone(), &[("Origin", "https://evil.example")]).await?;
+        assert_eq!(status, 401);
+        let (status, reply) = harness::api_post_with(open, "/api/access", ban.clone(), &[]).await?;
+        assert_eq!(status, 200, "{reply}");
+
+        let (status, _) = harness::api_post_with(guarded, "/api/admin/drain", json!({}), &[]).await?;
+        assert_eq!(status, 401);
+        let unflag = json!({ "peer": PeerId::random().to_string() });
+        let (status, _) = harness::api_post_with(guarded, "/api/admin/flags", unflag.clone(), &[]).await?;
+        assert_eq!(status, 401);
+        let (status, reply) = harness::api_post_with(guarded, "/api/admin/flags", unflag, &[("Authorization", "Bearer secret")]).await?;
+        assert_eq!((status, reply["cleared"].as_u64()), (200, Some(0)), "{reply}");
+        // Pinning content sends replicas to peers, so it is an admin route too
+        let path = format!("/api/replication/{}", Cid::sha256(b"content"));
+        let (status, _) = harness::api_send(reqwest::Method::PUT, guarded, &path, json!({ "factor": 3 }), &[]).await?;
+        assert_eq!(status, 401);
+        let (status, _) = harness::api_post_with(guarded, "/api/access", ban.clone(), &[("Authorization", "Bearer wrong")]).await?;
+        assert_eq!(status, 401);
+        let (status, reply) = harness::api_post_with(guarded, "/api/access", ban, &[("Authorization", "Bearer secret")]).await?;
+        assert_eq!(status, 200, "{reply}");
+        Ok(())
     }
 }
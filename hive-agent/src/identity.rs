@@ -0,0 +1,152 @@
+use anyhow::{anyhow, Result};
+use libp2p::identity::Keypair;
+use libp2p::PeerId;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use crate::blobstore::StorageBackend;
+
+/// File name of the node keypair.
+pub const KEY_FILE: &str = "identity.key";
+
+/// Where the node keypair lives: next to the storage directory, or next to
+/// the config file when content is not kept on disk. Either way it moves
+/// with the configured data rather than with the working directory, so the
+/// PeerId does not change with where the agent is started from.
+pub fn key_path(config_path: &Path, storage: &StorageBackend) -> PathBuf {
+    let dir = match storage {
+        StorageBackend::Fs { root } => root.parent(),
+        _ => config_path.parent(),
+    };
+    dir.unwrap_or(Path::new("")).join(KEY_FILE)
+}
+
+/// Reads a protobuf-encoded keypair, or `None` if the file does not exist.
+/// A key file others can read is restricted to the owner first.
+pub fn load(path: &Path) -> Result<Option<Keypair>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(path)?.permissions().mode();
+        if mode & 0o077 != 0 {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+            println!("Key file {} was readable by others ({:o}); restricted it to 0600", path.display(), mode & 0o777);
+        }
+    }
+    let bytes = std::fs::read(path)?;
+    let keypair = Keypair::from_protobuf_encoding(&bytes)
+        .map_err(|e| anyhow!("Invalid keypair in {}: {}", path.display(), e))?;
+    Ok(Some(keypair))
+}
+
+/// Writes the keypair readable by the owner only. The file is written under a
+/// temporary name and renamed, so a crash never leaves a half-written key.
+pub fn save(path: &Path, keypair: &Keypair) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let bytes = keypair.to_protobuf_encoding()?;
+    let tmp = path.with_extension("tmp");
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&tmp)?;
+    // `mode` applies only when the file is created, not to a leftover one
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    drop(file);
+
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Loads the node identity, creating and persisting a new one on first start.
+pub fn load_or_generate(path: &Path) -> Result<Keypair> {
+    if let Some(keypair) = load(path)? {
+        return Ok(keypair);
+    }
+    let keypair = Keypair::generate_ed25519();
+    save(path, &keypair)?;
+    println!("Generated new node identity {} at {}", PeerId::from(keypair.public()), path.display());
+    Ok(keypair)
+}
+
+/// Replaces the identity with a fresh one; see `replace`.
+pub fn rotate(path: &Path) -> Result<(Option<PeerId>, Keypair)> {
+    let keypair = Keypair::generate_ed25519();
+    let previous = replace(path, &keypair)?;
+    Ok((previous, keypair))
+}
+
+/// Makes `keypair` the identity. The previous key is kept next to it with an
+/// `.old` extension so a mistaken rotation or import can be undone by hand.
+pub fn replace(path: &Path, keypair: &Keypair) -> Result<Option<PeerId>> {
+    let previous = load(path)?;
+    if let Some(old) = &previous {
+        save(&backup_path(path), old)?;
+    }
+    save(path, keypair)?;
+    Ok(previous.map(|k| PeerId::from(k.public())))
+}
+
+pub fn backup_path(path: &Path) -> PathBuf {
+    path.with_extension("old")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identity_survives_restart_and_rotation_changes_it() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("identity.key");
+
+        let first = PeerId::from(load_or_generate(&path)?.public());
+        let second = PeerId::from(load_or_generate(&path)?.public());
+        assert_eq!(first, second);
+
+        let (previous, rotated) = rotate(&path)?;
+        assert_eq!(previous, Some(first));
+        assert_ne!(PeerId::from(rotated.public()), first);
+        assert_eq!(load(&backup_path(&path))?.map(|k| PeerId::from(k.public())), Some(first));
+
+        let imported = Keypair::generate_ed25519();
+        assert_eq!(replace(&path, &imported)?, Some(PeerId::from(rotated.public())));
+        assert_eq!(load(&backup_path(&path))?.map(|k| PeerId::from(k.public())), Some(PeerId::from(rotated.public())));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path)?.permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+
+            // Loosened by hand, tightened again on the next load
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644))?;
+            assert!(load(&path)?.is_some());
+            assert_eq!(std::fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn key_follows_the_configured_data() {
+        let config = Path::new("/etc/hive/config.json");
+        let fs = StorageBackend::Fs { root: PathBuf::from("/srv/hive/storage") };
+        assert_eq!(key_path(config, &fs), Path::new("/srv/hive/identity.key"));
+        assert_eq!(key_path(config, &StorageBackend::Memory), Path::new("/etc/hive/identity.key"));
+        assert_eq!(key_path(Path::new(".hive/config.json"), &StorageBackend::default()), Path::new(".hive/identity.key"));
+    }
+}
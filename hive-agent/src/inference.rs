@@ -2,9 +2,12 @@ use anyhow::{Error, Result};
 use crate::model::sharded_llama as model;
 use candle_core::{Tensor, Device};
 use candle_transformers::generation::LogitsProcessor;
-use model::ModelWeights;
+use model::{AllReduce, ModelWeights, TensorSplit};
 use tokenizers::Tokenizer;
 
+/// Sampler seed used when a task does not ask for one.
+pub const DEFAULT_SEED: u64 = 299792458;
+
 pub struct InferenceEngine {
     model: ModelWeights,
     tokenizer: Tokenizer,
@@ -13,7 +16,12 @@ pub struct InferenceEngine {
 }
 
 impl InferenceEngine {
-    pub fn load(model_path: &str, tokenizer_path: &str, layer_range: Option<(usize, usize)>) -> Result<Self> {
+    pub fn load(
+        model_path: &str,
+        tokenizer_path: &str,
+        layer_range: Option<(usize, usize)>,
+        tensor_split: Option<TensorSplit>,
+    ) -> Result<Self> {
         println!("Loading model from {}", model_path);
         let device = {
             #[cfg(feature = "cuda")]
@@ -41,8 +49,8 @@ impl InferenceEngine {
         println!("File opened");
         let content = candle_core::quantized::gguf_file::Content::read(&mut file)?;
         println!("Content read");
-        let model = ModelWeights::from_gguf(content, &mut file, &device, layer_range)?;
-        println!("Model loaded (Range: {:?})", layer_range);
+        let model = ModelWeights::from_gguf(content, &mut file, &device, layer_range, tensor_split)?;
+        println!("Model loaded (Range: {:?}, Split: {:?})", layer_range, tensor_split);
         
         let tokenizer = Tokenizer::from_file(tokenizer_path).map_err(Error::msg)?;
         println!("Tokenizer loaded");
@@ -55,7 +63,30 @@ impl InferenceEngine {
         })
     }
 
+    /// Connects this engine to the other tensor-parallel ranks.
+    pub fn set_all_reduce(&mut self, reducer: Box<dyn AllReduce>) {
+        self.model.set_all_reduce(reducer);
+    }
+
+    /// Runs the prompt through the model once and returns the next-token logits.
+    /// Used to compare sharded deployments against a single-node reference.
+    pub fn prompt_logits(&mut self, prompt: &str) -> Result<Vec<f32>> {
+        let tokens = self.tokenizer
+            .encode(prompt, true)
+            .map_err(Error::msg)?
+            .get_ids()
+            .to_vec();
+        let input = Tensor::new(tokens.as_slice(), &self.device)?.unsqueeze(0)?;
+        let logits = self.model.forward(&input, 0)?;
+        let logits = logits.squeeze(0)?.to_dtype(candle_core::DType::F32)?;
+        Ok(logits.to_vec1::<f32>()?)
+    }
+
     pub fn generate(&mut self, prompt: &str, sample_len: usize) -> Result<String> {
+        self.generate_seeded(prompt, sample_len, DEFAULT_SEED)
+    }
+
+    pub fn generate_seeded(&mut self, prompt: &str, sample_len: usize, seed: u64) -> Result<String> {
         println!("Encoding prompt...");
         let mut tokens = self.tokenizer
             .encode(prompt, true)
@@ -64,7 +95,7 @@ impl InferenceEngine {
             .to_vec();
         println!("Prompt encoded. Tokens: {}", tokens.len());
             
-        let mut logits_processor = LogitsProcessor::new(299792458, Some(0.8), Some(0.95));
+        let mut logits_processor = LogitsProcessor::new(seed, Some(0.8), Some(0.95));
         let mut new_tokens = vec![];
 
         println!("Starting generation loop...");
@@ -8,32 +8,44 @@ mod http_api;
 mod message;
 mod model;
 mod backend;
+mod tensor_parallel;
+mod node;
+mod verification;
+mod identity;
+mod config;
+mod capability;
+mod access;
+mod encryption;
+mod blobstore;
+mod s3;
+mod exchange;
+mod registry;
+mod replication;
+mod matmul;
+
+#[cfg(test)]
+mod harness;
 
 use clap::{Parser, Subcommand};
-use libp2p::{
-    core::upgrade,
-    gossipsub, mdns, noise,
-    swarm::SwarmEvent,
-    tcp, yamux, PeerId, Transport,
-};
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
-use std::time::Duration;
+use hive_core::Cid;
 use tracing::info;
-use p2p::HiveBehavior;
 use storage::Storage;
 use compute::ComputeEngine;
-use scheduler::Scheduler;
 use inference::InferenceEngine;
-use futures::future::Either;
-use futures::StreamExt;
-use std::sync::{Arc, Mutex};
+use tensor_parallel::ParallelMode;
+use encryption::KeySource;
+use node::{Node, NodeConfig};
+use registry::ModelRegistry;
+use verification::{MismatchPolicy, VerificationConfig};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     #[command(subcommand)]
     command: Option<Commands>,
+    /// Agent config file with storage, bootstrap peers and discovery settings
+    #[arg(long, global = true, default_value = config::DEFAULT_CONFIG_PATH)]
+    config: String,
 }
 
 #[derive(Subcommand, Debug)]
@@ -46,6 +58,17 @@ enum Commands {
         rpc: Option<String>, // e.g., 192.168.x.20:50052
         #[arg(long, default_value_t = 99)]
         ngl: usize,
+        /// How distributed tasks split the model across peers
+        #[arg(long, value_enum, default_value_t = ParallelMode::Pipeline)]
+        parallelism: ParallelMode,
+        /// Run distributed tasks on this many peers and compare their outputs
+        #[arg(long)]
+        verify_replicas: Option<usize>,
+        /// What to do when verification replicas disagree
+        #[arg(long, value_enum, default_value_t = MismatchPolicy::Retry)]
+        on_mismatch: MismatchPolicy,
+        #[command(flatten)]
+        network: NetworkArgs,
     },
     /// Upload a file to the Hive
     Upload {
@@ -53,11 +76,40 @@ enum Commands {
     },
     /// Retrieve a file from the Hive
     Get {
-        cid: String,
+        cid: Cid,
+        /// Seconds to keep looking for peers that hold the file
+        #[arg(long, default_value_t = 30)]
+        timeout: u64,
+        #[command(flatten)]
+        network: NetworkArgs,
+    },
+    /// Inspect and maintain local content storage
+    Storage {
+        #[command(subcommand)]
+        action: StorageCommand,
+    },
+    /// List, import and name models in the model registry
+    Models {
+        #[command(subcommand)]
+        action: ModelsCommand,
     },
-    /// Run a compute task (Matrix Multiplication)
+    /// Multiply two random square matrices on the hive, tile by tile
     Compute {
         size: usize,
+        /// Rows and columns per tile; every pair of tiles is one task
+        #[arg(long, default_value_t = 256)]
+        tile: usize,
+        /// Seconds to wait for peers before multiplying locally
+        #[arg(long, default_value_t = 10)]
+        timeout: u64,
+        /// Also multiply locally and check the result against it
+        #[arg(long)]
+        verify: bool,
+        /// Peers that must agree on every tile product; flags those that do not
+        #[arg(long, default_value_t = 1)]
+        replicas: usize,
+        #[command(flatten)]
+        network: NetworkArgs,
     },
     /// Run inference on a model
     Infer {
@@ -68,6 +120,28 @@ enum Commands {
         #[arg(long)]
         prompt: String,
     },
+    /// Compare a tensor-parallel split of a model against the single-node output
+    ValidateTp {
+        #[arg(long)]
+        model: String,
+        #[arg(long)]
+        tokenizer: String,
+        #[arg(long)]
+        prompt: String,
+        #[arg(long, default_value_t = 2)]
+        world_size: usize,
+        #[arg(long, default_value_t = 1e-3)]
+        tolerance: f32,
+    },
+    /// Show or manage this node's persistent identity (PeerId)
+    Identity {
+        #[command(subcommand)]
+        action: IdentityCommand,
+    },
+    /// Write a new pre-shared key for a private hive
+    SwarmKey {
+        path: String,
+    },
     /// Setup the agent environment (builds llama.cpp in WSL)
     Setup,
     /// Start as a Worker (RPC Server)
@@ -90,66 +164,401 @@ enum Commands {
     },
 }
 
+/// Networking flags of `start`, merged over the agent config file.
+#[derive(clap::Args, Debug, Clone, Default)]
+struct NetworkArgs {
+    /// Peer to join the DHT through, e.g. /ip4/1.2.3.4/tcp/4001/p2p/<PeerId> (repeatable)
+    #[arg(long)]
+    bootstrap: Vec<String>,
+    /// Disable LAN discovery (use with --bootstrap across networks)
+    #[arg(long)]
+    no_mdns: bool,
+    /// Named hive to join (repeatable); defaults to "main"
+    #[arg(long)]
+    hive: Vec<String>,
+    /// Pre-shared swarm key file; only nodes holding it can connect
+    #[arg(long)]
+    swarm_key: Option<String>,
+    /// Address to listen on (repeatable), e.g. /ip4/0.0.0.0/udp/4001/quic-v1 or /ip6/::/tcp/4001/ws
+    #[arg(long)]
+    listen: Vec<String>,
+    /// Address to announce to peers (repeatable), e.g. a public IP with a forwarded port
+    #[arg(long)]
+    external: Vec<String>,
+    /// Act as a relay for peers behind NAT (needs a public address)
+    #[arg(long)]
+    relay_server: bool,
+    /// Relay to reserve a slot on when behind NAT (repeatable), ending in /p2p/<PeerId>
+    #[arg(long)]
+    relay: Vec<String>,
+    /// Only connect to these peers (repeatable PeerId); adds to the config allowlist
+    #[arg(long)]
+    allow_peer: Vec<String>,
+    /// Never connect to this peer (repeatable PeerId)
+    #[arg(long)]
+    deny_peer: Vec<String>,
+    /// Limit on established connections in total
+    #[arg(long)]
+    max_connections: Option<u32>,
+    /// Limit on established connections to a single peer
+    #[arg(long)]
+    max_connections_per_peer: Option<u32>,
+    /// Bytes of content to store before unpinned content is collected
+    #[arg(long)]
+    storage_quota: Option<u64>,
+    /// Key file that unlocks encrypted storage; defaults to the HIVE_STORAGE_PASSPHRASE passphrase
+    #[arg(long)]
+    storage_key_file: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum IdentityCommand {
+    /// Print the PeerId of the stored keypair
+    Show,
+    /// Copy the keypair to a file (keep it secret)
+    Export {
+        path: String,
+    },
+    /// Replace the stored keypair with one exported from another node
+    Import {
+        path: String,
+        /// Overwrite an existing identity; the old one is kept as a backup
+        #[arg(long)]
+        force: bool,
+    },
+    /// Generate a new keypair; the old one is kept as a backup
+    Rotate,
+}
+
+#[derive(Subcommand, Debug)]
+enum StorageCommand {
+    /// List stored content with its size, pins and last use
+    Ls,
+    /// Show the space stored content takes and why it is kept
+    Du,
+    /// Re-hash all stored data and report corruption
+    Verify {
+        /// Delete corrupt data so that it can be fetched again
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Keep content through garbage collection; pins are counted
+    Pin {
+        cid: Cid,
+    },
+    /// Remove one pin from content
+    Unpin {
+        cid: Cid,
+    },
+    /// Keep this many copies of stored content across the hive; 0 stops
+    /// replicating it. A running agent recruits peers on its next check.
+    Replicate {
+        cid: Cid,
+        factor: u32,
+    },
+    /// Evict unpinned content, least recently used first, down to the quota
+    Gc {
+        /// Bytes to keep; defaults to the configured storage quota
+        #[arg(long)]
+        quota: Option<u64>,
+    },
+    /// Encrypt a plaintext store in place with the configured key file or
+    /// HIVE_STORAGE_PASSPHRASE. Stop the agent first; rerun if interrupted
+    Encrypt,
+    /// Re-seal stored data with a fresh data key. Stop the agent first
+    RotateKey {
+        /// Move to this key file; HIVE_STORAGE_NEW_PASSPHRASE moves to a new passphrase
+        #[arg(long)]
+        new_key_file: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ModelsCommand {
+    /// List registered models with their metadata
+    Ls,
+    /// Store a model file by CID and register it
+    Import {
+        path: String,
+        /// Name to refer to the model by instead of its CID
+        #[arg(long)]
+        alias: Option<String>,
+        /// tokenizer.json to ship with the model; defaults to a <path>.tokenizer.json sidecar
+        #[arg(long)]
+        tokenizer: Option<String>,
+    },
+    /// Point an alias at a registered model
+    Alias {
+        alias: String,
+        cid: Cid,
+    },
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tracing_subscriber::fmt::init();
     let args = Args::parse();
 
-    let storage = Storage::new(".hive/storage").await?;
-    let scheduler = Arc::new(Mutex::new(Scheduler::new()));
+    // Every command reads the same file, so the CLI and the node it starts use the same store
+    let config_path = std::path::Path::new(&args.config);
+    let agent_config = config::AgentConfig::load(config_path)?;
+    let storage_key = KeySource::configured(agent_config.storage_key_file.as_deref(), encryption::PASSPHRASE_ENV);
+    let backend = agent_config.storage.clone().unwrap_or_default();
+
+    // These work on stores that cannot be opened normally, e.g. half-encrypted ones
+    if let Some(Commands::Storage { action: action @ (StorageCommand::Encrypt | StorageCommand::RotateKey { .. }) }) = &args.command {
+        let key = storage_key.ok_or(format!("Set {} or storage_key_file in the config to choose the key", encryption::PASSPHRASE_ENV))?;
+        match action {
+            StorageCommand::RotateKey { new_key_file } => {
+                let new_key = KeySource::configured(new_key_file.as_deref(), encryption::NEW_PASSPHRASE_ENV);
+                let resealed = Storage::rotate_key(backend.open().await?, &key, new_key.as_ref()).await?;
+                println!("Re-sealed {} blob(s) with a new data key{}", resealed, if new_key.is_some() { "; the new key now unlocks storage" } else { "" });
+            }
+            _ => println!("Encrypted {} blob(s)", Storage::encrypt(backend.open().await?, &key).await?),
+        }
+        return Ok(());
+    }
+    // Opened only by the commands that use it, so that `start` can take the key from its own flags
+    let open_storage_with = |key: Option<KeySource>, quota: Option<u64>| {
+        let backend = &backend;
+        async move { Ok::<_, anyhow::Error>(Storage::open(backend.open().await?, key.as_ref()).await?.with_quota(quota)) }
+    };
+    let open_storage = || open_storage_with(storage_key.clone(), agent_config.storage_quota);
 
     match args.command {
         Some(Commands::Upload { path }) => {
-            let data = tokio::fs::read(&path).await?;
-            let cid = storage.store(&data).await?;
-            println!("Uploaded file. CID: {}", cid);
+            let storage = open_storage().await?;
+            let cid = storage.store_file(&path).await?;
+            storage.pin(&cid).await?;
+            println!("Uploaded file. CID: {} (pinned)", cid);
             return Ok(());
         }
-        Some(Commands::Get { cid }) => {
-            if let Some(data) = storage.retrieve(&cid).await? {
-                let filename = format!("download_{}", &cid[0..8]);
-                tokio::fs::write(&filename, data).await?;
-                println!("Retrieved file to {}", filename);
+        Some(Commands::Get { cid, timeout, network }) => {
+            let filename = format!("download_{}", cid.short());
+            // The node that fetches writes with the flags' key, so read with it too
+            let storage = open_storage_with(network_storage_key(&agent_config, &network), network.storage_quota.or(agent_config.storage_quota)).await?;
+            if storage.size(&cid).await?.is_some() {
+                save_content(&storage, &cid, &filename).await?;
             } else {
-                println!("File not found locally.");
+                // The fetching node opens the backend itself; with the memory
+                // backend that is a different store, so read through the node
+                drop(storage);
+                fetch_from_hive(&cid, &agent_config, &network, std::time::Duration::from_secs(timeout), &filename).await?;
+            }
+            println!("Retrieved file to {}", filename);
+            return Ok(());
+        }
+        Some(Commands::Storage { action }) => {
+            let storage = open_storage().await?;
+            match action {
+                StorageCommand::Ls => {
+                    let entries = storage.list().await?;
+                    if entries.is_empty() {
+                        println!("Storage is empty.");
+                    }
+                    for entry in entries {
+                        let why = if entry.pins > 0 { format!("pinned x{}", entry.pins) } else { "cached".to_string() };
+                        let chunks = if entry.chunks.is_empty() { "whole blob".to_string() } else { format!("{} chunk(s)", entry.chunks.len()) };
+                        let idle = entry.last_used.elapsed().map(|d| d.as_secs()).unwrap_or(0);
+                        let replicas = if entry.replication > 0 { format!("  {} replica(s)", entry.replication) } else { String::new() };
+                        println!("{}  {:>12} bytes  {:<12}  {:<10}  used {}s ago{}", entry.cid, entry.size, chunks, why, idle, replicas);
+                    }
+                }
+                StorageCommand::Du => {
+                    let usage = storage.usage().await?;
+                    println!("Pinned:   {:>14} bytes", usage.pinned);
+                    println!("Cached:   {:>14} bytes (unpinned, evicted first)", usage.cached);
+                    println!("Orphaned: {:>14} bytes (chunks of no stored content)", usage.orphaned);
+                    println!("Total:    {:>14} bytes", usage.total());
+                    match usage.quota {
+                        Some(quota) => println!("Quota:    {:>14} bytes", quota),
+                        None => println!("Quota:    none"),
+                    }
+                    println!("Encrypted: {}", if storage.encrypted() { "yes" } else { "no" });
+                }
+                StorageCommand::Verify { repair } => {
+                    let report = storage.verify(repair).await?;
+                    println!("Checked {} blob(s).", report.checked);
+                    for hash in &report.corrupt {
+                        println!("Corrupt: {}", hash);
+                    }
+                    for cid in &report.damaged {
+                        println!("Damaged content: {}{}", cid, if repair { " (removed; fetch it again)" } else { "" });
+                    }
+                    if !report.corrupt.is_empty() && !repair {
+                        return Err("Storage is corrupt; run `storage verify --repair` to drop the bad data".into());
+                    }
+                }
+                StorageCommand::Pin { cid } => println!("{} now has {} pin(s)", cid, storage.pin(&cid).await?),
+                StorageCommand::Unpin { cid } => println!("{} now has {} pin(s)", cid, storage.unpin(&cid).await?),
+                StorageCommand::Replicate { cid, factor } => match storage.set_replication(&cid, factor).await? {
+                    _ if factor == 0 => println!("{} is no longer replicated from here", cid),
+                    Some(previous) => println!("{} replication factor {} -> {}", cid, previous, factor),
+                    None => println!("{} replication factor {}", cid, factor),
+                },
+                StorageCommand::Encrypt | StorageCommand::RotateKey { .. } => unreachable!("handled before storage is opened"),
+                StorageCommand::Gc { quota } => {
+                    let quota = quota.or(storage.quota()).ok_or("No storage quota configured; pass --quota")?;
+                    let report = storage.collect_garbage(quota, None).await?;
+                    for cid in &report.removed {
+                        println!("Evicted {}", cid);
+                    }
+                    println!("Freed {} bytes in {} item(s) and {} orphaned chunk(s); {} bytes remain", report.freed, report.removed.len(), report.orphans, report.remaining);
+                }
+            }
+            return Ok(());
+        }
+        Some(Commands::Models { action }) => {
+            let storage = open_storage().await?;
+            let registry = ModelRegistry::open(storage.clone(), std::path::Path::new("models")).await?;
+            match action {
+                ModelsCommand::Ls => {
+                    let models = registry.list().await;
+                    if models.is_empty() {
+                        println!("No models registered. Add one with `models import <path>`.");
+                    }
+                    for model in models {
+                        let m = &model.metadata;
+                        println!("{}  {}", model.cid, model.file_name);
+                        println!(
+                            "    {} {}, {} parameters, {}, context {}, {} layers, tokenizer: {}, aliases: {}",
+                            m.format,
+                            m.architecture.as_deref().unwrap_or("?"),
+                            m.parameters.map_or("?".to_string(), |p| p.to_string()),
+                            m.quantization.as_deref().unwrap_or("?"),
+                            m.context_length.map_or("?".to_string(), |c| c.to_string()),
+                            m.layers.map_or("?".to_string(), |l| l.to_string()),
+                            if model.has_tokenizer() { "yes" } else { "no" },
+                            if model.aliases.is_empty() { "none".to_string() } else { model.aliases.join(", ") },
+                        );
+                    }
+                }
+                ModelsCommand::Import { path, alias, tokenizer } => {
+                    let tokenizer = tokenizer.as_ref().map(std::path::Path::new);
+                    let model = registry.import(std::path::Path::new(&path), alias.as_deref(), tokenizer).await?;
+                    println!("Registered {} as {}", path, model.cid);
+                }
+                ModelsCommand::Alias { alias, cid } => {
+                    registry.set_alias(&alias, &cid).await?;
+                    println!("{} now refers to {}", alias, cid);
+                }
             }
             return Ok(());
         }
-        Some(Commands::Compute { size }) => {
+        Some(Commands::Compute { size, tile, timeout, verify, replicas, network }) => {
             println!("Generating {}x{} matrices...", size, size);
             let matrix_a = ComputeEngine::generate_matrix(size, size);
             let matrix_b = ComputeEngine::generate_matrix(size, size);
 
-            println!("Serializing and storing matrices...");
-            let data_a = ComputeEngine::serialize_matrix(&matrix_a)?;
-            let data_b = ComputeEngine::serialize_matrix(&matrix_b)?;
-            
-            let cid_a = storage.store(&data_a).await?;
-            let cid_b = storage.store(&data_b).await?;
-            
+            let handle = join_hive(&agent_config, &network).await?;
+            let cid_a = handle.storage.store(&ComputeEngine::serialize_matrix(&matrix_a)?).await?;
+            let cid_b = handle.storage.store(&ComputeEngine::serialize_matrix(&matrix_b)?).await?;
             println!("Stored Matrix A: {}", cid_a);
             println!("Stored Matrix B: {}", cid_b);
 
-            println!("Computing locally for verification...");
+            println!("Waiting for peers...");
+            let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(timeout);
+            while handle.scheduler.lock().unwrap().peers.values().filter(|p| p.accepts_work()).count() < replicas && tokio::time::Instant::now() < deadline {
+                tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+            }
+            let workers = handle.scheduler.lock().unwrap().peers.values().filter(|p| p.accepts_work()).count();
+            if workers == 0 {
+                println!("No peers found; multiplying locally");
+            }
+
             let start = std::time::Instant::now();
-            let result = ComputeEngine::multiply(&matrix_a, &matrix_b)?;
-            let duration = start.elapsed();
-            
-            println!("Computation complete in {:.2?}", duration);
-            let data_res = ComputeEngine::serialize_matrix(&result)?;
-            let cid_res = storage.store(&data_res).await?;
+            let result = handle
+                .multiply(&cid_a, &cid_b, tile, replicas, |p| {
+                    let retries = if p.retries > 0 { format!(", {} retried", p.retries) } else { String::new() };
+                    println!("Tile products: {}/{} done{}", p.done, p.total, retries);
+                })
+                .await;
+            let elapsed = start.elapsed();
+            let checked = match &result {
+                Ok(cid) if verify => {
+                    println!("Computing locally for verification...");
+                    let expected = ComputeEngine::multiply(&matrix_a, &matrix_b)?;
+                    let data = handle.storage.retrieve(cid).await?.ok_or("Result vanished from storage")?;
+                    let actual = ComputeEngine::deserialize_matrix(&data)?;
+                    // Tiles sum in a different order, so allow for rounding
+                    Some(actual.dim() == expected.dim() && actual.iter().zip(&expected).all(|(x, y)| (x - y).abs() <= 1e-4 * y.abs().max(1.0)))
+                }
+                _ => None,
+            };
+            handle.drain().await?;
+            let cid_res = result?;
+            println!("Computation complete in {:.2?} on {} peer(s)", elapsed, workers);
             println!("Result stored at: {}", cid_res);
-            
+            match checked {
+                Some(true) => println!("Verified against the local product."),
+                Some(false) => return Err("The distributed product differs from the local one".into()),
+                None => {}
+            }
             return Ok(());
         }
         Some(Commands::Infer { model, tokenizer, prompt }) => {
             println!("Loading model from {}...", model);
-            let mut engine = InferenceEngine::load(&model, &tokenizer, None)?;
+            let mut engine = InferenceEngine::load(&model, &tokenizer, None, None)?;
             println!("Generating...");
             let output = engine.generate(&prompt, 50)?;
             println!("Output: {}{}", prompt, output);
             return Ok(());
         }
+        Some(Commands::ValidateTp { model, tokenizer, prompt, world_size, tolerance }) => {
+            println!("Validating {}-way tensor parallelism for {}...", world_size, model);
+            let diff = tensor_parallel::validate(&model, &tokenizer, &prompt, world_size)?;
+            println!("Max logit difference vs single node: {:e}", diff);
+            if diff > tolerance {
+                return Err(format!("Tensor-parallel output exceeds tolerance {:e}", tolerance).into());
+            }
+            println!("Tensor-parallel output matches single node.");
+            return Ok(());
+        }
+        Some(Commands::Identity { action }) => {
+            let key_path = &identity::key_path(config_path, &backend);
+            match action {
+                IdentityCommand::Show => match identity::load(key_path)? {
+                    Some(keypair) => println!("PeerId: {}\nKey file: {}", libp2p::PeerId::from(keypair.public()), key_path.display()),
+                    None => println!("No identity yet. One is created at {} on first start.", key_path.display()),
+                },
+                IdentityCommand::Export { path } => {
+                    let keypair = identity::load_or_generate(key_path)?;
+                    identity::save(std::path::Path::new(&path), &keypair)?;
+                    println!("Exported identity {} to {}", libp2p::PeerId::from(keypair.public()), path);
+                }
+                IdentityCommand::Import { path, force } => {
+                    let keypair = identity::load(std::path::Path::new(&path))?
+                        .ok_or_else(|| format!("No keypair found at {}", path))?;
+                    if key_path.exists() && !force {
+                        return Err(format!("An identity already exists at {}; pass --force to replace it", key_path.display()).into());
+                    }
+                    if let Some(previous) = identity::replace(key_path, &keypair)? {
+                        println!("Previous identity {} saved to {}", previous, identity::backup_path(key_path).display());
+                    }
+                    println!("Imported identity {}", libp2p::PeerId::from(keypair.public()));
+                }
+                IdentityCommand::Rotate => {
+                    let (previous, keypair) = identity::rotate(key_path)?;
+                    if let Some(previous) = previous {
+                        println!("Previous identity {} saved to {}", previous, identity::backup_path(key_path).display());
+                    }
+                    println!("New identity: {}", libp2p::PeerId::from(keypair.public()));
+                }
+            }
+            return Ok(());
+        }
+        Some(Commands::SwarmKey { path }) => {
+            let path = std::path::Path::new(&path);
+            if path.exists() {
+                return Err(format!("{} already exists", path.display()).into());
+            }
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(path, p2p::generate_swarm_key())?;
+            println!("Wrote swarm key to {}. Copy it to every node of the hive.", path.display());
+            return Ok(());
+        }
         Some(Commands::Setup) => {
             backend::llama_cpp::LlamaCppBackend::setup().map_err(|e| e.to_string())?;
             return Ok(());
@@ -179,24 +588,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     info!("Starting Hive Agent...");
 
-    // Initialize Inference Engine (shared state)
-    let inference_engine = Arc::new(Mutex::new(None));
-
-    // Channel for internal messages (e.g. inference results to broadcast)
-    let (tx, mut rx) = tokio::sync::mpsc::channel::<message::Message>(32);
-
-    // Shared state for pending requests (for Queen to wait for results)
-    let pending_requests = Arc::new(Mutex::new(std::collections::HashMap::<String, tokio::sync::oneshot::Sender<Result<String, String>>>::new()));
+    let parallel_mode = match &args.command {
+        Some(Commands::Start { parallelism, .. }) => *parallelism,
+        _ => ParallelMode::Pipeline,
+    };
 
-    // Start HTTP API in a separate task
-    let api_engine = inference_engine.clone();
-    let api_scheduler = scheduler.clone();
-    let api_tx = tx.clone();
-    let api_pending = pending_requests.clone();
-    
     // Extract config from args if Start command is used
     let server_config = match &args.command {
-        Some(Commands::Start { model: Some(m), rpc: Some(r), ngl }) => {
+        Some(Commands::Start { model: Some(m), rpc: Some(r), ngl, .. }) => {
             Some(http_api::ServerConfig {
                 model_path: m.clone(),
                 rpc_endpoint: r.clone(),
@@ -206,215 +605,164 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
          _ => None
     };
 
+    let verification = match &args.command {
+        Some(Commands::Start { verify_replicas: Some(replicas), on_mismatch, .. }) => Some(VerificationConfig {
+            replicas: *replicas,
+            policy: *on_mismatch,
+            ..VerificationConfig::default()
+        }),
+        _ => None,
+    };
+
+    let network = match &args.command {
+        Some(Commands::Start { network, .. }) => network.clone(),
+        _ => NetworkArgs::default(),
+    };
+    let config = NodeConfig {
+        parallel_mode,
+        server_config,
+        verification,
+        ..network_config(&agent_config, &network)?
+    };
+
+    // Reuse the persisted keypair so the PeerId is stable across restarts
+    let id_keys = identity::load_or_generate(&identity::key_path(config_path, &config.storage))?;
+    let node = Node::new(config, id_keys).await?;
+    let handle = node.handle();
     tokio::spawn(async move {
-        http_api::start_server(api_engine, api_scheduler, api_tx, api_pending, server_config).await;
+        shutdown_signal().await;
+        info!("Shutdown requested, draining...");
+        let _ = handle.drain().await;
     });
+    node.run().await;
+    Ok(())
+}
 
-    // Create a random PeerId
-    let id_keys = libp2p::identity::Keypair::generate_ed25519();
-    let peer_id = PeerId::from(id_keys.public());
-    info!("Local peer id: {peer_id}");
-
-    // Set up the transport
-    let tcp_transport = tcp::tokio::Transport::new(tcp::Config::default().nodelay(true))
-        .upgrade(upgrade::Version::V1)
-        .authenticate(noise::Config::new(&id_keys).unwrap())
-        .multiplex(yamux::Config::default())
-        .boxed();
-    
-    let ws_transport = libp2p::websocket::WsConfig::new(tcp::tokio::Transport::new(tcp::Config::default().nodelay(true)))
-        .upgrade(upgrade::Version::V1)
-        .authenticate(noise::Config::new(&id_keys).unwrap())
-        .multiplex(yamux::Config::default())
-        .boxed();
-
-    let transport = tcp_transport.or_transport(ws_transport)
-        .map(|either, _| match either {
-            Either::Left((peer_id, muxer)) => (peer_id, libp2p::core::muxing::StreamMuxerBox::new(muxer)),
-            Either::Right((peer_id, muxer)) => (peer_id, libp2p::core::muxing::StreamMuxerBox::new(muxer)),
-        })
-        .boxed();
-
-    // Set up the behaviour
-    let mdns = mdns::tokio::Behaviour::new(mdns::Config::default(), peer_id)?;
-    
-    // Gossipsub configuration
-    let message_id_fn = |message: &gossipsub::Message| {
-        let mut s = DefaultHasher::new();
-        message.data.hash(&mut s);
-        gossipsub::MessageId::from(s.finish().to_string())
-    };
-    let gossipsub_config = gossipsub::ConfigBuilder::default()
-        .heartbeat_interval(Duration::from_secs(1)) // Faster heartbeat for testing
-        .validation_mode(gossipsub::ValidationMode::Strict)
-        .message_id_fn(message_id_fn)
-        .mesh_n_low(0)
-        .mesh_n(2)
-        .mesh_n_high(4)
-        .mesh_outbound_min(0) 
-        .flood_publish(true) // Ensure it pushes even if mesh is empty
-        .build()
-        .map_err(|e| Box::new(e) as Box<dyn std::error::Error>)?;
-    let gossipsub = gossipsub::Behaviour::new(
-        gossipsub::MessageAuthenticity::Signed(id_keys),
-        gossipsub_config,
-    )?;
-
-    let behaviour = HiveBehavior {
-        gossipsub,
-        mdns,
+/// Network settings: config file first, CLI flags on top.
+fn network_config(agent_config: &config::AgentConfig, network: &NetworkArgs) -> anyhow::Result<NodeConfig> {
+    let bootstrap = parse_addrs(agent_config.bootstrap.iter().chain(network.bootstrap.iter()), "bootstrap")?;
+    // CLI listen addresses replace the configured ones; external ones add up
+    let listen = if !network.listen.is_empty() { network.listen.clone() } else { agent_config.listen.clone() };
+    let listen_addrs = parse_addrs(listen.iter(), "listen")?;
+    let external_addrs = parse_addrs(agent_config.external.iter().chain(network.external.iter()), "external")?;
+    let relays = parse_addrs(agent_config.relays.iter().chain(network.relay.iter()), "relay")?;
+
+    // CLI hives replace the configured ones rather than adding to them
+    let hives = if !network.hive.is_empty() { network.hive.clone() } else { agent_config.hives.clone() };
+    let psk = match network.swarm_key.clone().or(agent_config.swarm_key.clone()) {
+        Some(path) => Some(p2p::load_swarm_key(std::path::Path::new(&path))?),
+        None => None,
     };
 
-    // Build the Swarm
-    let mut swarm = libp2p::Swarm::new(transport, behaviour, peer_id, libp2p::swarm::Config::with_tokio_executor());
+    let allow_peers = parse_peers(agent_config.allow.iter().chain(network.allow_peer.iter()))?;
+    let deny_peers = parse_peers(agent_config.deny.iter().chain(network.deny_peer.iter()))?;
 
-    // Listen on all interfaces
-    swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+    let defaults = NodeConfig::default();
+    Ok(NodeConfig {
+        mdns: !network.no_mdns && agent_config.mdns.unwrap_or(defaults.mdns),
+        bootstrap,
+        random_walk_interval: agent_config
+            .random_walk_secs
+            .map(std::time::Duration::from_secs)
+            .unwrap_or(defaults.random_walk_interval),
+        hives: if hives.is_empty() { defaults.hives.clone() } else { hives },
+        listen_addrs: if listen_addrs.is_empty() { defaults.listen_addrs.clone() } else { listen_addrs },
+        external_addrs,
+        psk,
+        relay_server: network.relay_server || agent_config.relay_server.unwrap_or(defaults.relay_server),
+        relays,
+        allow_peers,
+        deny_peers,
+        max_connections: network.max_connections.or(agent_config.max_connections).or(defaults.max_connections),
+        max_connections_per_peer: network
+            .max_connections_per_peer
+            .or(agent_config.max_connections_per_peer)
+            .or(defaults.max_connections_per_peer),
+        storage: agent_config.storage.clone().unwrap_or_default(),
+        storage_quota: network.storage_quota.or(agent_config.storage_quota),
+        storage_key: network_storage_key(agent_config, network),
+        admin_token: std::env::var(http_api::ADMIN_TOKEN_ENV).ok().filter(|t| !t.is_empty()),
+        ..defaults
+    })
+}
 
-    // Subscribe to gossipsub topic
-    let topic = gossipsub::IdentTopic::new("hive-main");
-    swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
+/// The storage key: the flag's key file, then the configured one, then the
+/// passphrase in the environment.
+fn network_storage_key(agent_config: &config::AgentConfig, network: &NetworkArgs) -> Option<KeySource> {
+    KeySource::configured(
+        network.storage_key_file.as_deref().or(agent_config.storage_key_file.as_deref()),
+        encryption::PASSPHRASE_ENV,
+    )
+}
 
-    // Event loop
-    loop {
-        tokio::select! {
-            internal_msg = rx.recv() => {
-                if let Some(msg) = internal_msg {
-                    if let Ok(data) = serde_json::to_vec(&msg) {
-                        if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), data) {
-                             info!("Failed to publish message: {:?}", e);
-                        }
-                    }
-                }
-            }
-            event = swarm.select_next_some() => {
-                match event {
-                    SwarmEvent::NewListenAddr { address, .. } => {
-                        info!("Listening on {address:?}");
-                    }
+/// Starts a short-lived node for a single command. It gets a throwaway
+/// identity and ephemeral ports so that it can run next to an agent on the
+/// same machine. Drain it when done.
+async fn join_hive(agent_config: &config::AgentConfig, network: &NetworkArgs) -> anyhow::Result<node::NodeHandle> {
+    let config = NodeConfig {
+        listen_addrs: vec!["/ip4/0.0.0.0/tcp/0".parse()?, "/ip4/0.0.0.0/udp/0/quic-v1".parse()?],
+        api_port: None,
+        ..network_config(agent_config, network)?
+    };
+    let node = Node::new(config, libp2p::identity::Keypair::generate_ed25519()).await?;
+    let handle = node.handle();
+    tokio::spawn(node.run());
+    Ok(handle)
+}
 
-                    SwarmEvent::Behaviour(p2p::HiveBehaviorEvent::Mdns(mdns::Event::Discovered(list))) => {
-                        for (peer_id, multiaddr) in list {
-                            info!("mDNS discovered a new peer: {peer_id} at {multiaddr}");
-                            swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
-                            scheduler.lock().unwrap().add_peer(peer_id, multiaddr);
-                        }
-                    }
-                    SwarmEvent::Behaviour(p2p::HiveBehaviorEvent::Mdns(mdns::Event::Expired(list))) => {
-                        for (peer_id, _multiaddr) in list {
-                            info!("mDNS discover peer has expired: {peer_id}");
-                            swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
-                            scheduler.lock().unwrap().remove_peer(&peer_id);
-                        }
-                    }
-                    SwarmEvent::Behaviour(p2p::HiveBehaviorEvent::Gossipsub(gossipsub::Event::Message {
-                        propagation_source: peer_id,
-                        message_id: _id,
-                        message,
-                    })) => {
-                        // Deserialize message
-                        if let Ok(msg) = serde_json::from_slice::<message::Message>(&message.data) {
-                            info!("Received P2P message from {}: {:?}", peer_id, msg);
-                            
-                            match msg {
-                                message::Message::TaskRequest { task_id, prompt, model_name, download_url, layer_range } => {
-                                    info!("Processing Task {} (Range: {:?})...", task_id, layer_range);
-                                    let engine = inference_engine.clone();
-                                    let tx_inner = tx.clone();
-                                    
-                                    tokio::spawn(async move {
-                                         let model_path = format!("models/{}", model_name);
-                                         
-                                         // LAZY LOADING: Check if model exists, if not, try download
-                                         if !std::path::Path::new(&model_path).exists() {
-                                             if let Some(url) = download_url {
-                                                 info!("Model missing. Attempting to download from Queen: {}", url);
-                                                 match reqwest::get(&url).await {
-                                                     Ok(resp) => {
-                                                         if resp.status().is_success() {
-                                                             // Stream download
-                                                             use futures::StreamExt;
-                                                             if let Ok(file) = std::fs::File::create(&model_path) {
-                                                                 let mut file = std::io::BufWriter::new(file);
-                                                                 let mut stream = resp.bytes_stream();
-                                                                 while let Some(item) = stream.next().await {
-                                                                     if let Ok(chunk) = item {
-                                                                         let _ = std::io::Write::write_all(&mut file, &chunk);
-                                                                     }
-                                                                 }
-                                                                 // Flush
-                                                                 let _ = std::io::Write::flush(&mut file);
-                                                                 info!("Download complete: {}", model_path);
-                                                             }
-                                                         } else {
-                                                             info!("Queen failed to serve model (Status {})", resp.status());
-                                                         }
-                                                     }
-                                                     Err(e) => info!("Download error: {}", e),
-                                                 }
-                                             }
-                                         }
-
-                                         let res = tokio::task::spawn_blocking(move || {
-                                             let mut lock = engine.lock().unwrap();
-                                             // Check if loaded, if not try to load
-                                            if lock.is_none() || lock.as_ref().unwrap().model_path != model_path {
-                                                // Check for specific tokenizer
-                                                let specific_tok = format!("{}.tokenizer.json", model_path);
-                                                let tokenizer_path = if std::path::Path::new(&specific_tok).exists() {
-                                                    specific_tok
-                                                } else {
-                                                    "tokenizer.json".to_string()
-                                                };
-
-                                                 if std::path::Path::new(&model_path).exists() {
-                                                     info!("Loading model {} with range {:?}...", model_name, layer_range);
-                                                     if let Ok(new_engine) = InferenceEngine::load(&model_path, &tokenizer_path, layer_range) {
-                                                         *lock = Some(new_engine);
-                                                     }
-                                                 }
-                                            }
-                                            
-                                            if let Some(eng) = lock.as_mut() {
-                                                eng.generate(&prompt, 50).map_err(|e| e.to_string())
-                                            } else {
-                                                Err("Model not found or failed to load (Download might have failed)".to_string())
-                                            }
-                                         }).await;
-                                         
-                                         match res {
-                                             Ok(Ok(output)) => {
-                                                 let response = message::Message::TaskResponse {
-                                                     task_id,
-                                                     result: Ok(output),
-                                                 };
-                                                 let _ = tx_inner.send(response).await;
-                                             },
-                                             Ok(Err(e)) => {
-                                                  let response = message::Message::TaskResponse {
-                                                     task_id,
-                                                     result: Err(e),
-                                                 };
-                                                 let _ = tx_inner.send(response).await;
-                                             }
-                                             _ => {}
-                                         }
-                                    });
-                                }
-                                message::Message::TaskResponse { task_id, result } => {
-                                    info!("Result received for Task {}", task_id);
-                                    let mut pending = pending_requests.lock().unwrap();
-                                    if let Some(sender) = pending.remove(&task_id) {
-                                        let _ = sender.send(result);
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    _ => {}
-                }
-            }
+/// Copies stored content into the file `filename`.
+async fn save_content(storage: &Storage, cid: &Cid, filename: &str) -> anyhow::Result<()> {
+    let mut reader = storage.reader(cid).await?.ok_or_else(|| anyhow::anyhow!("{} vanished from storage", cid))?;
+    let mut file = tokio::fs::File::create(filename).await?;
+    tokio::io::copy(&mut reader, &mut file).await?;
+    Ok(())
+}
+
+/// Joins the hive as a short-lived node, fetches `cid` from whichever peers
+/// hold it and saves it to `filename` from the node's own storage.
+async fn fetch_from_hive(cid: &Cid, agent_config: &config::AgentConfig, network: &NetworkArgs, timeout: std::time::Duration, filename: &str) -> anyhow::Result<()> {
+    let handle = join_hive(agent_config, network).await?;
+    println!("Looking for {} in the hive...", cid);
+    let deadline = tokio::time::Instant::now() + timeout;
+    let result = loop {
+        match handle.fetch(cid).await {
+            Ok(()) => break Ok(()),
+            Err(e) if tokio::time::Instant::now() >= deadline => break Err(e),
+            // Peers are still being discovered
+            Err(_) => tokio::time::sleep(std::time::Duration::from_secs(1)).await,
+        }
+    };
+    let result = match result {
+        Ok(()) => save_content(&handle.storage, cid, filename).await,
+        Err(e) => Err(e),
+    };
+    handle.drain().await?;
+    result
+}
+
+/// Resolves on Ctrl-C, or on SIGTERM where there is one.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("installing a SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
         }
     }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+fn parse_addrs<'a>(addrs: impl Iterator<Item = &'a String>, kind: &str) -> anyhow::Result<Vec<libp2p::Multiaddr>> {
+    addrs
+        .map(|a| a.parse().map_err(|e| anyhow::anyhow!("Invalid {} address {}: {}", kind, a, e)))
+        .collect()
 }
 
+fn parse_peers<'a>(peers: impl Iterator<Item = &'a String>) -> anyhow::Result<Vec<libp2p::PeerId>> {
+    peers
+        .map(|p| p.parse().map_err(|e| anyhow::anyhow!("Invalid PeerId {}: {}", p, e)))
+        .collect()
+}
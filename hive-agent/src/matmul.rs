@@ -0,0 +1,336 @@
+use anyhow::{anyhow, Result};
+use futures::StreamExt;
+use hive_core::{Cid, ComputeTask};
+use libp2p::PeerId;
+use ndarray::{s, Array2};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tracing::info;
+use crate::compute::ComputeEngine;
+use crate::exchange;
+use crate::message::Message;
+use crate::node::{self, NodeCommand};
+use crate::scheduler::Scheduler;
+use crate::storage::Storage;
+
+/// Rounds of attempts at a tile product before the multiplication fails.
+pub const MAX_ATTEMPTS: usize = 3;
+
+/// How far a distributed multiplication has got.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Progress {
+    pub done: usize,
+    pub total: usize, // Tile products, one task each
+    pub retries: usize, // Tile products sent again after a failure
+}
+
+/// `A[i][k] · B[k][j]`, one of the terms that sum up to output tile (i, j).
+#[derive(Debug, Clone)]
+struct TileProduct {
+    i: usize,
+    j: usize,
+    k: usize,
+    a: Cid,
+    b: Cid,
+    dim: (usize, usize), // Shape the product must have; edge tiles are smaller
+}
+
+/// Where a tile product is computed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Worker {
+    Peer(PeerId),
+    Local, // When no peer is left to ask
+}
+
+async fn load(storage: &Storage, commands: &mpsc::Sender<NodeCommand>, scheduler: &Arc<Mutex<Scheduler>>, cid: &Cid) -> Result<Array2<f32>> {
+    ComputeEngine::deserialize_matrix(&exchange::get(storage, commands, scheduler, cid).await?)
+}
+
+/// Runs a compute task: fetches the operands from local storage or peers and
+/// stores the result.
+pub async fn run(storage: &Storage, commands: &mpsc::Sender<NodeCommand>, scheduler: &Arc<Mutex<Scheduler>>, task: ComputeTask) -> Result<Cid> {
+    match task {
+        ComputeTask::MatrixMul { matrix_a_cid, matrix_b_cid } => {
+            let a = load(storage, commands, scheduler, &matrix_a_cid).await?;
+            let b = load(storage, commands, scheduler, &matrix_b_cid).await?;
+            if a.ncols() != b.nrows() {
+                return Err(anyhow!("Cannot multiply a {}x{} matrix by a {}x{} one", a.nrows(), a.ncols(), b.nrows(), b.ncols()));
+            }
+            let product = tokio::task::spawn_blocking(move || ComputeEngine::multiply(&a, &b)).await??;
+            storage.store(&ComputeEngine::serialize_matrix(&product)?).await
+        }
+    }
+}
+
+/// Splits `matrix` into `tile`-sized blocks, stores each one and returns
+/// their CIDs by block row and column. Edge blocks are smaller.
+async fn store_tiles(storage: &Storage, matrix: &Array2<f32>, tile: usize) -> Result<Vec<Vec<Cid>>> {
+    let (rows, cols) = matrix.dim();
+    let mut tiles = Vec::new();
+    for r in (0..rows).step_by(tile) {
+        let mut row = Vec::new();
+        for c in (0..cols).step_by(tile) {
+            let block = matrix.slice(s![r..(r + tile).min(rows), c..(c + tile).min(cols)]).to_owned();
+            row.push(storage.store(&ComputeEngine::serialize_matrix(&block)?).await?);
+        }
+        tiles.push(row);
+    }
+    Ok(tiles)
+}
+
+/// Peers to hand tile products to, leaving out those that already failed
+/// one; this node itself once none is left. Checked runs also leave out
+/// peers flagged too often for disagreeing before.
+fn workers(scheduler: &Arc<Mutex<Scheduler>>, failed: &HashSet<Worker>, checked: bool) -> Vec<Worker> {
+    let scheduler = scheduler.lock().unwrap();
+    let mut peers: Vec<PeerId> = scheduler
+        .peers
+        .values()
+        .filter(|p| p.accepts_work() && (!checked || scheduler.is_trusted(&p.id)))
+        .map(|p| p.id)
+        .collect();
+    peers.sort();
+    let workers: Vec<Worker> = peers.into_iter().map(Worker::Peer).filter(|w| !failed.contains(w)).collect();
+    if workers.is_empty() {
+        vec![Worker::Local]
+    } else {
+        workers
+    }
+}
+
+/// Multiplies the stored matrices `a` and `b` on the hive and returns the CID
+/// of the stored product. Both are cut into `tile`-sized blocks stored by
+/// CID, and every block product `A[i][k] · B[k][j]` is one task for a peer.
+/// Each peer gets one task at a time. With `replicas` above one, every tile
+/// product goes to that many distinct peers and only counts if they all
+/// answer with the same CID; peers outside a strict majority are flagged, as
+/// for verified inference. Failed or disputed tile products are retried on
+/// peers that have not failed yet, for up to `MAX_ATTEMPTS` rounds. The
+/// products are then fetched and summed into the result. `on_progress` is
+/// called whenever a tile product finishes or a round of retries starts.
+#[allow(clippy::too_many_arguments)]
+pub async fn multiply(
+    storage: &Storage,
+    commands: &mpsc::Sender<NodeCommand>,
+    scheduler: &Arc<Mutex<Scheduler>>,
+    a: &Cid,
+    b: &Cid,
+    tile: usize,
+    replicas: usize,
+    on_progress: impl Fn(Progress),
+) -> Result<Cid> {
+    if tile == 0 {
+        return Err(anyhow!("Tiles need at least one row and column"));
+    }
+    if replicas == 0 {
+        return Err(anyhow!("Tile products need at least one replica"));
+    }
+    let a = load(storage, commands, scheduler, a).await?;
+    let b = load(storage, commands, scheduler, b).await?;
+    if a.ncols() != b.nrows() {
+        return Err(anyhow!("Cannot multiply a {}x{} matrix by a {}x{} one", a.nrows(), a.ncols(), b.nrows(), b.ncols()));
+    }
+    let (rows, inner, cols) = (a.nrows(), a.ncols(), b.ncols());
+    let a_tiles = store_tiles(storage, &a, tile).await?;
+    let b_tiles = store_tiles(storage, &b, tile).await?;
+    drop((a, b));
+
+    let mut pending = Vec::new();
+    for (i, a_row) in a_tiles.iter().enumerate() {
+        for j in 0..b_tiles.first().map_or(0, Vec::len) {
+            for (k, b_row) in b_tiles.iter().enumerate() {
+                let dim = ((rows - i * tile).min(tile), (cols - j * tile).min(tile));
+                pending.push(TileProduct { i, j, k, a: a_row[k].clone(), b: b_row[j].clone(), dim });
+            }
+        }
+    }
+    let mut progress = Progress { total: pending.len(), ..Progress::default() };
+    info!("Multiplying a {}x{} by a {}x{} matrix in {} tile product(s)", rows, inner, inner, cols, progress.total);
+    on_progress(progress);
+
+    let mut products: HashMap<(usize, usize, usize), Cid> = HashMap::new();
+    let mut failed = HashSet::new();
+    let mut last_error = None;
+    for attempt in 0..MAX_ATTEMPTS {
+        if pending.is_empty() {
+            break;
+        }
+        if attempt > 0 {
+            progress.retries += pending.len();
+            info!("Retrying {} tile product(s)", pending.len());
+            on_progress(progress);
+        }
+        let workers = workers(scheduler, &failed, replicas > 1);
+        if replicas > 1 && workers.len() < replicas {
+            return Err(anyhow!("Checking tile products needs {} peers, {} available", replicas, workers.len()));
+        }
+        let round: Vec<TileProduct> = std::mem::take(&mut pending);
+        let mut assigned = vec![Vec::new(); workers.len()];
+        for n in 0..round.len() {
+            for r in 0..replicas {
+                assigned[(n + r) % workers.len()].push(n);
+            }
+        }
+        let round = &round;
+        let queues = workers.into_iter().zip(assigned).map(|(worker, queue)| {
+            futures::stream::iter(queue)
+                .then(move |n| async move {
+                    let result = compute(storage, commands, scheduler, worker, &round[n]).await;
+                    (worker, n, result)
+                })
+                .boxed()
+        });
+        let mut results = futures::stream::select_all(queues);
+        let mut answers: HashMap<usize, (usize, Vec<(Worker, Cid)>)> = HashMap::new();
+        while let Some((worker, n, result)) = results.next().await {
+            let product = &round[n];
+            let (replied, cids) = answers.entry(n).or_default();
+            *replied += 1;
+            match result {
+                Ok(cid) => cids.push((worker, cid)),
+                Err(e) => {
+                    info!("Tile product ({}, {}, {}) failed on {:?}: {}", product.i, product.j, product.k, worker, e);
+                    failed.insert(worker);
+                    last_error = Some(e);
+                }
+            }
+            if *replied < replicas {
+                continue;
+            }
+            let (_, cids) = answers.remove(&n).unwrap();
+            match judge(scheduler, &mut failed, product, replicas, cids) {
+                Ok(cid) => {
+                    products.insert((product.i, product.j, product.k), cid);
+                    progress.done += 1;
+                    on_progress(progress);
+                }
+                Err(e) => {
+                    last_error = Some(e);
+                    pending.push(product.clone());
+                }
+            }
+        }
+    }
+    if let Some(e) = last_error.filter(|_| !pending.is_empty()) {
+        return Err(anyhow!("{} tile product(s) still failed after {} attempts: {}", pending.len(), MAX_ATTEMPTS, e));
+    }
+
+    // Sum the terms of each output tile in order of k, so the result does not depend on who finished first
+    let mut result = Array2::<f32>::zeros((rows, cols));
+    let mut keys: Vec<_> = products.keys().copied().collect();
+    keys.sort();
+    for (i, j, k) in keys {
+        let term = load(storage, commands, scheduler, &products[&(i, j, k)]).await?;
+        let mut block = result.slice_mut(s![i * tile..i * tile + term.nrows(), j * tile..j * tile + term.ncols()]);
+        block += &term;
+    }
+    let cid = storage.store(&ComputeEngine::serialize_matrix(&result)?).await?;
+    info!("Product of {} tile product(s) stored as {}", progress.total, cid);
+    Ok(cid)
+}
+
+/// Accepts a tile product once every replica answered with the same CID.
+/// When the answers differ, none of the replicas is asked again this run,
+/// and if a strict majority agrees the peers outside it are flagged. A tie
+/// flags nobody. Peers that agreed with a majority lose a flag. Replicas
+/// that failed were already left out by the caller.
+fn judge(
+    scheduler: &Arc<Mutex<Scheduler>>,
+    failed: &mut HashSet<Worker>,
+    product: &TileProduct,
+    replicas: usize,
+    cids: Vec<(Worker, Cid)>,
+) -> Result<Cid> {
+    let mut groups: Vec<(&Cid, usize)> = Vec::new();
+    for (_, cid) in &cids {
+        match groups.iter_mut().find(|(c, _)| *c == cid) {
+            Some((_, count)) => *count += 1,
+            None => groups.push((cid, 1)),
+        }
+    }
+    if groups.len() <= 1 && cids.len() < replicas {
+        return Err(anyhow!("{} of {} replica(s) failed on tile product ({}, {}, {})", replicas - cids.len(), replicas, product.i, product.j, product.k));
+    }
+    groups.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    let majority = (groups[0].1 * 2 > replicas).then_some(groups[0].0);
+    if replicas > 1 {
+        let mut scheduler = scheduler.lock().unwrap();
+        for (worker, cid) in &cids {
+            if let (Worker::Peer(peer), Some(agreed)) = (worker, majority) {
+                if cid == agreed {
+                    scheduler.record_agreement(peer);
+                } else {
+                    info!("Flagging {:?} for disagreeing on tile product ({}, {}, {})", worker, product.i, product.j, product.k);
+                    scheduler.flag_peer(peer);
+                }
+            }
+        }
+    }
+    if groups.len() == 1 {
+        return Ok(groups[0].0.clone());
+    }
+    for (worker, cid) in &cids {
+        if Some(cid) != majority {
+            failed.insert(*worker);
+        }
+    }
+    Err(anyhow!("Replicas disagreed on tile product ({}, {}, {})", product.i, product.j, product.k))
+}
+
+/// Has `worker` multiply one pair of tiles and returns the CID of the
+/// product, after checking that the product has the shape it must have.
+async fn compute(storage: &Storage, commands: &mpsc::Sender<NodeCommand>, scheduler: &Arc<Mutex<Scheduler>>, worker: Worker, product: &TileProduct) -> Result<Cid> {
+    let task = ComputeTask::MatrixMul { matrix_a_cid: product.a.clone(), matrix_b_cid: product.b.clone() };
+    let cid = match worker {
+        Worker::Local => run(storage, commands, scheduler, task).await?,
+        Worker::Peer(peer) => {
+            let request = Message::Compute { task_id: uuid::Uuid::new_v4().to_string(), task };
+            let reply = node::send_task(commands, peer, request).await.map_err(|e| anyhow!("{}", e))?;
+            reply.parse().map_err(|e| anyhow!("Peer {} answered with no CID: {}", peer, e))?
+        }
+    };
+    // A product of the wrong shape would be summed into the neighbouring tiles, or panic
+    let term = load(storage, commands, scheduler, &cid).await?;
+    if term.dim() != product.dim {
+        return Err(anyhow!("Product {} is {:?}, expected {:?}", cid, term.dim(), product.dim));
+    }
+    Ok(cid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disagreeing_replicas_are_flagged() {
+        let peers: Vec<PeerId> = (0..3).map(|_| PeerId::random()).collect();
+        let scheduler = Arc::new(Mutex::new(Scheduler::new()));
+        for peer in &peers {
+            scheduler.lock().unwrap().add_peer(*peer, "/ip4/127.0.0.1/tcp/1".parse().unwrap());
+        }
+        let (good, bad) = (Cid::sha256(b"good"), Cid::sha256(b"bad"));
+        let product = TileProduct { i: 0, j: 0, k: 0, a: good.clone(), b: good.clone(), dim: (1, 1) };
+        let answers = |cids: [&Cid; 3]| peers.iter().zip(cids).map(|(p, c)| (Worker::Peer(*p), c.clone())).collect::<Vec<_>>();
+        let flags = |peer: &PeerId| scheduler.lock().unwrap().flags(peer);
+
+        let mut failed = HashSet::new();
+        assert_eq!(judge(&scheduler, &mut failed, &product, 3, answers([&good, &good, &good])).unwrap(), good);
+        assert!(failed.is_empty());
+
+        // The odd one out is flagged and the product is tried again
+        assert!(judge(&scheduler, &mut failed, &product, 3, answers([&good, &bad, &good])).is_err());
+        assert_eq!(failed, HashSet::from([Worker::Peer(peers[1])]));
+        assert_eq!(peers.iter().map(flags).collect::<Vec<_>>(), vec![0, 1, 0]);
+
+        // A replica that failed is not held against the others
+        let mut failed = HashSet::new();
+        assert!(judge(&scheduler, &mut failed, &product, 3, answers([&good, &good, &good])[..2].to_vec()).is_err());
+        assert!(failed.is_empty());
+
+        // A tie is retried elsewhere but flags nobody
+        assert!(judge(&scheduler, &mut failed, &product, 2, answers([&good, &bad, &good])[..2].to_vec()).is_err());
+        assert_eq!(failed, HashSet::from([Worker::Peer(peers[0]), Worker::Peer(peers[1])]));
+        assert_eq!(peers.iter().map(flags).collect::<Vec<_>>(), vec![0, 1, 0]);
+    }
+}
@@ -1,4 +1,18 @@
+use hive_core::ComputeTask;
+use libp2p::PeerId;
 use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::capability::SignedCapability;
+use crate::registry::ModelRef;
+
+/// Wire version of `Message`. Adding fields or variants keeps it; renaming or
+/// retyping anything bumps it.
+pub const PROTOCOL_VERSION: u16 = 1;
+/// Oldest version this build still reads.
+pub const MIN_COMPATIBLE_VERSION: u16 = 1;
+/// Largest gossip message we accept.
+pub const MAX_CONTROL_MESSAGE_SIZE: usize = 256 * 1024;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum Message {
@@ -7,10 +21,289 @@ pub enum Message {
         prompt: String,
         model_name: String,
         download_url: Option<String>,
+        model: Option<ModelRef>, // Workers fetch and load this by CID instead of going by name
         layer_range: Option<(usize, usize)>,
+        tp_ranks: Option<Vec<String>>, // PeerIds in rank order for tensor-parallel tasks
+        seed: Option<u64>, // Sampler seed, fixed for verified tasks
     },
-    TaskResponse {
+    /// Work other than inference, sent over the task protocol like a
+    /// `TaskRequest`. The reply is the CID of the stored result.
+    Compute {
         task_id: String,
-        result: Result<String, String>,
+        task: ComputeTask,
+    },
+    /// A node's hardware and models, republished periodically.
+    Capability {
+        signed: SignedCapability,
     },
+    /// The sender is draining and should get no new tasks.
+    Leaving,
+}
+
+/// Reply to a `TaskRequest` sent over the task protocol.
+pub type TaskReply = Result<String, TaskError>;
+
+/// Why an addressed task produced no output.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum TaskError {
+    /// The worker is already running as many tasks as it accepts
+    Busy,
+    /// No reply within the request timeout
+    Timeout,
+    /// The worker could not be reached or dropped the connection
+    Unreachable(String),
+    /// The worker ran the task and it failed
+    Failed(String),
+    /// The access lists of one side do not admit the other
+    Denied,
+    /// The worker is shutting down and takes no new tasks
+    Draining,
+}
+
+impl std::fmt::Display for TaskError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TaskError::Busy => write!(f, "Worker is busy"),
+            TaskError::Timeout => write!(f, "Task timed out"),
+            TaskError::Unreachable(e) => write!(f, "Worker unreachable: {}", e),
+            TaskError::Failed(e) => write!(f, "Task failed: {}", e),
+            TaskError::Denied => write!(f, "Peer is not allowed to exchange tasks with us"),
+            TaskError::Draining => write!(f, "Worker is shutting down"),
+        }
+    }
+}
+
+/// Two nodes can talk if their supported version ranges overlap.
+pub fn is_compatible(min_version: u16, version: u16) -> bool {
+    min_version <= PROTOCOL_VERSION && version >= MIN_COMPATIBLE_VERSION
+}
+
+/// The version range advertised through identify, e.g. "/hive/wire/1-1".
+pub fn wire_protocol_version() -> String {
+    format!("/hive/wire/{}-{}", MIN_COMPATIBLE_VERSION, PROTOCOL_VERSION)
+}
+
+/// Parses a peer's `wire_protocol_version` string into `(min_version, version)`.
+pub fn parse_wire_protocol_version(s: &str) -> Option<(u16, u16)> {
+    let (min, max) = s.strip_prefix("/hive/wire/")?.split_once('-')?;
+    Some((min.parse().ok()?, max.parse().ok()?))
+}
+
+/// What goes on the gossip wire: a `Message` with the metadata needed to
+/// reject it cleanly when the sender runs an incompatible build.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Envelope<T = Message> {
+    pub version: u16,
+    pub min_version: u16, // Oldest version that can read `payload`
+    pub id: String, // Unique per envelope, even for identical payloads
+    pub sender: String, // PeerId
+    pub timestamp: u64, // Unix milliseconds
+    pub payload: T,
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    /// Not a CBOR envelope at all
+    Malformed(String),
+    /// An envelope from a build whose version range does not overlap ours
+    Incompatible { min_version: u16, version: u16 },
+    /// A compatible envelope with a payload this build does not know
+    UnknownPayload(String),
+    /// A readable envelope that fails validation: spoofed sender, oversized, forged record
+    Invalid(String),
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Malformed(e) => write!(f, "Malformed envelope: {}", e),
+            DecodeError::Incompatible { min_version, version } => {
+                write!(f, "Incompatible protocol versions {}-{} (ours {}-{})", min_version, version, MIN_COMPATIBLE_VERSION, PROTOCOL_VERSION)
+            }
+            DecodeError::UnknownPayload(e) => write!(f, "Unknown payload: {}", e),
+            DecodeError::Invalid(e) => write!(f, "Invalid message: {}", e),
+        }
+    }
+}
+
+impl Envelope {
+    pub fn seal(sender: PeerId, payload: Message) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_COMPATIBLE_VERSION,
+            id: uuid::Uuid::new_v4().to_string(),
+            sender: sender.to_string(),
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0),
+            payload,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        ciborium::into_writer(self, &mut buf).expect("writing CBOR to a Vec cannot fail");
+        buf
+    }
+
+    /// Checks the version before touching the payload, so a payload change in a
+    /// newer build is reported as incompatible rather than as garbage.
+    pub fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        let raw: Envelope<ciborium::Value> = ciborium::from_reader(data).map_err(|e| DecodeError::Malformed(e.to_string()))?;
+        if !is_compatible(raw.min_version, raw.version) {
+            return Err(DecodeError::Incompatible { min_version: raw.min_version, version: raw.version });
+        }
+        let payload = raw.payload.deserialized().map_err(|e| DecodeError::UnknownPayload(e.to_string()))?;
+        Ok(Envelope {
+            version: raw.version,
+            min_version: raw.min_version,
+            id: raw.id,
+            sender: raw.sender,
+            timestamp: raw.timestamp,
+            payload,
+        })
+    }
+
+    /// Application-level checks on a decoded envelope. `source` is the
+    /// gossipsub author and `size` the encoded length.
+    pub fn validate(self, source: Option<PeerId>, size: usize) -> Result<Self, DecodeError> {
+        if source.map(|s| s.to_string()) != Some(self.sender.clone()) {
+            return Err(DecodeError::Invalid(format!("sender {} did not sign the message", self.sender)));
+        }
+        if size > MAX_CONTROL_MESSAGE_SIZE {
+            return Err(DecodeError::Invalid(format!("{} bytes exceeds the {} byte limit", size, MAX_CONTROL_MESSAGE_SIZE)));
+        }
+        if let Message::Capability { signed } = &self.payload {
+            let (signer, _) = signed.verify().map_err(|e| DecodeError::Invalid(e.to_string()))?;
+            if signer.to_string() != self.sender {
+                return Err(DecodeError::Invalid(format!("capability record of {} published by {}", signer, self.sender)));
+            }
+        }
+        Ok(self)
+    }
+}
+
+/// The envelope id of an encoded message, without decoding the payload.
+/// Gossipsub uses it as the message id, so identical payloads stay distinct.
+pub fn envelope_id(data: &[u8]) -> Option<String> {
+    #[derive(Deserialize)]
+    struct Id {
+        id: String,
+    }
+    ciborium::from_reader::<Id, _>(data).ok().map(|e| e.id)
+}
+
+/// Counts of gossip messages by outcome, for the status API.
+#[derive(Debug, Default)]
+pub struct MessageStats {
+    pub accepted: AtomicU64,
+    pub malformed: AtomicU64,
+    pub incompatible: AtomicU64,
+    pub unknown: AtomicU64,
+    pub rejected: AtomicU64,
+}
+
+impl MessageStats {
+    pub fn record(&self, result: &Result<Envelope, DecodeError>) {
+        let counter = match result {
+            Ok(_) => &self.accepted,
+            Err(DecodeError::Malformed(_)) => &self.malformed,
+            Err(DecodeError::Incompatible { .. }) => &self.incompatible,
+            Err(DecodeError::UnknownPayload(_)) => &self.unknown,
+            Err(DecodeError::Invalid(_)) => &self.rejected,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "accepted": self.accepted.load(Ordering::Relaxed),
+            "malformed": self.malformed.load(Ordering::Relaxed),
+            "incompatible": self.incompatible.load(Ordering::Relaxed),
+            "unknown": self.unknown.load(Ordering::Relaxed),
+            "rejected": self.rejected.load(Ordering::Relaxed),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> Message {
+        Message::TaskRequest {
+            task_id: "t".to_string(),
+            prompt: "p".to_string(),
+            model_name: "m".to_string(),
+            download_url: None,
+            model: None,
+            layer_range: Some((0, 3)),
+            tp_ranks: None,
+            seed: Some(3),
+        }
+    }
+
+    #[test]
+    fn envelope_round_trips() {
+        let sender = PeerId::random();
+        let sealed = Envelope::seal(sender, request());
+        let opened = Envelope::decode(&sealed.encode()).unwrap();
+        assert_eq!(opened.id, sealed.id);
+        assert_eq!(opened.sender, sender.to_string());
+        assert!(matches!(opened.payload, Message::TaskRequest { layer_range: Some((0, 3)), seed: Some(3), .. }));
+        // Same payload, different envelope
+        assert_ne!(Envelope::seal(sender, request()).id, sealed.id);
+        assert_eq!(envelope_id(&sealed.encode()), Some(sealed.id));
+    }
+
+    #[test]
+    fn incompatible_and_unknown_messages_are_told_apart() {
+        let mut future = Envelope::seal(PeerId::random(), request());
+        future.version = PROTOCOL_VERSION + 1;
+        future.min_version = PROTOCOL_VERSION + 1;
+        assert!(matches!(Envelope::decode(&future.encode()), Err(DecodeError::Incompatible { .. })));
+
+        let sealed = Envelope::seal(PeerId::random(), request());
+        let unknown = Envelope {
+            version: sealed.version,
+            min_version: sealed.min_version,
+            id: sealed.id,
+            sender: sealed.sender,
+            timestamp: sealed.timestamp,
+            payload: ciborium::Value::Text("NewVariant".to_string()),
+        };
+        let mut buf = Vec::new();
+        ciborium::into_writer(&unknown, &mut buf).unwrap();
+        assert!(matches!(Envelope::decode(&buf), Err(DecodeError::UnknownPayload(_))));
+
+        assert!(matches!(Envelope::decode(b"{\"json\": true}"), Err(DecodeError::Malformed(_))));
+    }
+
+    #[test]
+    fn spoofed_and_forged_messages_are_invalid() {
+        let keypair = libp2p::identity::Keypair::generate_ed25519();
+        let sender = keypair.public().to_peer_id();
+        let sealed = Envelope::seal(sender, request());
+        assert!(sealed.clone().validate(Some(sender), 0).is_ok());
+        assert!(matches!(sealed.validate(Some(PeerId::random()), 0), Err(DecodeError::Invalid(_))));
+
+        // Someone else's capability record, republished under our name
+        let other = libp2p::identity::Keypair::generate_ed25519();
+        let signed = SignedCapability::sign(&other, &Default::default()).unwrap();
+        let forged = Envelope::seal(sender, Message::Capability { signed });
+        assert!(matches!(forged.validate(Some(sender), 0), Err(DecodeError::Invalid(_))));
+
+        let prompt = "x".repeat(MAX_CONTROL_MESSAGE_SIZE);
+        let request = Message::TaskRequest {
+            task_id: "t".to_string(),
+            prompt,
+            model_name: "m".to_string(),
+            download_url: None,
+            model: None,
+            layer_range: None,
+            tp_ranks: None,
+            seed: None,
+        };
+        let oversized = Envelope::seal(sender, request);
+        let size = oversized.encode().len();
+        assert!(matches!(oversized.validate(Some(sender), size), Err(DecodeError::Invalid(_))));
+    }
 }
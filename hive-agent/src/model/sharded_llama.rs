@@ -1,11 +1,27 @@
 use std::collections::HashMap;
+use candle_core::quantized::ggml_file::qtensor_from_ggml;
 use candle_core::quantized::QTensor;
 use candle_core::quantized::gguf_file;
 use candle_core::{DType, Device, IndexOp, Result, Tensor};
 use candle_nn::{Embedding, Module};
+use serde::{Deserialize, Serialize};
 
 pub const MAX_SEQ_LEN: usize = 4096;
 
+/// Which slice of every layer a tensor-parallel rank owns.
+/// Attention heads and FFN columns are split evenly across `world_size` ranks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TensorSplit {
+    pub rank: usize,
+    pub world_size: usize,
+}
+
+/// Sums the partial outputs of all ranks. Every rank must see the same result,
+/// so implementations have to add the partials in rank order.
+pub trait AllReduce: Send {
+    fn all_reduce(&mut self, partial: &Tensor) -> Result<Tensor>;
+}
+
 fn repeat_kv(x: Tensor, n_rep: usize) -> Result<Tensor> {
     if n_rep == 1 {
         Ok(x)
@@ -62,6 +78,40 @@ impl QMatMul {
     }
 }
 
+// GGUF weights are stored as (out_features, in_features).
+// Splitting rows splits the outputs (Q/K/V, gate/up), splitting columns splits
+// the inputs (attn_output, down) so that each rank produces a partial sum.
+// Slices are cut on block boundaries so that they stay quantized.
+fn split_rows(qtensor: &QTensor, split: &TensorSplit, device: &Device) -> Result<QMatMul> {
+    let (rows, cols) = qtensor.shape().dims2()?;
+    if rows % split.world_size != 0 {
+        candle_core::bail!("cannot split {rows} rows across {} ranks", split.world_size);
+    }
+    let chunk = rows / split.world_size;
+    let dtype = qtensor.dtype();
+    let row_bytes = cols / dtype.block_size() * dtype.type_size();
+    let data = qtensor.data()?;
+    let slice = &data[split.rank * chunk * row_bytes..(split.rank + 1) * chunk * row_bytes];
+    QMatMul::from_qtensor(qtensor_from_ggml(dtype, slice, vec![chunk, cols], device)?)
+}
+
+fn split_cols(qtensor: &QTensor, split: &TensorSplit, device: &Device) -> Result<QMatMul> {
+    let (rows, cols) = qtensor.shape().dims2()?;
+    let dtype = qtensor.dtype();
+    if cols % split.world_size != 0 || !(cols / split.world_size).is_multiple_of(dtype.block_size()) {
+        candle_core::bail!("cannot split {cols} columns of {dtype:?} across {} ranks", split.world_size);
+    }
+    let chunk = cols / split.world_size;
+    let row_bytes = cols / dtype.block_size() * dtype.type_size();
+    let chunk_bytes = chunk / dtype.block_size() * dtype.type_size();
+    let data = qtensor.data()?;
+    let mut slice = Vec::with_capacity(rows * chunk_bytes);
+    for row in data.chunks_exact(row_bytes) {
+        slice.extend_from_slice(&row[split.rank * chunk_bytes..(split.rank + 1) * chunk_bytes]);
+    }
+    QMatMul::from_qtensor(qtensor_from_ggml(dtype, &slice, vec![rows, chunk], device)?)
+}
+
 #[derive(Debug, Clone)]
 struct Mlp {
     feed_forward_w1: QMatMul,
@@ -189,7 +239,7 @@ impl LayerWeights {
         index_pos: usize,
     ) -> Result<Tensor> {
         let _enter = self.span_attn.enter();
-        let (b_sz, seq_len, n_embd) = x.dims3()?;
+        let (b_sz, seq_len, _n_embd) = x.dims3()?;
         let q = self.attention_wq.forward(x)?;
         let k = self.attention_wk.forward(x)?;
         let v = self.attention_wv.forward(x)?;
@@ -248,7 +298,9 @@ impl LayerWeights {
             att.matmul(&v.contiguous()?)?
         };
 
-        let y = y.transpose(1, 2)?.reshape(&[b_sz, seq_len, n_embd])?;
+        // With tensor parallelism only this rank's heads are present, so the
+        // width is n_head * head_dim rather than the embedding size.
+        let y = y.transpose(1, 2)?.reshape(&[b_sz, seq_len, self.n_head * self.head_dim])?;
         let y = self.attention_wo.forward(&y)?;
         Ok(y)
     }
@@ -260,6 +312,7 @@ pub struct ModelWeights {
     norm: Option<RmsNorm>, // Changed to Option
     output: Option<QMatMul>, // Changed to Option
     masks: HashMap<usize, Tensor>,
+    reducer: Option<Box<dyn AllReduce>>, // Set for tensor-parallel ranks
     span: tracing::Span,
     span_output: tracing::Span,
 }
@@ -289,7 +342,11 @@ impl ModelWeights {
         reader: &mut R,
         device: &Device,
         layer_range: Option<(usize, usize)>, // ADDED: Sharding support
+        tensor_split: Option<TensorSplit>,
     ) -> Result<Self> {
+        if layer_range.is_some() && tensor_split.is_some() {
+            candle_core::bail!("layer_range and tensor_split are mutually exclusive");
+        }
         let md_get = |s: &str| match ct.metadata.get(s) {
             None => candle_core::bail!("cannot find {s} in metadata"),
             Some(v) => Ok(v),
@@ -311,6 +368,34 @@ impl ModelWeights {
         let rope_freq_base = md_get("llama.rope.freq_base")
             .and_then(|m| m.to_f32())
             .unwrap_or(10000f32);
+        let (n_head_local, n_kv_head_local) = match &tensor_split {
+            None => (head_count, head_count_kv),
+            Some(split) => {
+                if split.world_size == 0 || split.rank >= split.world_size {
+                    candle_core::bail!("invalid tensor split {:?}", split);
+                }
+                if head_count % split.world_size != 0 || head_count_kv % split.world_size != 0 {
+                    candle_core::bail!(
+                        "{head_count} heads / {head_count_kv} kv heads cannot be split across {} ranks",
+                        split.world_size
+                    );
+                }
+                if n_expert > 1 {
+                    candle_core::bail!("tensor parallelism is not supported for MoE models");
+                }
+                (head_count / split.world_size, head_count_kv / split.world_size)
+            }
+        };
+        // Loads a projection either whole or as this rank's row/column slice.
+        let load_rows = |t: QTensor| match &tensor_split {
+            None => QMatMul::from_qtensor(t),
+            Some(split) => split_rows(&t, split, device),
+        };
+        let load_cols = |t: QTensor| match &tensor_split {
+            None => QMatMul::from_qtensor(t),
+            Some(split) => split_cols(&t, split, device),
+        };
+
         let (cos, sin) = precomput_freqs_cis(rope_dim, rope_freq_base, device)?;
         let neg_inf = Tensor::new(f32::NEG_INFINITY, device)?;
 
@@ -320,6 +405,7 @@ impl ModelWeights {
         // Shard N: Layers Y-Z. MUST load Head IF (Z == block_count).
         // For simplicity: We only load embeddings if we are starting at 0.
         // We only load head if we are ending at block_count.
+        // Tensor-parallel ranks always cover every layer, so they replicate both.
         
         let (start_layer, end_layer) = layer_range.unwrap_or((0, block_count));
         let should_load_embeddings = start_layer == 0;
@@ -380,9 +466,9 @@ impl ModelWeights {
                 let feed_forward_w3 =
                     ct.tensor(reader, &format!("{prefix}.ffn_up.weight"), device)?;
                 MlpOrMoe::Mlp(Mlp {
-                    feed_forward_w1: QMatMul::from_qtensor(feed_forward_w1)?,
-                    feed_forward_w2: QMatMul::from_qtensor(feed_forward_w2)?,
-                    feed_forward_w3: QMatMul::from_qtensor(feed_forward_w3)?,
+                    feed_forward_w1: load_rows(feed_forward_w1)?,
+                    feed_forward_w2: load_cols(feed_forward_w2)?,
+                    feed_forward_w3: load_rows(feed_forward_w3)?,
                 })
             } else {
                 let feed_forward_gate_inp =
@@ -415,15 +501,15 @@ impl ModelWeights {
             let span_rot = tracing::span!(tracing::Level::TRACE, "attn-rot");
             let span_mlp = tracing::span!(tracing::Level::TRACE, "attn-mlp");
             layers.push(LayerWeights {
-                attention_wq: QMatMul::from_qtensor(attention_wq)?,
-                attention_wk: QMatMul::from_qtensor(attention_wk)?,
-                attention_wv: QMatMul::from_qtensor(attention_wv)?,
-                attention_wo: QMatMul::from_qtensor(attention_wo)?,
+                attention_wq: load_rows(attention_wq)?,
+                attention_wk: load_rows(attention_wk)?,
+                attention_wv: load_rows(attention_wv)?,
+                attention_wo: load_cols(attention_wo)?,
                 attention_norm: RmsNorm::from_qtensor(attention_norm, rms_norm_eps)?,
                 mlp_or_moe,
                 ffn_norm: RmsNorm::from_qtensor(ffn_norm, rms_norm_eps)?,
-                n_head: head_count,
-                n_kv_head: head_count_kv,
+                n_head: n_head_local,
+                n_kv_head: n_kv_head_local,
                 head_dim: embedding_length / head_count,
                 cos: cos.clone(),
                 sin: sin.clone(),
@@ -442,11 +528,17 @@ impl ModelWeights {
             norm,
             output,
             masks: HashMap::new(),
+            reducer: None,
             span,
             span_output,
         })
     }
 
+    /// Installs the all-reduce used to combine partial outputs of a tensor-parallel rank.
+    pub fn set_all_reduce(&mut self, reducer: Box<dyn AllReduce>) {
+        self.reducer = Some(reducer);
+    }
+
     fn mask(&mut self, t: usize, device: &Device) -> Result<Tensor> {
         if let Some(mask) = self.masks.get(&t) {
             Ok(mask.clone())
@@ -461,7 +553,8 @@ impl ModelWeights {
     }
 
     pub fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
-        let (_b_sz, seq_len) = x.dims2()?;
+        // Token ids (b, seq) on the first shard, hidden states (b, seq, embd) after it
+        let seq_len = x.dim(1)?;
         let mask = if seq_len == 1 {
             None
         } else {
@@ -482,6 +575,10 @@ impl ModelWeights {
             let residual = &x;
             let x = layer.attention_norm.forward(&x)?;
             let attn = layer.forward_attn(&x, mask.as_ref(), index_pos)?;
+            let attn = match self.reducer.as_mut() {
+                Some(reducer) => reducer.all_reduce(&attn)?,
+                None => attn,
+            };
             let x = (attn + residual)?;
 
             // MLP
@@ -489,6 +586,10 @@ impl ModelWeights {
             let residual = &x;
             let x = layer.ffn_norm.forward(&x)?;
             let x = layer.mlp_or_moe.forward(&x)?;
+            let x = match self.reducer.as_mut() {
+                Some(reducer) => reducer.all_reduce(&x)?,
+                None => x,
+            };
             let x = (x + residual)?;
             layer_in = x
         }
@@ -508,3 +609,32 @@ impl ModelWeights {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::quantized::GgmlDType;
+
+    #[test]
+    fn shards_stay_quantized_and_match_the_full_weight() -> Result<()> {
+        let device = Device::Cpu;
+        let data: Vec<f32> = (0..4 * 64).map(|i| (i % 17) as f32 / 17.0 - 0.5).collect();
+        let full = QTensor::quantize(&Tensor::from_vec(data, (4, 64), &device)?, GgmlDType::Q8_0)?;
+        let reference = full.dequantize(&device)?;
+        for rank in 0..2 {
+            let split = TensorSplit { rank, world_size: 2 };
+            for (shard, expected) in [
+                (split_rows(&full, &split, &device)?, reference.narrow(0, rank * 2, 2)?),
+                (split_cols(&full, &split, &device)?, reference.narrow(1, rank * 32, 32)?),
+            ] {
+                let candle_core::quantized::QMatMul::QTensor(q) = &shard.inner else {
+                    panic!("shard was dequantized");
+                };
+                assert_eq!(q.dtype(), GgmlDType::Q8_0);
+                let diff = (q.dequantize(&device)? - &expected)?.abs()?.max_all()?.to_scalar::<f32>()?;
+                assert_eq!(diff, 0.0);
+            }
+        }
+        Ok(())
+    }
+}
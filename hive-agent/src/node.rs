@@ -0,0 +1,1176 @@
+use anyhow::Result;
+use futures::future::Either;
+use futures::StreamExt;
+use libp2p::{
+    core::{muxing::StreamMuxerBox, transport::Boxed, upgrade},
+    allow_block_list, autonat, connection_limits, dcutr, gossipsub, identify, kad, mdns, noise, ping, quic, relay, request_response,
+    multiaddr::Protocol,
+    pnet::{PnetConfig, PreSharedKey},
+    swarm::{behaviour::toggle::Toggle, SwarmEvent},
+    tcp, yamux, Multiaddr, PeerId, Swarm, Transport,
+};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
+use tracing::info;
+use hive_core::{Cid, ComputeTask};
+use crate::access::{self, AccessChange, AccessList};
+use crate::blobstore::StorageBackend;
+use crate::capability::{self, SignedCapability};
+use crate::encryption::KeySource;
+use crate::exchange::{self, BlockRequest, BlockResponse};
+use crate::http_api::{self, ServerConfig};
+use crate::inference::{InferenceEngine, DEFAULT_SEED};
+use crate::matmul::{self, Progress};
+use crate::message::{self, DecodeError, Envelope, Message, MessageStats, TaskError, TaskReply};
+use crate::model::sharded_llama::TensorSplit;
+use crate::p2p::{self, HiveBehavior, HiveBehaviorEvent, BLOCK_PROTOCOL, KAD_PROTOCOL, PARTIAL_PROTOCOL, TASK_PROTOCOL};
+use crate::registry::{ModelRef, ModelRegistry};
+use crate::replication::Replicator;
+use crate::scheduler::{Reachability, Scheduler};
+use crate::storage::Storage;
+use crate::tensor_parallel::{self, ParallelMode, PeerAllReduce, TensorPartial, TpSessions};
+use crate::verification::VerificationConfig;
+
+/// Everything that has to differ between two agents running in the same process.
+#[derive(Clone)]
+pub struct NodeConfig {
+    pub storage: StorageBackend,
+    pub models_dir: PathBuf,
+    pub listen_addrs: Vec<Multiaddr>, // TCP, QUIC (/udp/<port>/quic-v1) or WebSocket (/tcp/<port>/ws)
+    pub external_addrs: Vec<Multiaddr>, // Announced to peers, e.g. a public IP behind port forwarding
+    pub mdns: bool,
+    pub api_port: Option<u16>, // None disables the dashboard API
+    pub parallel_mode: ParallelMode,
+    pub server_config: Option<ServerConfig>,
+    pub verification: Option<VerificationConfig>, // Default for distributed API requests
+    pub bootstrap: Vec<Multiaddr>, // Must end in /p2p/<PeerId>
+    pub random_walk_interval: Duration,
+    pub max_concurrent_tasks: usize, // Further task requests are answered with `TaskError::Busy`
+    pub task_timeout: Duration, // How long a sent task may take before `TaskError::Timeout`
+    pub capability_interval: Duration, // How often to republish this node's capability record
+    pub hives: Vec<String>, // Named hives to join; messages sent through the handle go to the first
+    pub psk: Option<PreSharedKey>, // Private network: only nodes with the same key can connect
+    pub relay_server: bool, // Serve relay reservations for peers behind NAT
+    pub relays: Vec<Multiaddr>, // Relays to reserve a slot on, each ending in /p2p/<PeerId>
+    pub allow_peers: Vec<PeerId>, // Empty lets every peer in
+    pub deny_peers: Vec<PeerId>,
+    pub bans_path: PathBuf, // Runtime bans, merged into `deny_peers` on start
+    pub max_connections: Option<u32>, // Established connections in total
+    pub max_connections_per_peer: Option<u32>,
+    pub storage_quota: Option<u64>, // Bytes; None keeps everything
+    pub storage_key: Option<KeySource>, // Unlocks encrypted storage; None for plaintext
+    pub replication_interval: Duration, // How often replicated content is checked for lost holders
+    pub drain_timeout: Duration, // How long a drain waits for running tasks before cancelling them
+    pub admin_token: Option<String>, // Required by the admin API routes; None admits local tools only
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        Self {
+            storage: StorageBackend::default(),
+            models_dir: PathBuf::from("models"),
+            // Fixed ports so that bootstrap addresses and firewall rules stay valid across restarts
+            listen_addrs: vec![
+                "/ip4/0.0.0.0/tcp/4001".parse().unwrap(),
+                "/ip4/0.0.0.0/udp/4001/quic-v1".parse().unwrap(),
+            ],
+            external_addrs: Vec::new(),
+            mdns: true,
+            api_port: Some(3000),
+            parallel_mode: ParallelMode::Pipeline,
+            server_config: None,
+            verification: None,
+            bootstrap: Vec::new(),
+            random_walk_interval: Duration::from_secs(30),
+            max_concurrent_tasks: 1,
+            task_timeout: Duration::from_secs(1200),
+            capability_interval: Duration::from_secs(60),
+            hives: vec![p2p::DEFAULT_HIVE.to_string()],
+            psk: None,
+            relay_server: false,
+            relays: Vec::new(),
+            allow_peers: Vec::new(),
+            deny_peers: Vec::new(),
+            bans_path: PathBuf::from(access::DEFAULT_BANS_PATH),
+            max_connections: Some(512),
+            // Room for TCP, QUIC and a relayed circuit to the same peer
+            max_connections_per_peer: Some(8),
+            storage_quota: None,
+            storage_key: None,
+            replication_interval: Duration::from_secs(60),
+            drain_timeout: Duration::from_secs(60),
+            admin_token: None,
+        }
+    }
+}
+
+/// Requests from outside the event loop that need the swarm itself.
+pub enum NodeCommand {
+    Dial(Multiaddr),
+    SendTask {
+        peer: PeerId,
+        request: Message,
+        reply: oneshot::Sender<TaskReply>,
+    },
+    Block {
+        peer: PeerId,
+        request: BlockRequest,
+        reply: oneshot::Sender<Result<BlockResponse, String>>,
+    },
+    Access {
+        change: AccessChange,
+        reply: oneshot::Sender<Result<(), String>>,
+    },
+    /// A tensor-parallel partial for another rank. Not acknowledged: the
+    /// receiving rank times out if it never arrives.
+    Partial {
+        peer: PeerId,
+        partial: TensorPartial,
+    },
+    /// Stop taking work, finish or cancel running tasks, then shut down.
+    /// `done` fires once the node has stopped.
+    Drain {
+        done: oneshot::Sender<()>,
+    },
+}
+
+/// Cloneable handle to a running node.
+#[derive(Clone)]
+pub struct NodeHandle {
+    pub peer_id: PeerId,
+    pub scheduler: Arc<Mutex<Scheduler>>,
+    pub storage: Arc<Storage>,
+    pub registry: ModelRegistry,
+    pub replicator: Replicator,
+    pub p2p_sender: mpsc::Sender<Message>,
+    pub listen_addrs: Arc<Mutex<Vec<Multiaddr>>>,
+    pub commands: mpsc::Sender<NodeCommand>,
+    pub access: Arc<Mutex<AccessList>>,
+}
+
+impl NodeHandle {
+    pub async fn dial(&self, addr: Multiaddr) -> Result<()> {
+        self.commands.send(NodeCommand::Dial(addr)).await?;
+        Ok(())
+    }
+
+    pub async fn send_task(&self, peer: PeerId, request: Message) -> TaskReply {
+        send_task(&self.commands, peer, request).await
+    }
+
+    pub async fn update_access(&self, change: AccessChange) -> Result<()> {
+        update_access(&self.commands, change).await
+    }
+
+    /// The content behind `cid`, from local storage or fetched from peers.
+    pub async fn get(&self, cid: &Cid) -> Result<Vec<u8>> {
+        exchange::get(&self.storage, &self.commands, &self.scheduler, cid).await
+    }
+
+    /// Makes sure `cid` is in local storage, fetching it from peers if needed.
+    pub async fn fetch(&self, cid: &Cid) -> Result<()> {
+        if self.storage.size(cid).await?.is_some() {
+            return Ok(());
+        }
+        exchange::fetch(&self.storage, &self.commands, &self.scheduler, cid).await
+    }
+
+    /// Multiplies two stored matrices on the hive; see `matmul::multiply`.
+    pub async fn multiply(&self, a: &Cid, b: &Cid, tile: usize, replicas: usize, on_progress: impl Fn(Progress)) -> Result<Cid> {
+        matmul::multiply(&self.storage, &self.commands, &self.scheduler, a, b, tile, replicas, on_progress).await
+    }
+
+    /// Drains the node and waits until it has stopped.
+    pub async fn drain(&self) -> Result<()> {
+        let (done, rx) = oneshot::channel();
+        self.commands.send(NodeCommand::Drain { done }).await?;
+        // A node that already stopped drops `done`; either way it is down
+        let _ = rx.await;
+        Ok(())
+    }
+}
+
+/// True for a bare file name: no directories, and not `.` or `..`.
+fn is_plain_file_name(name: &str) -> bool {
+    !name.contains(['/', '\\']) && Path::new(name).file_name().is_some_and(|n| n == name)
+}
+
+/// Sends a `TaskRequest` to `peer` over the task protocol and waits for its reply.
+/// The timeout is the node's `task_timeout`, enforced by the protocol.
+pub async fn send_task(commands: &mpsc::Sender<NodeCommand>, peer: PeerId, request: Message) -> TaskReply {
+    let (reply, rx) = oneshot::channel();
+    if commands.send(NodeCommand::SendTask { peer, request, reply }).await.is_err() {
+        return Err(TaskError::Unreachable("P2P loop closed".to_string()));
+    }
+    rx.await.unwrap_or_else(|_| Err(TaskError::Unreachable("P2P loop closed".to_string())))
+}
+
+/// Bans, unbans, allows or disallows a peer. Connections the change no longer
+/// admits are closed.
+pub async fn update_access(commands: &mpsc::Sender<NodeCommand>, change: AccessChange) -> Result<()> {
+    let (reply, rx) = oneshot::channel();
+    commands.send(NodeCommand::Access { change, reply }).await.map_err(|_| anyhow::anyhow!("P2P loop closed"))?;
+    rx.await?.map_err(anyhow::Error::msg)
+}
+
+/// How long a draining node keeps its connections after announcing that it
+/// is leaving, so that gossipsub gets to send the announcement.
+const LEAVING_GRACE: Duration = Duration::from_millis(500);
+
+/// A drain in progress.
+struct Drain {
+    deadline: tokio::time::Instant,
+    linger_until: tokio::time::Instant, // Not before the Leaving announcement had time to go out
+    done: Vec<oneshot::Sender<()>>,
+}
+
+pub struct Node {
+    config: NodeConfig,
+    peer_id: PeerId,
+    keypair: libp2p::identity::Keypair,
+    swarm: Swarm<HiveBehavior>,
+    topics: Vec<gossipsub::IdentTopic>,
+    scheduler: Arc<Mutex<Scheduler>>,
+    storage: Arc<Storage>,
+    registry: ModelRegistry,
+    replicator: Replicator,
+    replication_round: Option<tokio::task::JoinHandle<()>>, // The last `Replicator::maintain` run
+    inference_engine: Arc<Mutex<Option<InferenceEngine>>>,
+    tp_sessions: TpSessions,
+    message_stats: Arc<MessageStats>,
+    access: Arc<Mutex<AccessList>>,
+    server_process: Arc<Mutex<Option<std::process::Child>>>, // llama-server started for the API
+    running_tasks: JoinSet<()>,
+    inbound_tasks: usize, // Task requests not yet answered
+    drain: Option<Drain>,
+    outbound_tasks: HashMap<request_response::OutboundRequestId, oneshot::Sender<TaskReply>>,
+    task_slots: Arc<Semaphore>,
+    replies_tx: mpsc::Sender<(request_response::ResponseChannel<TaskReply>, TaskReply)>,
+    replies_rx: mpsc::Receiver<(request_response::ResponseChannel<TaskReply>, TaskReply)>,
+    outbound_blocks: HashMap<request_response::OutboundRequestId, oneshot::Sender<Result<BlockResponse, String>>>,
+    block_replies_tx: mpsc::Sender<(request_response::ResponseChannel<BlockResponse>, BlockResponse)>,
+    block_replies_rx: mpsc::Receiver<(request_response::ResponseChannel<BlockResponse>, BlockResponse)>,
+    listen_addrs: Arc<Mutex<Vec<Multiaddr>>>,
+    tx: mpsc::Sender<Message>,
+    rx: mpsc::Receiver<Message>,
+    commands_tx: mpsc::Sender<NodeCommand>,
+    commands_rx: mpsc::Receiver<NodeCommand>,
+}
+
+impl Node {
+    pub async fn new(config: NodeConfig, id_keys: libp2p::identity::Keypair) -> Result<Self> {
+        let peer_id = PeerId::from(id_keys.public());
+        info!("Local peer id: {peer_id}");
+
+        let storage = Arc::new(Storage::open(config.storage.open().await?, config.storage_key.as_ref()).await?.with_quota(config.storage_quota));
+        let access = AccessList::load(&config.allow_peers, &config.deny_peers, &config.bans_path)?;
+        let registry = ModelRegistry::open((*storage).clone(), &config.models_dir).await?;
+
+        // Set up the transport
+        if let Some(psk) = &config.psk {
+            info!("Private hive, swarm key fingerprint {}", psk.fingerprint());
+        }
+        let tcp_transport = secure(tcp::tokio::Transport::new(tcp::Config::default().nodelay(true)), &id_keys, config.psk)?;
+        let ws_transport = secure(
+            libp2p::websocket::WsConfig::new(tcp::tokio::Transport::new(tcp::Config::default().nodelay(true))),
+            &id_keys,
+            config.psk,
+        )?;
+
+        // Circuits through a relay are secured end to end like any other stream
+        let (relay_transport, relay_client) = relay::client::new(peer_id);
+        let relay_transport = secure(relay_transport, &id_keys, config.psk)?;
+
+        let stream_transport = tcp_transport.or_transport(ws_transport)
+            .map(|either, _| match either {
+                Either::Left(output) | Either::Right(output) => output,
+            })
+            .or_transport(relay_transport)
+            .map(|either, _| match either {
+                Either::Left(output) | Either::Right(output) => output,
+            })
+            .boxed();
+
+        // QUIC brings its own TLS handshake, which the private-network layer cannot wrap
+        let transport = if config.psk.is_none() {
+            quic::tokio::Transport::new(quic::Config::new(&id_keys))
+                .map(|(peer_id, connection), _| (peer_id, StreamMuxerBox::new(connection)))
+                .or_transport(stream_transport)
+                .map(|either, _| match either {
+                    Either::Left(output) | Either::Right(output) => output,
+                })
+                .boxed()
+        } else {
+            stream_transport
+        };
+
+        // Set up the behaviour. mDNS is optional so that nodes sharing a host
+        // (tests, several agents per machine) only meet the peers they dial.
+        let mdns = if config.mdns {
+            Some(mdns::tokio::Behaviour::new(mdns::Config::default(), peer_id)?)
+        } else {
+            None
+        };
+
+        // Gossipsub configuration
+        // Envelope ids are unique per send, so two users sending the same prompt
+        // are not deduplicated; undecodable data falls back to a content hash
+        let message_id_fn = |message: &gossipsub::Message| match message::envelope_id(&message.data) {
+            Some(id) => gossipsub::MessageId::from(id),
+            None => {
+                let mut s = DefaultHasher::new();
+                message.data.hash(&mut s);
+                gossipsub::MessageId::from(s.finish().to_string())
+            }
+        };
+        let gossipsub_config = gossipsub::ConfigBuilder::default()
+            .heartbeat_interval(Duration::from_secs(1)) // Faster heartbeat for testing
+            .validation_mode(gossipsub::ValidationMode::Strict)
+            .message_id_fn(message_id_fn)
+            .validate_messages() // Forwarded only after `report_message_validation_result`
+            .mesh_n_low(0)
+            .mesh_n(2)
+            .mesh_n_high(4)
+            .mesh_outbound_min(0)
+            .flood_publish(true) // Ensure it pushes even if mesh is empty
+            .max_transmit_size(message::MAX_CONTROL_MESSAGE_SIZE + 64 * 1024) // Room for signature and framing
+            .build()?;
+        let local_public_key = id_keys.public();
+        let mut gossipsub = gossipsub::Behaviour::new(
+            gossipsub::MessageAuthenticity::Signed(id_keys.clone()),
+            gossipsub_config,
+        )
+        .map_err(anyhow::Error::msg)?;
+        // One gossipsub topic per hive
+        if config.hives.is_empty() {
+            return Err(anyhow::anyhow!("A node has to join at least one hive"));
+        }
+        let topics: Vec<gossipsub::IdentTopic> = config.hives.iter().map(|name| p2p::hive_topic(name)).collect();
+        gossipsub
+            .with_peer_score(p2p::peer_score_params(&topics), gossipsub::PeerScoreThresholds::default())
+            .map_err(anyhow::Error::msg)?;
+
+        // Kademlia for discovery beyond the LAN. Hive nodes always answer DHT
+        // queries; they are not light clients.
+        let mut kad_config = kad::Config::default();
+        kad_config.set_protocol_names(vec![KAD_PROTOCOL]);
+        let mut kademlia = kad::Behaviour::with_config(peer_id, kad::store::MemoryStore::new(peer_id), kad_config);
+        kademlia.set_mode(Some(kad::Mode::Server));
+
+        let identify = identify::Behaviour::new(
+            // The protocol version carries our wire version range, so peers can tell compatibility up front
+            identify::Config::new(message::wire_protocol_version(), local_public_key)
+                .with_agent_version(format!("hive-agent/{}", env!("CARGO_PKG_VERSION"))),
+        );
+
+        let behaviour = HiveBehavior {
+            gossipsub,
+            mdns: Toggle::from(mdns),
+            kademlia,
+            identify,
+            ping: ping::Behaviour::new(ping::Config::new()),
+            tasks: request_response::cbor::Behaviour::new(
+                [(TASK_PROTOCOL, request_response::ProtocolSupport::Full)],
+                request_response::Config::default().with_request_timeout(config.task_timeout),
+            ),
+            blocks: request_response::cbor::Behaviour::new(
+                [(BLOCK_PROTOCOL, request_response::ProtocolSupport::Full)],
+                request_response::Config::default(),
+            ),
+            partials: request_response::Behaviour::new(
+                [(PARTIAL_PROTOCOL, request_response::ProtocolSupport::Full)],
+                request_response::Config::default().with_request_timeout(tensor_parallel::PARTIAL_TIMEOUT),
+            ),
+            relay: Toggle::from(config.relay_server.then(|| relay::Behaviour::new(peer_id, relay::Config::default()))),
+            relay_client,
+            dcutr: dcutr::Behaviour::new(peer_id),
+            autonat: autonat::Behaviour::new(peer_id, autonat::Config::default()),
+            denied: {
+                let mut denied = allow_block_list::Behaviour::default();
+                for peer in access.deny.iter().chain(&access.banned) {
+                    denied.block_peer(*peer);
+                }
+                denied
+            },
+            allowed: Toggle::from(access.allow.as_ref().map(|allow| {
+                let mut allowed = allow_block_list::Behaviour::default();
+                for peer in allow {
+                    allowed.allow_peer(*peer);
+                }
+                allowed
+            })),
+            limits: connection_limits::Behaviour::new(
+                connection_limits::ConnectionLimits::default()
+                    .with_max_established(config.max_connections)
+                    .with_max_established_per_peer(config.max_connections_per_peer),
+            ),
+        };
+
+        // Build the Swarm
+        let mut swarm = Swarm::new(
+            transport,
+            behaviour,
+            peer_id,
+            libp2p::swarm::Config::with_tokio_executor()
+                .with_idle_connection_timeout(Duration::from_secs(60)),
+        );
+
+        for addr in &config.listen_addrs {
+            if config.psk.is_some() && addr.iter().any(|p| matches!(p, Protocol::QuicV1)) {
+                info!("Not listening on {}: QUIC is unavailable in private hives", addr);
+                continue;
+            }
+            swarm.listen_on(addr.clone())?;
+        }
+        for addr in &config.external_addrs {
+            swarm.add_external_address(addr.clone());
+        }
+        if config.relay_server && config.external_addrs.is_empty() {
+            info!("Relay server starts serving once AutoNAT confirms a public address; pass --external to skip the wait");
+        }
+        // Listening on a circuit address makes a reservation on that relay
+        for relay_addr in &config.relays {
+            if !matches!(relay_addr.iter().last(), Some(Protocol::P2p(_))) {
+                return Err(anyhow::anyhow!("Relay address {} must end in /p2p/<PeerId>", relay_addr));
+            }
+            swarm.listen_on(relay_addr.clone().with(Protocol::P2pCircuit))?;
+        }
+
+        for addr in &config.bootstrap {
+            let Some(Protocol::P2p(bootstrap_peer)) = addr.iter().last() else {
+                return Err(anyhow::anyhow!("Bootstrap address {} must end in /p2p/<PeerId>", addr));
+            };
+            if !access.permits(&bootstrap_peer) {
+                info!("Not bootstrapping through {}: the peer is denied", addr);
+                continue;
+            }
+            swarm.behaviour_mut().kademlia.add_address(&bootstrap_peer, addr.clone());
+            swarm.dial(addr.clone())?;
+        }
+        if !config.bootstrap.is_empty() {
+            swarm.behaviour_mut().kademlia.bootstrap()?;
+        }
+
+        for topic in &topics {
+            swarm.behaviour_mut().gossipsub.subscribe(topic)?;
+        }
+
+        // Channel for internal messages (e.g. inference results to broadcast)
+        let (tx, rx) = mpsc::channel::<Message>(32);
+        let (commands_tx, commands_rx) = mpsc::channel(32);
+        let (replies_tx, replies_rx) = mpsc::channel(32);
+        let (block_replies_tx, block_replies_rx) = mpsc::channel(32);
+        let task_slots = Arc::new(Semaphore::new(config.max_concurrent_tasks));
+        let scheduler = Arc::new(Mutex::new(Scheduler::new()));
+        let replicator = Replicator::new(peer_id, storage.clone(), commands_tx.clone(), scheduler.clone());
+
+        Ok(Self {
+            config,
+            peer_id,
+            keypair: id_keys,
+            swarm,
+            topics,
+            scheduler,
+            storage,
+            registry,
+            replicator,
+            replication_round: None,
+            inference_engine: Arc::new(Mutex::new(None)),
+            tp_sessions: Arc::new(Mutex::new(HashMap::new())),
+            message_stats: Arc::new(MessageStats::default()),
+            access: Arc::new(Mutex::new(access)),
+            server_process: Arc::new(Mutex::new(None)),
+            running_tasks: JoinSet::new(),
+            inbound_tasks: 0,
+            drain: None,
+            outbound_tasks: HashMap::new(),
+            task_slots,
+            replies_tx,
+            replies_rx,
+            outbound_blocks: HashMap::new(),
+            block_replies_tx,
+            block_replies_rx,
+            listen_addrs: Arc::new(Mutex::new(Vec::new())),
+            tx,
+            rx,
+            commands_tx,
+            commands_rx,
+        })
+    }
+
+    pub fn handle(&self) -> NodeHandle {
+        NodeHandle {
+            peer_id: self.peer_id,
+            scheduler: self.scheduler.clone(),
+            storage: self.storage.clone(),
+            registry: self.registry.clone(),
+            replicator: self.replicator.clone(),
+            p2p_sender: self.tx.clone(),
+            listen_addrs: self.listen_addrs.clone(),
+            commands: self.commands_tx.clone(),
+            access: self.access.clone(),
+        }
+    }
+
+    /// Runs the dashboard API (if enabled) and the swarm event loop until the
+    /// node is drained.
+    pub async fn run(mut self) {
+        if let Some(port) = self.config.api_port {
+            let api_engine = self.inference_engine.clone();
+            let api_scheduler = self.scheduler.clone();
+            let api_tx = self.tx.clone();
+            let api_commands = self.commands_tx.clone();
+            let server_config = self.config.server_config.clone();
+            let parallel_mode = self.config.parallel_mode;
+            let verification = self.config.verification;
+            let message_stats = self.message_stats.clone();
+            let access = self.access.clone();
+            let server_process = self.server_process.clone();
+            let storage = self.storage.clone();
+            let registry = self.registry.clone();
+            let replicator = self.replicator.clone();
+            let admin_token = self.config.admin_token.clone();
+            let peer_id = self.peer_id;
+            tokio::spawn(async move {
+                http_api::start_server(peer_id, api_engine, api_scheduler, api_tx, api_commands, server_config, parallel_mode, verification, message_stats, access, server_process, storage, registry, replicator, admin_token, port).await;
+            });
+        }
+
+        let mut random_walk = tokio::time::interval(self.config.random_walk_interval);
+        let mut capability_tick = tokio::time::interval(self.config.capability_interval);
+        let mut score_tick = tokio::time::interval(Duration::from_secs(1));
+        let mut drain_tick = tokio::time::interval(Duration::from_millis(100));
+        let mut replication_tick = tokio::time::interval(self.config.replication_interval);
+
+        // Event loop
+        loop {
+            tokio::select! {
+                _ = random_walk.tick() => {
+                    // Look up a random key so the routing table keeps filling in
+                    self.swarm.behaviour_mut().kademlia.get_closest_peers(PeerId::random());
+                }
+                _ = capability_tick.tick() => self.publish_capability(),
+                _ = score_tick.tick() => self.refresh_gossip_scores(),
+                _ = replication_tick.tick() => self.maintain_replicas(),
+                _ = drain_tick.tick(), if self.drain.is_some() => {
+                    let (deadline, linger_until) = self.drain.as_ref().map(|d| (d.deadline, d.linger_until)).unwrap();
+                    if self.inbound_tasks == 0 && tokio::time::Instant::now() >= linger_until {
+                        break;
+                    }
+                    if tokio::time::Instant::now() >= deadline {
+                        info!("Drain deadline passed; cancelling {} running tasks", self.running_tasks.len());
+                        self.running_tasks.abort_all();
+                        break;
+                    }
+                }
+                Some(_) = self.running_tasks.join_next() => {}
+                internal_msg = self.rx.recv() => {
+                    if let Some(msg) = internal_msg {
+                        let data = Envelope::seal(self.peer_id, msg).encode();
+                        if let Err(e) = self.swarm.behaviour_mut().gossipsub.publish(self.topics[0].clone(), data) {
+                             info!("Failed to publish message: {:?}", e);
+                        }
+                    }
+                }
+                command = self.commands_rx.recv() => {
+                    match command {
+                        Some(NodeCommand::Dial(addr)) => {
+                            if let Err(e) = self.swarm.dial(addr.clone()) {
+                                info!("Failed to dial {}: {}", addr, e);
+                            }
+                        }
+                        Some(NodeCommand::SendTask { peer, request, reply }) => {
+                            if self.access.lock().unwrap().permits(&peer) {
+                                let request_id = self.swarm.behaviour_mut().tasks.send_request(&peer, request);
+                                self.outbound_tasks.insert(request_id, reply);
+                            } else {
+                                let _ = reply.send(Err(TaskError::Denied));
+                            }
+                        }
+                        Some(NodeCommand::Block { peer, request, reply }) => {
+                            let request_id = self.swarm.behaviour_mut().blocks.send_request(&peer, request);
+                            self.outbound_blocks.insert(request_id, reply);
+                        }
+                        Some(NodeCommand::Access { change, reply }) => {
+                            let _ = reply.send(self.update_access(change).map_err(|e| e.to_string()));
+                        }
+                        Some(NodeCommand::Partial { peer, partial }) => {
+                            self.swarm.behaviour_mut().partials.send_request(&peer, partial);
+                        }
+                        Some(NodeCommand::Drain { done }) => self.start_drain(done),
+                        None => {}
+                    }
+                }
+                Some((channel, reply)) = self.replies_rx.recv() => {
+                    // Fails only if the requester went away, which it learns on its own
+                    let _ = self.swarm.behaviour_mut().tasks.send_response(channel, reply);
+                }
+                Some((channel, response)) = self.block_replies_rx.recv() => {
+                    let _ = self.swarm.behaviour_mut().blocks.send_response(channel, response);
+                }
+                event = self.swarm.select_next_some() => self.handle_swarm_event(event),
+            }
+        }
+        self.shutdown().await;
+    }
+
+    /// Stops accepting tasks and tells the hive this node is leaving. The
+    /// event loop keeps running so that running tasks can still answer.
+    fn start_drain(&mut self, done: oneshot::Sender<()>) {
+        if let Some(drain) = &mut self.drain {
+            drain.done.push(done);
+            return;
+        }
+        info!("Draining: waiting up to {:?} for {} task(s)", self.config.drain_timeout, self.inbound_tasks);
+        self.publish_all(Message::Leaving);
+        let now = tokio::time::Instant::now();
+        self.drain = Some(Drain {
+            deadline: now + self.config.drain_timeout,
+            linger_until: now + LEAVING_GRACE,
+            done: vec![done],
+        });
+    }
+
+    /// Runs after the event loop: stops child processes and flushes storage.
+    async fn shutdown(&mut self) {
+        if let Some(mut child) = self.server_process.lock().unwrap().take() {
+            info!("Stopping llama-server (pid {})", child.id());
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        if let Err(e) = self.storage.flush().await {
+            info!("Failed to flush storage: {}", e);
+        }
+        info!("Node {} stopped", self.peer_id);
+        for done in self.drain.take().map(|d| d.done).unwrap_or_default() {
+            let _ = done.send(());
+        }
+    }
+
+    fn handle_swarm_event(&mut self, event: SwarmEvent<HiveBehaviorEvent>) {
+        match event {
+            SwarmEvent::NewListenAddr { address, .. } => {
+                info!("Listening on {address:?}");
+                self.listen_addrs.lock().unwrap().push(address);
+            }
+            SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => {
+                // Covers peers we dialled or that dialled us, not only mDNS ones
+                self.swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+                self.scheduler.lock().unwrap().add_peer(peer_id, endpoint.get_remote_address().clone());
+            }
+            SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                self.swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+                self.scheduler.lock().unwrap().remove_peer(&peer_id);
+            }
+            SwarmEvent::Behaviour(HiveBehaviorEvent::Mdns(mdns::Event::Discovered(list))) => {
+                for (peer_id, multiaddr) in list {
+                    if !self.access.lock().unwrap().permits(&peer_id) {
+                        continue;
+                    }
+                    info!("mDNS discovered a new peer: {peer_id} at {multiaddr}");
+                    self.swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+                    self.scheduler.lock().unwrap().add_peer(peer_id, multiaddr);
+                }
+            }
+            SwarmEvent::Behaviour(HiveBehaviorEvent::Mdns(mdns::Event::Expired(list))) => {
+                for (peer_id, _multiaddr) in list {
+                    info!("mDNS discover peer has expired: {peer_id}");
+                    self.swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+                    self.scheduler.lock().unwrap().remove_peer(&peer_id);
+                }
+            }
+            SwarmEvent::Behaviour(HiveBehaviorEvent::Identify(identify::Event::Received { peer_id, info, .. })) => {
+                let wire_versions = message::parse_wire_protocol_version(&info.protocol_version);
+                match wire_versions {
+                    Some((min, max)) if message::is_compatible(min, max) => {}
+                    _ => info!("Peer {} speaks {:?}, which this build cannot read; it will not get tasks", peer_id, info.protocol_version),
+                }
+                let mut scheduler = self.scheduler.lock().unwrap();
+                scheduler.set_identity(
+                    &peer_id,
+                    info.agent_version.clone(),
+                    info.protocols.iter().map(|p| p.to_string()).collect(),
+                    info.observed_addr.clone(),
+                );
+                scheduler.set_wire_versions(&peer_id, wire_versions);
+                drop(scheduler);
+                if info.protocols.contains(&KAD_PROTOCOL) {
+                    for addr in info.listen_addrs {
+                        self.swarm.behaviour_mut().kademlia.add_address(&peer_id, addr);
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(HiveBehaviorEvent::Ping(ping::Event { peer, result: Ok(rtt), .. })) => {
+                self.scheduler.lock().unwrap().set_latency(&peer, rtt);
+            }
+            SwarmEvent::Behaviour(HiveBehaviorEvent::Autonat(autonat::Event::StatusChanged { new, .. })) => {
+                info!("NAT status: {:?}", new);
+                self.scheduler.lock().unwrap().reachability = match new {
+                    autonat::NatStatus::Public(addr) => Reachability::Public(addr),
+                    autonat::NatStatus::Private => Reachability::Private,
+                    autonat::NatStatus::Unknown => Reachability::Unknown,
+                };
+            }
+            SwarmEvent::Behaviour(HiveBehaviorEvent::RelayClient(relay::client::Event::ReservationReqAccepted { relay_peer_id, .. })) => {
+                info!("Reserved a relay slot on {}", relay_peer_id);
+            }
+            SwarmEvent::Behaviour(HiveBehaviorEvent::Dcutr(event)) => {
+                info!("Hole punch with {}: {}", event.remote_peer_id, if event.result.is_ok() { "direct connection" } else { "failed, staying relayed" });
+            }
+            SwarmEvent::Behaviour(HiveBehaviorEvent::Kademlia(kad::Event::RoutingUpdated { peer, addresses, .. })) => {
+                if !self.access.lock().unwrap().permits(&peer) {
+                    return;
+                }
+                info!("Kademlia discovered peer {peer}");
+                self.swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer);
+                let mut scheduler = self.scheduler.lock().unwrap();
+                for addr in addresses.iter() {
+                    scheduler.add_peer(peer, addr.clone());
+                }
+            }
+            SwarmEvent::Behaviour(HiveBehaviorEvent::Kademlia(kad::Event::OutboundQueryProgressed {
+                result: kad::QueryResult::GetClosestPeers(Ok(ok)),
+                ..
+            })) => {
+                for peer in ok.peers {
+                    if peer != self.peer_id && !self.swarm.is_connected(&peer) && self.access.lock().unwrap().permits(&peer) {
+                        let _ = self.swarm.dial(peer);
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(HiveBehaviorEvent::Tasks(request_response::Event::Message { peer, message, .. })) => match message {
+                request_response::Message::Request { request, channel, .. } => {
+                    self.inbound_tasks += 1;
+                    self.handle_task_request(peer, request, channel);
+                }
+                request_response::Message::Response { request_id, response } => {
+                    if let Some(reply) = self.outbound_tasks.remove(&request_id) {
+                        let _ = reply.send(response);
+                    }
+                }
+            },
+            SwarmEvent::Behaviour(HiveBehaviorEvent::Tasks(request_response::Event::OutboundFailure { peer, request_id, error, .. })) => {
+                info!("Task request to {} failed: {}", peer, error);
+                let error = match error {
+                    request_response::OutboundFailure::Timeout => TaskError::Timeout,
+                    other => TaskError::Unreachable(other.to_string()),
+                };
+                if let Some(reply) = self.outbound_tasks.remove(&request_id) {
+                    let _ = reply.send(Err(error));
+                }
+            }
+            SwarmEvent::Behaviour(HiveBehaviorEvent::Tasks(request_response::Event::InboundFailure { peer, error, .. })) => {
+                info!("Task request from {} failed: {}", peer, error);
+                self.inbound_tasks = self.inbound_tasks.saturating_sub(1);
+            }
+            SwarmEvent::Behaviour(HiveBehaviorEvent::Tasks(request_response::Event::ResponseSent { .. })) => {
+                self.inbound_tasks = self.inbound_tasks.saturating_sub(1);
+            }
+            SwarmEvent::Behaviour(HiveBehaviorEvent::Blocks(request_response::Event::Message { peer, message, .. })) => match message {
+                request_response::Message::Request { request, channel, .. } => {
+                    // Reads hit the disk, so they run off the event loop
+                    let replicator = self.replicator.clone();
+                    let replies = self.block_replies_tx.clone();
+                    tokio::spawn(async move {
+                        let response = replicator.serve(peer, request).await;
+                        let _ = replies.send((channel, response)).await;
+                    });
+                }
+                request_response::Message::Response { request_id, response } => {
+                    if let Some(reply) = self.outbound_blocks.remove(&request_id) {
+                        let _ = reply.send(Ok(response));
+                    }
+                }
+            },
+            SwarmEvent::Behaviour(HiveBehaviorEvent::Blocks(request_response::Event::OutboundFailure { request_id, error, .. })) => {
+                if let Some(reply) = self.outbound_blocks.remove(&request_id) {
+                    let _ = reply.send(Err(error.to_string()));
+                }
+            }
+            SwarmEvent::Behaviour(HiveBehaviorEvent::Partials(request_response::Event::Message { peer, message, .. })) => {
+                if let request_response::Message::Request { request, channel, .. } = message {
+                    // Not logged: partials are large and arrive once per layer
+                    if let Some(sender) = self.tp_sessions.lock().unwrap().get(&request.session_id) {
+                        let _ = sender.send(tensor_parallel::Partial { from: peer, step: request.step, rank: request.rank, data: request.data });
+                    }
+                    let _ = self.swarm.behaviour_mut().partials.send_response(channel, ());
+                }
+            }
+            SwarmEvent::Behaviour(HiveBehaviorEvent::Partials(request_response::Event::OutboundFailure { peer, error, .. })) => {
+                info!("Sending a tensor partial to {} failed: {}", peer, error);
+            }
+            SwarmEvent::Behaviour(HiveBehaviorEvent::Gossipsub(gossipsub::Event::Subscribed { peer_id, topic })) => {
+                self.scheduler.lock().unwrap().add_topic(&peer_id, topic.to_string());
+            }
+            SwarmEvent::Behaviour(HiveBehaviorEvent::Gossipsub(gossipsub::Event::Unsubscribed { peer_id, topic })) => {
+                self.scheduler.lock().unwrap().remove_topic(&peer_id, topic.as_str());
+            }
+            SwarmEvent::Behaviour(HiveBehaviorEvent::Gossipsub(gossipsub::Event::Message {
+                propagation_source: peer_id,
+                message_id: id,
+                message,
+            })) => {
+                let envelope = Envelope::decode(&message.data)
+                    .and_then(|envelope| envelope.validate(message.source, message.data.len()));
+                self.message_stats.record(&envelope);
+                // Rejections count against the relaying peer's score; messages from
+                // other versions are merely not forwarded
+                let acceptance = match &envelope {
+                    Ok(_) => gossipsub::MessageAcceptance::Accept,
+                    Err(DecodeError::Malformed(_) | DecodeError::Invalid(_)) => gossipsub::MessageAcceptance::Reject,
+                    Err(DecodeError::Incompatible { .. } | DecodeError::UnknownPayload(_)) => gossipsub::MessageAcceptance::Ignore,
+                };
+                let _ = self.swarm.behaviour_mut().gossipsub.report_message_validation_result(&id, &peer_id, acceptance);
+                match envelope {
+                    // Validated, so the source is the envelope's sender
+                    Ok(envelope) => self.handle_message(message.source.unwrap_or(peer_id), envelope.payload),
+                    Err(e) => info!("Dropping message relayed by {}: {}", peer_id, e),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Records an access change and mirrors it into the gating behaviours,
+    /// which close connections to peers that are no longer admitted.
+    fn update_access(&mut self, change: AccessChange) -> Result<()> {
+        self.access.lock().unwrap().apply(change)?;
+        let behaviour = self.swarm.behaviour_mut();
+        match change {
+            AccessChange::Ban(peer) => {
+                behaviour.denied.block_peer(peer);
+                behaviour.gossipsub.remove_explicit_peer(&peer);
+            }
+            AccessChange::Unban(peer) => behaviour.denied.unblock_peer(peer),
+            AccessChange::Allow(peer) => {
+                if let Some(allowed) = behaviour.allowed.as_mut() {
+                    allowed.allow_peer(peer);
+                }
+            }
+            AccessChange::Disallow(peer) => {
+                if let Some(allowed) = behaviour.allowed.as_mut() {
+                    allowed.disallow_peer(peer);
+                }
+                behaviour.gossipsub.remove_explicit_peer(&peer);
+            }
+        }
+        info!("Access list updated: {:?}", change);
+        Ok(())
+    }
+
+    /// Copies gossipsub scores into the scheduler for the peers API.
+    fn refresh_gossip_scores(&mut self) {
+        let gossipsub = &self.swarm.behaviour().gossipsub;
+        let mut scheduler = self.scheduler.lock().unwrap();
+        for peer in gossipsub.all_peers().map(|(peer, _)| *peer).collect::<Vec<_>>() {
+            if let Some(score) = gossipsub.peer_score(&peer) {
+                scheduler.set_gossip_score(&peer, score);
+            }
+        }
+    }
+
+    /// Handles a validated gossip message from `peer_id`, its author.
+    fn handle_message(&mut self, peer_id: PeerId, msg: Message) {
+        match msg {
+            Message::Leaving => {
+                info!("Peer {} is leaving the hive", peer_id);
+                self.scheduler.lock().unwrap().set_status(&peer_id, "leaving");
+            }
+            Message::Capability { signed } => match signed.verify() {
+                Ok((origin, capability)) => {
+                    if !self.scheduler.lock().unwrap().set_capability(origin, capability, signed.issued_at()) {
+                        info!("Dropping capability record of {} not newer than the one stored", origin);
+                    }
+                }
+                Err(e) => info!("Dropping capability record from {}: {}", peer_id, e),
+            },
+            // Tasks travel over the task protocol; a gossiped one is stale or misrouted
+            Message::TaskRequest { task_id, .. } | Message::Compute { task_id, .. } => {
+                info!("Ignoring task {} broadcast by {}", task_id, peer_id);
+            }
+        }
+    }
+
+    /// Probes this machine and publishes a signed record of it. The record is
+    /// also kept locally so the API lists this node next to its peers.
+    fn publish_capability(&mut self) {
+        let capability = capability::probe(&self.config.models_dir);
+        let signed = match SignedCapability::sign(&self.keypair, &capability) {
+            Ok(signed) => signed,
+            Err(e) => {
+                info!("Failed to sign capability record: {}", e);
+                return;
+            }
+        };
+        self.scheduler.lock().unwrap().set_capability(self.peer_id, capability, signed.issued_at());
+
+        self.publish_all(Message::Capability { signed });
+    }
+
+    /// Starts a round of replica maintenance unless the last one is still
+    /// asking around.
+    fn maintain_replicas(&mut self) {
+        if self.replication_round.as_ref().is_some_and(|round| !round.is_finished()) {
+            return;
+        }
+        let replicator = self.replicator.clone();
+        self.replication_round = Some(tokio::spawn(async move {
+            if let Err(e) = replicator.maintain().await {
+                info!("Replica maintenance failed: {}", e);
+            }
+        }));
+    }
+
+    /// Publishes `msg` to every hive this node joined.
+    fn publish_all(&mut self, msg: Message) {
+        let data = Envelope::seal(self.peer_id, msg).encode();
+        for topic in &self.topics {
+            // InsufficientPeers until someone joins; periodic messages try again next tick
+            let _ = self.swarm.behaviour_mut().gossipsub.publish(topic.clone(), data.clone());
+        }
+    }
+
+    fn handle_task_request(&mut self, peer_id: PeerId, request: Message, channel: request_response::ResponseChannel<TaskReply>) {
+        if let Message::Compute { task_id, task } = request {
+            info!("Received compute task {} from {}", task_id, peer_id);
+            match self.admit(&peer_id, &task_id) {
+                Ok(permit) => self.spawn_compute(task_id, task, permit, channel),
+                Err(e) => {
+                    let _ = self.swarm.behaviour_mut().tasks.send_response(channel, Err(e));
+                }
+            }
+            return;
+        }
+        let Message::TaskRequest { task_id, prompt, model_name, download_url, model, layer_range, tp_ranks, seed } = request else {
+            let _ = self.swarm.behaviour_mut().tasks.send_response(channel, Err(TaskError::Failed("Not a task request".to_string())));
+            return;
+        };
+        info!("Received task {} from {}", task_id, peer_id);
+        // The name is joined onto the models directory and downloaded to, so it must not be a path
+        if !is_plain_file_name(&model_name) {
+            let _ = self.swarm.behaviour_mut().tasks.send_response(channel, Err(TaskError::Failed(format!("Invalid model name {:?}", model_name))));
+            return;
+        }
+        let permit = match self.admit(&peer_id, &task_id) {
+            Ok(permit) => permit,
+            Err(e) => {
+                let _ = self.swarm.behaviour_mut().tasks.send_response(channel, Err(e));
+                return;
+            }
+        };
+
+        // Tensor-parallel tasks name the ranks; anyone else was sent it by mistake
+        let tensor_split = match tp_ranks.map(|ranks| ranks.iter().map(|p| p.parse::<PeerId>()).collect::<Result<Vec<_>, _>>()) {
+            Some(Ok(ranks)) => match ranks.iter().position(|p| *p == self.peer_id) {
+                Some(rank) => Some((TensorSplit { rank, world_size: ranks.len() }, ranks)),
+                None => {
+                    let _ = self.swarm.behaviour_mut().tasks.send_response(channel, Err(TaskError::Failed("Not one of the task's ranks".to_string())));
+                    return;
+                }
+            },
+            Some(Err(e)) => {
+                let _ = self.swarm.behaviour_mut().tasks.send_response(channel, Err(TaskError::Failed(format!("Invalid rank: {}", e))));
+                return;
+            }
+            None => None,
+        };
+
+        let seed = seed.unwrap_or(DEFAULT_SEED);
+        self.spawn_task(task_id, prompt, model_name, download_url, model, layer_range, tensor_split, seed, permit, channel);
+    }
+
+    /// Takes a task slot for a task from `peer_id`, unless the peer is not
+    /// allowed in, the node is draining or every slot is taken.
+    fn admit(&mut self, peer_id: &PeerId, task_id: &str) -> Result<OwnedSemaphorePermit, TaskError> {
+        if !self.access.lock().unwrap().permits(peer_id) {
+            return Err(TaskError::Denied);
+        }
+        if self.drain.is_some() {
+            return Err(TaskError::Draining);
+        }
+        // Backpressure: refuse straight away instead of queueing behind a long generation
+        self.task_slots.clone().try_acquire_owned().map_err(|_| {
+            info!("Busy, refusing task {}", task_id);
+            TaskError::Busy
+        })
+    }
+
+    /// Runs a compute task and answers with the CID of its stored result.
+    fn spawn_compute(&mut self, task_id: String, task: ComputeTask, permit: OwnedSemaphorePermit, channel: request_response::ResponseChannel<TaskReply>) {
+        let storage = self.storage.clone();
+        let commands = self.commands_tx.clone();
+        let scheduler = self.scheduler.clone();
+        let replies = self.replies_tx.clone();
+        self.running_tasks.spawn(async move {
+            let result = matmul::run(&storage, &commands, &scheduler, task).await;
+            drop(permit);
+            let reply = match result {
+                Ok(cid) => Ok(cid.to_string()),
+                Err(e) => {
+                    info!("Compute task {} failed: {}", task_id, e);
+                    Err(TaskError::Failed(e.to_string()))
+                }
+            };
+            let _ = replies.send((channel, reply)).await;
+        });
+    }
+
+    fn spawn_task(
+        &mut self,
+        task_id: String,
+        prompt: String,
+        model_name: String,
+        download_url: Option<String>,
+        model: Option<ModelRef>,
+        layer_range: Option<(usize, usize)>,
+        tensor_split: Option<(TensorSplit, Vec<PeerId>)>, // This node's split and the PeerId of every rank
+        seed: u64,
+        permit: OwnedSemaphorePermit,
+        channel: request_response::ResponseChannel<TaskReply>,
+    ) {
+        info!("Processing Task {} (Range: {:?}, Split: {:?})...", task_id, layer_range, tensor_split.as_ref().map(|(split, _)| split));
+        // Register before loading so early partials from faster ranks are kept
+        let tensor_split = tensor_split.map(|(split, ranks)| {
+            let (partial_tx, partial_rx) = std::sync::mpsc::channel();
+            self.tp_sessions.lock().unwrap().insert(task_id.clone(), partial_tx);
+            (split, PeerAllReduce::new(task_id.clone(), split, ranks, self.commands_tx.clone(), partial_rx))
+        });
+
+        let engine = self.inference_engine.clone();
+        let replies = self.replies_tx.clone();
+        let sessions = self.tp_sessions.clone();
+        let mut model_path = self.config.models_dir.join(&model_name).to_string_lossy().to_string();
+        let registry = self.registry.clone();
+        let commands = self.commands_tx.clone();
+        let scheduler = self.scheduler.clone();
+
+        self.running_tasks.spawn(async move {
+            // Models named by CID come from the registry, fetched from peers if need be
+            let mut registry_tokenizer = None;
+            if let Some(model) = &model {
+                match registry.prepare(model, &commands, &scheduler).await {
+                    Ok((path, tokenizer)) => {
+                        model_path = path.to_string_lossy().to_string();
+                        registry_tokenizer = tokenizer.map(|t| t.to_string_lossy().to_string());
+                    }
+                    Err(e) => {
+                        drop(permit);
+                        sessions.lock().unwrap().remove(&task_id);
+                        let _ = replies.send((channel, Err(TaskError::Failed(format!("Model {} unavailable: {}", model.cid, e))))).await;
+                        return;
+                    }
+                }
+            }
+
+            // LAZY LOADING: Check if model exists, if not, try download
+            if model.is_none() && !Path::new(&model_path).exists() {
+                if let Some(url) = download_url {
+                    info!("Model missing. Attempting to download from Queen: {}", url);
+                    match reqwest::get(&url).await {
+                        Ok(resp) => {
+                            if resp.status().is_success() {
+                                // Stream download
+                                if let Ok(file) = std::fs::File::create(&model_path) {
+                                    let mut file = std::io::BufWriter::new(file);
+                                    let mut stream = resp.bytes_stream();
+                                    while let Some(item) = stream.next().await {
+                                        if let Ok(chunk) = item {
+                                            let _ = std::io::Write::write_all(&mut file, &chunk);
+                                        }
+                                    }
+                                    // Flush
+                                    let _ = std::io::Write::flush(&mut file);
+                                    info!("Download complete: {}", model_path);
+                                }
+                            } else {
+                                info!("Queen failed to serve model (Status {})", resp.status());
+                            }
+                        }
+                        Err(e) => info!("Download error: {}", e),
+                    }
+                }
+            }
+
+            // Check for specific tokenizer
+            let specific_tok = format!("{}.tokenizer.json", model_path);
+            let tokenizer_path = if let Some(tokenizer) = registry_tokenizer {
+                tokenizer
+            } else if Path::new(&specific_tok).exists() {
+                specific_tok
+            } else {
+                "tokenizer.json".to_string()
+            };
+
+            let res = if let Some((split, reducer)) = tensor_split {
+                let res = tokio::task::spawn_blocking(move || {
+                    tensor_parallel::run_rank(&model_path, &tokenizer_path, &prompt, seed, split, reducer)
+                        .map_err(|e| e.to_string())
+                }).await;
+                sessions.lock().unwrap().remove(&task_id);
+                res
+            } else {
+                tokio::task::spawn_blocking(move || {
+                    let mut lock = engine.lock().unwrap();
+                    // Check if loaded, if not try to load
+                    if lock.is_none() || lock.as_ref().unwrap().model_path != model_path {
+                        if Path::new(&model_path).exists() {
+                            info!("Loading model {} with range {:?}...", model_name, layer_range);
+                            if let Ok(new_engine) = InferenceEngine::load(&model_path, &tokenizer_path, layer_range, None) {
+                                *lock = Some(new_engine);
+                            }
+                        }
+                    }
+
+                    if let Some(eng) = lock.as_mut() {
+                        eng.generate_seeded(&prompt, 50, seed).map_err(|e| e.to_string())
+                    } else {
+                        Err("Model not found or failed to load (Download might have failed)".to_string())
+                    }
+                }).await
+            };
+
+            drop(permit);
+            let reply = match res {
+                Ok(Ok(output)) => Ok(output),
+                Ok(Err(e)) => Err(TaskError::Failed(e)),
+                Err(e) => Err(TaskError::Failed(format!("Task {} panicked: {}", task_id, e))),
+            };
+            let _ = replies.send((channel, reply)).await;
+        });
+    }
+}
+
+/// Authenticates and multiplexes a raw transport. With a pre-shared key every
+/// connection first runs the private-network handshake, so nodes without the
+/// key cannot even complete noise.
+fn secure<T>(base: T, id_keys: &libp2p::identity::Keypair, psk: Option<PreSharedKey>) -> Result<Boxed<(PeerId, StreamMuxerBox)>>
+where
+    T: Transport + Send + Unpin + 'static,
+    T::Output: futures::AsyncRead + futures::AsyncWrite + Unpin + Send + 'static,
+    T::Error: Send + Sync + 'static,
+    T::Dial: Send + 'static,
+    T::ListenerUpgrade: Send + 'static,
+{
+    let noise = noise::Config::new(id_keys)?;
+    Ok(match psk {
+        Some(psk) => base
+            .and_then(move |socket, _| PnetConfig::new(psk).handshake(socket))
+            .upgrade(upgrade::Version::V1)
+            .authenticate(noise)
+            .multiplex(yamux::Config::default())
+            .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)))
+            .boxed(),
+        None => base
+            .upgrade(upgrade::Version::V1)
+            .authenticate(noise)
+            .multiplex(yamux::Config::default())
+            .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)))
+            .boxed(),
+    })
+}
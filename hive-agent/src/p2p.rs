@@ -1,9 +1,90 @@
+use anyhow::{anyhow, Result};
 use libp2p::{
-    gossipsub, mdns, swarm::NetworkBehaviour,
+    allow_block_list, autonat, connection_limits, dcutr, gossipsub, identify, kad, mdns, ping, pnet::PreSharedKey, relay, request_response,
+    swarm::{behaviour::toggle::Toggle, NetworkBehaviour},
+    StreamProtocol,
 };
+use crate::exchange::{BlockRequest, BlockResponse};
+use crate::message::{Message, TaskReply};
+use crate::tensor_parallel::PartialCodec;
+use std::path::Path;
+use std::time::Duration;
+
+/// Kademlia protocol name, so hives do not mix with the public IPFS DHT.
+pub const KAD_PROTOCOL: StreamProtocol = StreamProtocol::new("/hive/kad/1.0.0");
+/// Tasks addressed to a single worker, answered on the same stream.
+pub const TASK_PROTOCOL: StreamProtocol = StreamProtocol::new("/hive/task/1.0.0");
+/// Content exchange: who holds a CID, and ranges of it.
+pub const BLOCK_PROTOCOL: StreamProtocol = StreamProtocol::new("/hive/blocks/1.0.0");
+/// Tensor-parallel partials, sent only to the other ranks of a session.
+pub const PARTIAL_PROTOCOL: StreamProtocol = StreamProtocol::new("/hive/partials/1.0.0");
 
 #[derive(NetworkBehaviour)]
 pub struct HiveBehavior {
     pub gossipsub: gossipsub::Behaviour,
-    pub mdns: mdns::tokio::Behaviour,
+    pub mdns: Toggle<mdns::tokio::Behaviour>,
+    pub kademlia: kad::Behaviour<kad::store::MemoryStore>,
+    pub identify: identify::Behaviour, // Listen addresses for Kademlia, agent version for the peers API
+    pub ping: ping::Behaviour,
+    pub tasks: request_response::cbor::Behaviour<Message, TaskReply>,
+    pub blocks: request_response::cbor::Behaviour<BlockRequest, BlockResponse>,
+    pub partials: request_response::Behaviour<PartialCodec>,
+    pub relay: Toggle<relay::Behaviour>, // Only on nodes that serve as relays
+    pub relay_client: relay::client::Behaviour,
+    pub dcutr: dcutr::Behaviour, // Upgrades relayed connections to direct ones
+    pub autonat: autonat::Behaviour,
+    pub denied: allow_block_list::Behaviour<allow_block_list::BlockedPeers>, // Configured and runtime bans
+    pub allowed: Toggle<allow_block_list::Behaviour<allow_block_list::AllowedPeers>>, // Only with an allowlist
+    pub limits: connection_limits::Behaviour,
+}
+
+/// The hive every node joins unless told otherwise.
+pub const DEFAULT_HIVE: &str = "main";
+
+/// Gossipsub topic of a named hive; the default hive keeps the old "hive-main".
+pub fn hive_topic(name: &str) -> gossipsub::IdentTopic {
+    gossipsub::IdentTopic::new(format!("hive-{}", name))
+}
+
+/// The hive name behind a topic, or `None` for topics that are not hives.
+pub fn hive_name(topic: &str) -> Option<&str> {
+    topic.strip_prefix("hive-")
+}
+
+/// Gossipsub scoring for the hive topics. Hive traffic is sparse, so mesh
+/// delivery rates are not scored; invalid messages are, heavily enough that a
+/// few of them push a peer below the graylist threshold.
+pub fn peer_score_params(topics: &[gossipsub::IdentTopic]) -> gossipsub::PeerScoreParams {
+    let mut params = gossipsub::PeerScoreParams::default();
+    for topic in topics {
+        let topic_params = gossipsub::TopicScoreParams {
+            topic_weight: 1.0,
+            time_in_mesh_weight: 0.01,
+            time_in_mesh_quantum: Duration::from_secs(1),
+            time_in_mesh_cap: 10.0,
+            first_message_deliveries_weight: 1.0,
+            first_message_deliveries_decay: 0.9,
+            first_message_deliveries_cap: 10.0,
+            mesh_message_deliveries_weight: 0.0,
+            mesh_failure_penalty_weight: 0.0,
+            invalid_message_deliveries_weight: -10.0,
+            invalid_message_deliveries_decay: 0.99, // Forgiven over minutes, not seconds
+            ..Default::default()
+        };
+        params.topics.insert(topic.hash(), topic_params);
+    }
+    params.topic_score_cap = 20.0;
+    params
+}
+
+/// Reads a swarm key in the usual `/key/swarm/psk/1.0.0/` text format.
+pub fn load_swarm_key(path: &Path) -> Result<PreSharedKey> {
+    let text = std::fs::read_to_string(path)?;
+    text.parse().map_err(|e| anyhow!("Invalid swarm key {}: {}", path.display(), e))
+}
+
+/// A fresh swarm key in the format `load_swarm_key` reads.
+pub fn generate_swarm_key() -> String {
+    let key: [u8; 32] = rand::random();
+    format!("/key/swarm/psk/1.0.0/\n/base16/\n{}\n", hex::encode(key))
 }
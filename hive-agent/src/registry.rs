@@ -0,0 +1,368 @@
+use anyhow::{anyhow, Result};
+use candle_core::quantized::gguf_file;
+use hive_core::Cid;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Seek};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, Mutex};
+use tracing::info;
+use crate::exchange;
+use crate::node::NodeCommand;
+use crate::scheduler::Scheduler;
+use crate::storage::{self, Storage};
+
+/// A model as tasks name it: the CID of its weights, plus the CID of its
+/// `tokenizer.json` when the tokenizer is not embedded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelRef {
+    pub cid: Cid,
+    pub tokenizer: Option<Cid>,
+}
+
+/// What a model file records about itself; anything it does not record is None.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ModelMetadata {
+    pub format: String, // "gguf", or the file extension for formats that are not parsed
+    pub name: Option<String>,
+    pub architecture: Option<String>,
+    pub parameters: Option<u64>,
+    pub quantization: Option<String>,
+    pub context_length: Option<u64>,
+    pub layers: Option<u64>,
+    pub embedded_tokenizer: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelInfo {
+    pub cid: Cid,
+    pub file_name: String, // Name the model was first imported under
+    pub size: u64,
+    pub tokenizer: Option<Cid>,
+    pub aliases: Vec<String>,
+    pub metadata: ModelMetadata,
+}
+
+impl ModelInfo {
+    pub fn model_ref(&self) -> ModelRef {
+        ModelRef { cid: self.cid.clone(), tokenizer: self.tokenizer.clone() }
+    }
+
+    pub fn has_tokenizer(&self) -> bool {
+        self.metadata.embedded_tokenizer || self.tokenizer.is_some()
+    }
+
+    /// Extension of the materialised file, so loaders that go by it still work.
+    fn extension(&self) -> &str {
+        Path::new(&self.file_name).extension().and_then(|e| e.to_str()).unwrap_or("gguf")
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Index {
+    models: BTreeMap<Cid, ModelInfo>,
+}
+
+/// Models kept in `Storage` by CID, with aliases and parsed metadata, indexed
+/// in `<models_dir>/registry.json`. Loaders need a plain file, so each model is
+/// also materialised once as `<models_dir>/<cid>.<ext>`. Cheap to clone.
+#[derive(Clone)]
+pub struct ModelRegistry {
+    storage: Storage,
+    models_dir: PathBuf,
+    index: Arc<Mutex<Index>>,
+    verified: Arc<Mutex<HashMap<PathBuf, SystemTime>>>, // Materialised files known to match their CID, by modification time
+}
+
+impl ModelRegistry {
+    pub async fn open(storage: Storage, models_dir: &Path) -> Result<Self> {
+        tokio::fs::create_dir_all(models_dir).await?;
+        let path = models_dir.join("registry.json");
+        let index = match tokio::fs::read(&path).await {
+            Ok(data) => serde_json::from_slice(&data).map_err(|e| anyhow!("Invalid model registry {}: {}", path.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Index::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            storage,
+            models_dir: models_dir.to_path_buf(),
+            index: Arc::new(Mutex::new(index)),
+            verified: Arc::default(),
+        })
+    }
+
+    async fn save(&self, index: &Index) -> Result<()> {
+        storage::write_atomic(&self.models_dir.join("registry.json"), &serde_json::to_vec_pretty(index)?).await
+    }
+
+    pub async fn list(&self) -> Vec<ModelInfo> {
+        self.index.lock().await.models.values().cloned().collect()
+    }
+
+    /// Looks a model up by CID or alias.
+    pub async fn resolve(&self, name: &str) -> Option<ModelInfo> {
+        let index = self.index.lock().await;
+        match name.parse::<Cid>() {
+            Ok(cid) => index.models.get(&cid).cloned(),
+            Err(_) => index.models.values().find(|m| m.aliases.iter().any(|a| a == name)).cloned(),
+        }
+    }
+
+    /// Stores a model file, and its tokenizer (`tokenizer`, or else a
+    /// `<file>.tokenizer.json` sidecar), and registers them pinned. The
+    /// materialised copy is written from storage, never linked to the file,
+    /// so later edits to the file cannot change what the CID loads.
+    pub async fn import(&self, path: &Path, alias: Option<&str>, tokenizer: Option<&Path>) -> Result<ModelInfo> {
+        let metadata = inspect_file(path).await?;
+        let cid = self.storage.store_file(path).await?;
+        let sidecar = PathBuf::from(format!("{}.tokenizer.json", path.display()));
+        let tokenizer = match tokenizer.map(Path::to_path_buf).or(sidecar.exists().then_some(sidecar)) {
+            Some(tokenizer) => Some(self.storage.store_file(&tokenizer).await?),
+            None => None,
+        };
+
+        let mut index = self.index.lock().await;
+        if !index.models.contains_key(&cid) {
+            self.storage.pin(&cid).await?;
+            let info = ModelInfo {
+                cid: cid.clone(),
+                file_name: path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default(),
+                size: self.storage.size(&cid).await?.unwrap_or(0),
+                tokenizer: None,
+                aliases: Vec::new(),
+                metadata,
+            };
+            index.models.insert(cid.clone(), info);
+        }
+        let info = index.models.get_mut(&cid).expect("inserted above");
+        if let (None, Some(tokenizer)) = (&info.tokenizer, tokenizer) {
+            self.storage.pin(&tokenizer).await?;
+            info.tokenizer = Some(tokenizer);
+        }
+        self.materialize(&cid, info.extension()).await?;
+        if let Some(alias) = alias {
+            set_alias(&mut index, alias, &cid)?;
+        }
+        let info = index.models[&cid].clone();
+        self.save(&index).await?;
+        info!("Registered model {} ({})", cid, info.file_name);
+        Ok(info)
+    }
+
+    /// Records a tokenizer for a registered model that has none, pinned.
+    pub async fn attach_tokenizer(&self, cid: &Cid, path: &Path) -> Result<()> {
+        let tokenizer = self.storage.store_file(path).await?;
+        let mut index = self.index.lock().await;
+        let info = index.models.get_mut(cid).ok_or_else(|| anyhow!("Model {} is not registered", cid))?;
+        if info.tokenizer.is_none() {
+            self.storage.pin(&tokenizer).await?;
+            info.tokenizer = Some(tokenizer);
+            self.save(&index).await?;
+        }
+        Ok(())
+    }
+
+    /// Points `alias` at a registered model, taking it from any other model.
+    pub async fn set_alias(&self, alias: &str, cid: &Cid) -> Result<()> {
+        let mut index = self.index.lock().await;
+        set_alias(&mut index, alias, cid)?;
+        self.save(&index).await
+    }
+
+    /// Paths of the weights and tokenizer of a registered model, writing the
+    /// materialised copies from storage if they are missing.
+    pub async fn paths(&self, info: &ModelInfo) -> Result<(PathBuf, Option<PathBuf>)> {
+        let model = self.materialize(&info.cid, info.extension()).await?;
+        let tokenizer = match &info.tokenizer {
+            Some(cid) => Some(self.materialize(cid, "tokenizer.json").await?),
+            None => None,
+        };
+        Ok((model, tokenizer))
+    }
+
+    /// Makes a model a task names ready to load: fetches whatever is missing
+    /// from peers, each chunk checked against its hash, registers it if it is
+    /// new here and returns the paths to load. Fetched models are not pinned.
+    pub async fn prepare(&self, model: &ModelRef, commands: &mpsc::Sender<NodeCommand>, scheduler: &Arc<std::sync::Mutex<Scheduler>>) -> Result<(PathBuf, Option<PathBuf>)> {
+        for cid in std::iter::once(&model.cid).chain(&model.tokenizer) {
+            if self.storage.size(cid).await?.is_none() {
+                exchange::fetch(&self.storage, commands, scheduler, cid).await?;
+            }
+        }
+        let mut index = self.index.lock().await;
+        let info = match index.models.get(&model.cid) {
+            Some(info) => ModelInfo { tokenizer: info.tokenizer.clone().or(model.tokenizer.clone()), ..info.clone() },
+            None => {
+                let path = self.materialize(&model.cid, "gguf").await?;
+                let info = ModelInfo {
+                    cid: model.cid.clone(),
+                    file_name: format!("{}.gguf", model.cid),
+                    size: self.storage.size(&model.cid).await?.unwrap_or(0),
+                    tokenizer: model.tokenizer.clone(),
+                    aliases: Vec::new(),
+                    metadata: inspect_file(&path).await?,
+                };
+                index.models.insert(model.cid.clone(), info.clone());
+                self.save(&index).await?;
+                info
+            }
+        };
+        drop(index);
+        self.paths(&info).await
+    }
+
+    /// Writes `cid` from storage to `<models_dir>/<cid>.<extension>`. A file
+    /// already there is hashed before it is used, once per modification,
+    /// since anything could have written to the models directory.
+    async fn materialize(&self, cid: &Cid, extension: &str) -> Result<PathBuf> {
+        let path = self.models_dir.join(format!("{}.{}", cid, extension));
+        if let Ok(modified) = tokio::fs::metadata(&path).await.and_then(|m| m.modified()) {
+            if self.verified.lock().await.get(&path) == Some(&modified) {
+                return Ok(path);
+            }
+            if storage::content_cid(tokio::fs::File::open(&path).await?).await? == *cid {
+                self.verified.lock().await.insert(path.clone(), modified);
+                return Ok(path);
+            }
+            info!("{} does not match its CID; writing it again", path.display());
+        }
+        let mut reader = self.storage.reader(cid).await?.ok_or_else(|| anyhow!("{} is not in local storage", cid))?;
+        // Unique, so that concurrent tasks loading the same model do not write into each other's copy
+        let tmp = self.models_dir.join(format!("{}.{}.{}.part", cid, extension, uuid::Uuid::new_v4()));
+        let mut file = tokio::fs::File::create(&tmp).await?;
+        tokio::io::copy(&mut reader, &mut file).await?;
+        file.flush().await?;
+        drop(file);
+        tokio::fs::rename(&tmp, &path).await?;
+        if let Ok(modified) = tokio::fs::metadata(&path).await.and_then(|m| m.modified()) {
+            self.verified.lock().await.insert(path.clone(), modified);
+        }
+        Ok(path)
+    }
+}
+
+fn set_alias(index: &mut Index, alias: &str, cid: &Cid) -> Result<()> {
+    if alias.parse::<Cid>().is_ok() {
+        return Err(anyhow!("Alias {} would read as a CID", alias));
+    }
+    if !index.models.contains_key(cid) {
+        return Err(anyhow!("Model {} is not registered", cid));
+    }
+    for info in index.models.values_mut() {
+        info.aliases.retain(|a| a != alias);
+    }
+    index.models.get_mut(cid).expect("checked above").aliases.push(alias.to_string());
+    Ok(())
+}
+
+async fn inspect_file(path: &Path) -> Result<ModelMetadata> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || inspect(&path)).await?
+}
+
+/// Reads a model file's metadata. GGUF headers are parsed without loading
+/// any tensor data; other formats are only identified by extension.
+pub fn inspect(path: &Path) -> Result<ModelMetadata> {
+    let mut file = std::fs::File::open(path)?;
+    let mut magic = [0u8; 4];
+    if file.read_exact(&mut magic).is_err() || &magic != b"GGUF" {
+        let format = path.extension().map(|e| e.to_string_lossy().to_string()).unwrap_or_else(|| "unknown".to_string());
+        return Ok(ModelMetadata { format, ..ModelMetadata::default() });
+    }
+    file.rewind()?;
+    let content = gguf_file::Content::read(&mut file).map_err(|e| anyhow!("Invalid GGUF {}: {}", path.display(), e))?;
+
+    let text = |key: &str| content.metadata.get(key).and_then(|v| v.to_string().ok()).cloned();
+    let number = |key: &str| {
+        let value = content.metadata.get(key)?;
+        value.to_u64().or_else(|_| value.to_u32().map(u64::from)).ok()
+    };
+    let architecture = text("general.architecture");
+    let per_architecture = |key: &str| architecture.as_ref().and_then(|arch| number(&format!("{}.{}", arch, key)));
+
+    // Tensor types weighted by size, for files that do not state a file type
+    let mut by_type: HashMap<String, u64> = HashMap::new();
+    for tensor in content.tensor_infos.values() {
+        *by_type.entry(format!("{:?}", tensor.ggml_dtype)).or_default() += tensor.shape.elem_count() as u64;
+    }
+    let dominant_type = by_type.into_iter().max_by_key(|(_, count)| *count).map(|(name, _)| name);
+
+    Ok(ModelMetadata {
+        format: "gguf".to_string(),
+        name: text("general.name"),
+        parameters: Some(content.tensor_infos.values().map(|t| t.shape.elem_count() as u64).sum()),
+        quantization: number("general.file_type").and_then(file_type_name).map(str::to_string).or(dominant_type),
+        context_length: per_architecture("context_length"),
+        layers: per_architecture("block_count"),
+        embedded_tokenizer: content.metadata.contains_key("tokenizer.ggml.model"),
+        architecture,
+    })
+}
+
+/// Names of llama.cpp's `general.file_type` values.
+fn file_type_name(file_type: u64) -> Option<&'static str> {
+    Some(match file_type {
+        0 => "F32",
+        1 => "F16",
+        2 => "Q4_0",
+        3 => "Q4_1",
+        7 => "Q8_0",
+        8 => "Q5_0",
+        9 => "Q5_1",
+        10 => "Q2_K",
+        11 => "Q3_K_S",
+        12 => "Q3_K_M",
+        13 => "Q3_K_L",
+        14 => "Q4_K_S",
+        15 => "Q4_K_M",
+        16 => "Q5_K_S",
+        17 => "Q5_K_M",
+        18 => "Q6_K",
+        32 => "BF16",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness;
+
+    #[tokio::test]
+    async fn imported_model_is_described_and_found_by_alias() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let model = harness::install_synthetic_model(&dir.path().join("upload"))?;
+        let storage = Storage::new(dir.path().join("storage")).await?;
+        let registry = ModelRegistry::open(storage.clone(), &dir.path().join("models")).await?;
+
+        let info = registry.import(&model, Some("tiny"), None).await?;
+        assert_eq!(info.metadata.format, "gguf");
+        assert_eq!(info.metadata.architecture.as_deref(), Some("llama"));
+        assert_eq!(info.metadata.layers, Some(harness::BLOCKS as u64));
+        assert_eq!(info.metadata.context_length, Some(256));
+        assert_eq!(info.metadata.quantization.as_deref(), Some("F32"));
+        assert!(info.metadata.parameters.unwrap() > (harness::VOCAB * harness::EMBD) as u64);
+        assert!(info.has_tokenizer() && !info.metadata.embedded_tokenizer);
+        assert_eq!(storage.pins().await?.get(&info.cid), Some(&1));
+
+        // The index survives a restart, and the alias resolves to the CID
+        let registry = ModelRegistry::open(storage, &dir.path().join("models")).await?;
+        let found = registry.resolve("tiny").await.expect("alias resolves");
+        assert_eq!(found.cid, info.cid);
+        assert!(registry.resolve(&info.cid.to_string()).await.is_some());
+        let (weights, tokenizer) = registry.paths(&found).await?;
+        assert_eq!(std::fs::read(&weights)?, std::fs::read(&model)?);
+
+        // Neither edits to the imported file nor to the copy change what the CID loads
+        std::fs::write(&model, b"edited")?;
+        std::fs::write(&weights, b"tampered")?;
+        let (weights, _) = registry.paths(&found).await?;
+        assert_eq!(storage::content_cid(tokio::fs::File::open(&weights).await?).await?, info.cid);
+        assert!(tokenizer.is_some_and(|t| t.exists()));
+        assert!(registry.set_alias(&info.cid.to_string(), &info.cid).await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,242 @@
+use anyhow::{anyhow, Result};
+use hive_core::Cid;
+use libp2p::PeerId;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tracing::info;
+use crate::exchange::{self, BlockRequest, BlockResponse};
+use crate::node::NodeCommand;
+use crate::scheduler::Scheduler;
+use crate::storage::Storage;
+
+/// Who keeps a replica of a CID, among this node and its connected peers.
+#[derive(Debug, Clone)]
+pub struct ReplicationStatus {
+    pub cid: Cid,
+    pub factor: Option<u32>, // This node's factor, else the highest a holder reports; None if nobody replicates it
+    pub holders: Vec<PeerId>, // Sorted; replicas still being fetched count
+}
+
+impl ReplicationStatus {
+    /// Copies still to be made before the factor is met.
+    pub fn missing(&self) -> usize {
+        (self.factor.unwrap_or(0) as usize).saturating_sub(self.holders.len())
+    }
+}
+
+/// Keeps the replication factors set in local storage. Every holder of a
+/// replica knows the factor, so the hive keeps maintaining it when the node
+/// that set it goes away. Cheap to clone.
+#[derive(Clone)]
+pub struct Replicator {
+    local: PeerId,
+    storage: Arc<Storage>,
+    commands: mpsc::Sender<NodeCommand>,
+    scheduler: Arc<Mutex<Scheduler>>,
+    fetching: Arc<Mutex<HashMap<Cid, (u32, u64)>>>, // Replicas accepted but not yet stored, with their factor and size
+}
+
+impl Replicator {
+    pub fn new(local: PeerId, storage: Arc<Storage>, commands: mpsc::Sender<NodeCommand>, scheduler: Arc<Mutex<Scheduler>>) -> Self {
+        Self { local, storage, commands, scheduler, fetching: Arc::default() }
+    }
+
+    /// Asks the hive to keep `factor` copies of stored content, this one
+    /// included, and recruits peers for the copies missing. 0 stops
+    /// replicating it here; copies already made elsewhere stay.
+    pub async fn set_factor(&self, cid: &Cid, factor: u32) -> Result<ReplicationStatus> {
+        self.storage.set_replication(cid, factor).await?;
+        let status = self.status(cid).await?;
+        if factor > 0 && status.missing() > 0 {
+            self.recruit(&status).await?;
+        }
+        Ok(status)
+    }
+
+    /// The replication factor of `cid` here, if this node keeps a replica.
+    async fn held(&self, cid: &Cid) -> Result<Option<u32>> {
+        if let Some((factor, _)) = self.fetching.lock().unwrap().get(cid) {
+            return Ok(Some(*factor));
+        }
+        Ok(self.storage.replication().await?.get(cid).copied())
+    }
+
+    /// Asks this node and every connected peer whether it keeps a replica of `cid`.
+    pub async fn status(&self, cid: &Cid) -> Result<ReplicationStatus> {
+        let mut holders = Vec::new();
+        let local_factor = self.held(cid).await?;
+        if local_factor.is_some() {
+            holders.push(self.local);
+        }
+        let peers: Vec<PeerId> = self.scheduler.lock().unwrap().peers.keys().copied().collect();
+        let answers = futures::future::join_all(
+            peers.iter().map(|peer| exchange::request(&self.commands, *peer, BlockRequest::Replica { cid: cid.clone() })),
+        )
+        .await;
+        let mut reported = None;
+        for (peer, answer) in peers.into_iter().zip(answers) {
+            if let Ok(BlockResponse::Replica { factor }) = answer {
+                holders.push(peer);
+                reported = reported.max(Some(factor));
+            }
+        }
+        holders.sort();
+        Ok(ReplicationStatus { cid: cid.clone(), factor: local_factor.or(reported), holders })
+    }
+
+    /// Checks every CID replicated here and tops up the ones that lost
+    /// holders. Only the holder with the lowest PeerId recruits, so that the
+    /// others do not all ask for the same copies at once.
+    pub async fn maintain(&self) -> Result<()> {
+        for cid in self.storage.replication().await?.into_keys() {
+            let status = self.status(&cid).await?;
+            if status.missing() > 0 && status.holders.first() == Some(&self.local) {
+                self.recruit(&status).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Asks peers that do not hold `status.cid` yet to take a replica until
+    /// enough have accepted. Peers decline when they set no quota, their
+    /// quota has no room, or they do not trust this node.
+    async fn recruit(&self, status: &ReplicationStatus) -> Result<()> {
+        let cid = &status.cid;
+        let size = self.storage.size(cid).await?.ok_or_else(|| anyhow!("{} is not stored", cid))?;
+        let factor = status.factor.unwrap_or(1);
+        let mut candidates: Vec<PeerId> = self.scheduler.lock().unwrap().peers.values()
+            .filter(|p| p.accepts_work() && !status.holders.contains(&p.id))
+            .map(|p| p.id)
+            .collect();
+        candidates.sort();
+
+        let mut recruited = 0;
+        for peer in candidates {
+            if recruited == status.missing() {
+                break;
+            }
+            let request = BlockRequest::Replicate { cid: cid.clone(), size, factor };
+            match exchange::request(&self.commands, peer, request).await {
+                Ok(BlockResponse::Accepted) => {
+                    info!("Peer {} takes a replica of {}", peer, cid);
+                    recruited += 1;
+                }
+                Ok(BlockResponse::Refused(reason)) => info!("Peer {} declined a replica of {}: {}", peer, cid, reason),
+                Ok(_) => {}
+                Err(e) => info!("Asking {} to replicate {} failed: {}", peer, cid, e),
+            }
+        }
+        if recruited < status.missing() {
+            info!("{} is under-replicated: {} of {} copies", cid, status.holders.len() + recruited, factor);
+        }
+        Ok(())
+    }
+
+    /// Answers a block request from `peer`, including the replication ones
+    /// that `exchange::serve` leaves to us.
+    pub async fn serve(&self, peer: PeerId, request: BlockRequest) -> BlockResponse {
+        match request {
+            BlockRequest::Replica { cid } => match self.held(&cid).await {
+                Ok(Some(factor)) => BlockResponse::Replica { factor },
+                _ => BlockResponse::NotFound,
+            },
+            BlockRequest::Replicate { cid, size, factor } => self.accept(peer, cid, size, factor).await,
+            request => exchange::serve(&self.storage, request).await,
+        }
+    }
+
+    /// Takes a replica for `peer` if the quota leaves room for it next to
+    /// the content already pinned and the replicas still being fetched.
+    /// Nodes without a quota have no idea how much room they have, so they
+    /// take no replicas; nor do they take any from peers that are not
+    /// connected or no longer trusted. The size comes from the manifest,
+    /// not from the request, so the quota holds against peers that
+    /// understate it. The chunks are fetched in the background; until they
+    /// are, the replica counts as held so that it is not requested twice.
+    async fn accept(&self, peer: PeerId, cid: Cid, declared: u64, factor: u32) -> BlockResponse {
+        let Some(quota) = self.storage.quota() else {
+            return BlockResponse::Refused("No storage quota set, so no room to promise".to_string());
+        };
+        {
+            let scheduler = self.scheduler.lock().unwrap();
+            if !scheduler.peers.get(&peer).is_some_and(|p| p.accepts_work()) || !scheduler.is_trusted(&peer) {
+                return BlockResponse::Refused("Replicas are only taken for trusted peers".to_string());
+            }
+        }
+        match self.held(&cid).await {
+            Ok(Some(_)) => return BlockResponse::Accepted,
+            Ok(None) => {}
+            Err(e) => return BlockResponse::Refused(e.to_string()),
+        }
+        let refuse = |size| BlockResponse::Refused(format!("No room for {} bytes under the storage quota", size));
+        let pinned = match self.storage.usage().await {
+            Ok(usage) if usage.pinned + declared > quota => return refuse(declared),
+            Ok(usage) => usage.pinned,
+            Err(e) => return BlockResponse::Refused(e.to_string()),
+        };
+        let (size, source) = match self.storage.size(&cid).await {
+            Ok(Some(size)) => (size, None),
+            Ok(None) => match exchange::locate(&self.commands, &self.scheduler, &cid).await {
+                Ok(source) => (source.manifest.size, Some(source)),
+                Err(e) => return BlockResponse::Refused(e.to_string()),
+            },
+            Err(e) => return BlockResponse::Refused(e.to_string()),
+        };
+        {
+            // Checked and reserved under one lock, so concurrent offers cannot overbook the quota
+            let mut fetching = self.fetching.lock().unwrap();
+            let in_flight: u64 = fetching.values().map(|(_, size)| size).sum();
+            if pinned + in_flight + size > quota {
+                return refuse(size);
+            }
+            fetching.insert(cid.clone(), (factor, size));
+        }
+
+        let this = self.clone();
+        tokio::spawn(async move {
+            let result = async {
+                if let Some(source) = source {
+                    exchange::fetch_from(&this.storage, &this.commands, &cid, &source).await?;
+                }
+                this.storage.set_replication(&cid, factor).await
+            }
+            .await;
+            this.fetching.lock().unwrap().remove(&cid);
+            match result {
+                Ok(_) => info!("Keeping a replica of {} (factor {})", cid, factor),
+                Err(e) => info!("Failed to replicate {}: {}", cid, e),
+            }
+        });
+        BlockResponse::Accepted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::TestHive;
+    use crate::node::NodeConfig;
+    use crate::storage::CHUNK_SIZE;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn replicas_are_sized_by_their_manifest() -> Result<()> {
+        let hive = TestHive::start_with(2, |i, config| NodeConfig { storage_quota: (i == 1).then_some(CHUNK_SIZE), ..config }).await?;
+        let (origin, small) = (&hive.nodes[0], &hive.nodes[1]);
+        let large = origin.storage.store(&vec![7; 2 * CHUNK_SIZE as usize]).await?;
+        let fits = origin.storage.store(&[7; 1000]).await?;
+
+        // Understating the size does not get content past the quota
+        let offer = |cid: &Cid, size| BlockRequest::Replicate { cid: cid.clone(), size, factor: 2 };
+        let answer = exchange::request(&origin.commands, small.peer_id, offer(&large, 1)).await?;
+        assert!(matches!(answer, BlockResponse::Refused(_)), "{:?}", answer);
+        let answer = exchange::request(&origin.commands, small.peer_id, offer(&fits, 1000)).await?;
+        assert!(matches!(answer, BlockResponse::Accepted), "{:?}", answer);
+
+        // Without a quota the origin cannot tell whether it has room
+        let back = small.storage.store(&[8; 1000]).await?;
+        let answer = exchange::request(&small.commands, origin.peer_id, offer(&back, 1000)).await?;
+        assert!(matches!(answer, BlockResponse::Refused(_)), "{:?}", answer);
+        Ok(())
+    }
+}
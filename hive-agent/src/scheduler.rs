@@ -1,21 +1,75 @@
+use hive_core::NodeCapability;
 use libp2p::{PeerId, Multiaddr};
 use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+/// Flags at which a peer is no longer picked for verified work. One flag
+/// can be bad luck, e.g. a replica running a different build.
+pub const FLAG_LIMIT: u32 = 3;
 
 #[derive(Debug, Clone)]
 pub struct PeerInfo {
     pub id: PeerId,
     pub address: Vec<Multiaddr>,
-    pub status: String, // "active", "busy"
+    pub status: String, // "active", "busy", "leaving"
+    pub topics: Vec<String>, // Gossipsub topics the peer has subscribed to
+    pub latency: Option<Duration>, // Last ping round trip
+    pub agent_version: Option<String>, // From identify, e.g. "hive-agent/0.1.0"
+    pub protocols: Vec<String>,
+    pub observed_addr: Option<Multiaddr>, // How the peer sees this node
+    pub wire_versions: Option<(u16, u16)>, // Message versions the peer reads, from identify
+    pub gossip_score: Option<f64>, // Our gossipsub score for the peer; negative after invalid messages
+}
+
+impl PeerInfo {
+    /// False only for peers known to run a build we cannot exchange messages with.
+    /// Peers that have not been identified yet get the benefit of the doubt.
+    pub fn is_compatible(&self) -> bool {
+        self.wire_versions.map(|(min, max)| crate::message::is_compatible(min, max)).unwrap_or(true)
+    }
+
+    /// Compatible and not on its way out of the hive.
+    pub fn accepts_work(&self) -> bool {
+        self.is_compatible() && self.status != "leaving"
+    }
+}
+
+/// The latest capability record a node published, and when it arrived.
+#[derive(Debug, Clone)]
+pub struct CapabilityRecord {
+    pub capability: NodeCapability,
+    pub issued_at: SystemTime, // Signed by the node, by its clock
+    pub received: SystemTime,
+}
+
+/// Whether other peers can dial this node, as AutoNAT probes found.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum Reachability {
+    #[default]
+    Unknown,
+    Public(Multiaddr),
+    Private, // Behind NAT; reachable only through a relay
 }
 
 pub struct Scheduler {
     pub peers: HashMap<PeerId, PeerInfo>,
+    // Kept apart from `peers`: records are relayed by gossip, so they also
+    // arrive from nodes we have no direct connection to
+    pub capabilities: HashMap<PeerId, CapabilityRecord>,
+    // Times each peer disagreed with the majority of a verified task, less
+    // the times it agreed since. Also kept apart, so that reconnecting does
+    // not clear a peer's record
+    pub flags: HashMap<PeerId, u32>,
+    pub reachability: Reachability, // Of this node, not of the peers
 }
 
 impl Scheduler {
     pub fn new() -> Self {
         Self {
             peers: HashMap::new(),
+            capabilities: HashMap::new(),
+            flags: HashMap::new(),
+            reachability: Reachability::Unknown,
         }
     }
 
@@ -24,17 +78,198 @@ impl Scheduler {
             id: peer_id,
             address: Vec::new(),
             status: "active".to_string(),
+            topics: Vec::new(),
+            latency: None,
+            agent_version: None,
+            protocols: Vec::new(),
+            observed_addr: None,
+            wire_versions: None,
+            gossip_score: None,
         });
         if !entry.address.contains(&addr) {
             entry.address.push(addr);
         }
     }
 
+    pub fn add_topic(&mut self, peer_id: &PeerId, topic: String) {
+        if let Some(entry) = self.peers.get_mut(peer_id) {
+            if !entry.topics.contains(&topic) {
+                entry.topics.push(topic);
+            }
+        }
+    }
+
+    /// Stores a record only if it was issued after the one already stored:
+    /// gossip can deliver old records after new ones, and anyone can replay
+    /// a signed record. A replay must not refresh `received`, or a relayed
+    /// record would keep a departed node looking fresh. Returns whether it
+    /// was stored.
+    pub fn set_capability(&mut self, peer_id: PeerId, capability: NodeCapability, issued_at: SystemTime) -> bool {
+        if self.capabilities.get(&peer_id).is_some_and(|r| r.issued_at >= issued_at) {
+            return false;
+        }
+        self.capabilities.insert(peer_id, CapabilityRecord { capability, issued_at, received: SystemTime::now() });
+        true
+    }
+
+    /// Capability records received within `max_age`.
+    pub fn fresh_capabilities(&self, max_age: Duration) -> impl Iterator<Item = (&PeerId, &CapabilityRecord)> {
+        self.capabilities.iter().filter(move |(_, r)| r.received.elapsed().map(|age| age <= max_age).unwrap_or(true))
+    }
+
+    pub fn remove_topic(&mut self, peer_id: &PeerId, topic: &str) {
+        if let Some(entry) = self.peers.get_mut(peer_id) {
+            entry.topics.retain(|t| t != topic);
+        }
+    }
+
+    /// Peers that have joined the hive with this gossipsub topic.
+    pub fn peers_in_hive(&self, topic: &str) -> Vec<PeerId> {
+        let mut peers: Vec<PeerId> = self.peers.values()
+            .filter(|p| p.topics.iter().any(|t| t == topic))
+            .map(|p| p.id)
+            .collect();
+        peers.sort();
+        peers
+    }
+
     pub fn remove_peer(&mut self, peer_id: &PeerId) {
         self.peers.remove(peer_id);
     }
 
+    pub fn set_latency(&mut self, peer_id: &PeerId, rtt: Duration) {
+        if let Some(entry) = self.peers.get_mut(peer_id) {
+            entry.latency = Some(rtt);
+        }
+    }
+
+    pub fn set_status(&mut self, peer_id: &PeerId, status: &str) {
+        if let Some(entry) = self.peers.get_mut(peer_id) {
+            entry.status = status.to_string();
+        }
+    }
+
+    pub fn set_gossip_score(&mut self, peer_id: &PeerId, score: f64) {
+        if let Some(entry) = self.peers.get_mut(peer_id) {
+            entry.gossip_score = Some(score);
+        }
+    }
+
+    pub fn set_identity(&mut self, peer_id: &PeerId, agent_version: String, protocols: Vec<String>, observed_addr: Multiaddr) {
+        if let Some(entry) = self.peers.get_mut(peer_id) {
+            entry.agent_version = Some(agent_version);
+            entry.protocols = protocols;
+            entry.observed_addr = Some(observed_addr);
+        }
+    }
+
+    pub fn set_wire_versions(&mut self, peer_id: &PeerId, versions: Option<(u16, u16)>) {
+        if let Some(entry) = self.peers.get_mut(peer_id) {
+            entry.wire_versions = versions;
+        }
+    }
+
+    /// The closest peer by measured round trip; peers not pinged yet come last.
     pub fn get_available_peer(&self) -> Option<PeerId> {
-        self.peers.keys().next().cloned()
+        self.peers.values()
+            .filter(|p| p.accepts_work())
+            .min_by_key(|p| (p.latency.is_none(), p.latency, p.id))
+            .map(|p| p.id)
+    }
+
+    /// Picks up to `count` trusted peers, skipping `exclude`.
+    pub fn get_trusted_peers(&self, count: usize, exclude: &[PeerId]) -> Vec<PeerId> {
+        let mut candidates: Vec<PeerId> = self.peers.values()
+            .filter(|p| self.is_trusted(&p.id) && p.accepts_work() && !exclude.contains(&p.id))
+            .map(|p| p.id)
+            .collect();
+        candidates.sort();
+        candidates.truncate(count);
+        candidates
+    }
+
+    pub fn flags(&self, peer_id: &PeerId) -> u32 {
+        self.flags.get(peer_id).copied().unwrap_or(0)
+    }
+
+    /// Whether the peer may run verified work: it has fewer than `FLAG_LIMIT` flags.
+    pub fn is_trusted(&self, peer_id: &PeerId) -> bool {
+        self.flags(peer_id) < FLAG_LIMIT
+    }
+
+    pub fn flag_peer(&mut self, peer_id: &PeerId) {
+        *self.flags.entry(*peer_id).or_default() += 1;
+    }
+
+    /// Takes one flag off a peer that agreed with the majority, so that
+    /// flags decay for peers that mostly answer right.
+    pub fn record_agreement(&mut self, peer_id: &PeerId) {
+        if let Some(flags) = self.flags.get_mut(peer_id) {
+            *flags -= 1;
+            if *flags == 0 {
+                self.flags.remove(peer_id);
+            }
+        }
+    }
+
+    /// Forgets a peer's flags. Returns how many it had.
+    pub fn clear_flags(&mut self, peer_id: &PeerId) -> u32 {
+        self.flags.remove(peer_id).unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn older_capability_records_are_dropped() {
+        let mut scheduler = Scheduler::new();
+        let peer = PeerId::random();
+        let now = SystemTime::now();
+        let newer = NodeCapability { cpu_cores: 8, ..Default::default() };
+        let older = NodeCapability { cpu_cores: 64, ..Default::default() };
+
+        assert!(scheduler.set_capability(peer, newer, now));
+        // A replayed record from before the stored one
+        assert!(!scheduler.set_capability(peer, older, now - Duration::from_secs(60)));
+        assert_eq!(scheduler.capabilities[&peer].capability.cpu_cores, 8);
+        assert!(scheduler.set_capability(peer, NodeCapability::default(), now + Duration::from_secs(60)));
+        assert_eq!(scheduler.capabilities[&peer].capability.cpu_cores, 0);
+    }
+
+    #[test]
+    fn replayed_capability_records_do_not_stay_fresh() {
+        let mut scheduler = Scheduler::new();
+        let peer = PeerId::random();
+        let issued_at = SystemTime::now();
+        let max_age = Duration::from_millis(10);
+
+        assert!(scheduler.set_capability(peer, NodeCapability::default(), issued_at));
+        std::thread::sleep(max_age * 2);
+        // The same signed record, relayed again after it went stale
+        assert!(!scheduler.set_capability(peer, NodeCapability::default(), issued_at));
+        assert_eq!(scheduler.fresh_capabilities(max_age).count(), 0);
+    }
+
+    #[test]
+    fn flags_exclude_peers_only_at_the_limit_and_decay() {
+        let mut scheduler = Scheduler::new();
+        let peer = PeerId::random();
+        scheduler.add_peer(peer, "/ip4/127.0.0.1/tcp/1".parse().unwrap());
+
+        for _ in 1..FLAG_LIMIT {
+            scheduler.flag_peer(&peer);
+        }
+        assert_eq!(scheduler.get_trusted_peers(1, &[]), vec![peer]);
+        scheduler.flag_peer(&peer);
+        assert!(scheduler.get_trusted_peers(1, &[]).is_empty());
+
+        scheduler.record_agreement(&peer);
+        assert_eq!(scheduler.get_trusted_peers(1, &[]), vec![peer]);
+        assert_eq!(scheduler.clear_flags(&peer), FLAG_LIMIT - 1);
+        assert_eq!(scheduler.flags(&peer), 0);
+        scheduler.record_agreement(&peer);
+        assert_eq!(scheduler.flags(&peer), 0);
     }
 }
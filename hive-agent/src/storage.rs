@@ -1,41 +1,930 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use futures::StreamExt;
+use hive_core::{Chunk, Cid, Manifest, MERKLE_SHA2_256};
 use sha2::{Digest, Sha256};
-use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 use tokio::fs;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tracing::info;
+use crate::blobstore::{self, BlobMeta, BlobStore, FsStore};
+use crate::encryption::{Cipher, KeySource, Keyring, PASSPHRASE_ENV};
 
+pub use hive_core::CHUNK_SIZE;
+
+/// Orphaned chunks younger than this may belong to a fetch in progress, so
+/// garbage collection leaves them alone.
+const ORPHAN_GRACE: Duration = Duration::from_secs(60 * 60);
+
+const PINS: &str = "pins.json";
+const REPLICATION: &str = "replication.json";
+
+/// Stored content being read, one chunk in memory at a time.
+pub type ContentReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Content-addressed store on top of a `BlobStore`. Content is kept as
+/// deduplicated chunks under `chunks/` plus a manifest per CID under
+/// `manifests/`, each sharded by the first byte of the digest; pin counts live
+/// in `pins.json` and replication factors in `replication.json`. An encrypted
+/// store seals chunks and whole blobs with the data keys in `keyring.json`;
+/// manifests and the other metadata stay readable. Cheap to clone.
+#[derive(Clone)]
 pub struct Storage {
-    root_dir: PathBuf,
+    store: Arc<dyn BlobStore>,
+    quota: Option<u64>, // Bytes to hold before unpinned content is collected
+    lock: Arc<tokio::sync::Mutex<()>>, // Serialises pin updates and garbage collection
+    cipher: Option<Arc<Cipher>>, // Set for encrypted stores
+}
+
+/// One piece of stored content, as listed by `storage ls`.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub cid: Cid,
+    pub size: u64,
+    pub chunks: Vec<Cid>, // Empty for whole blobs stored before chunking
+    pub pins: u64,
+    pub replication: u32, // Replication factor, 0 if none
+    pub last_used: SystemTime,
+}
+
+impl Entry {
+    /// Pinned and replicated content is never collected.
+    pub fn held(&self) -> bool {
+        self.pins > 0 || self.replication > 0
+    }
+}
+
+/// Bytes on disk by the reason they are kept. Replicated content counts as
+/// pinned, and so does a chunk shared by pinned and unpinned content.
+#[derive(Debug, Default, Clone)]
+pub struct Usage {
+    pub pinned: u64,
+    pub cached: u64,  // Unpinned content, evicted first when over quota
+    pub orphaned: u64, // Chunks no manifest refers to, e.g. from an interrupted fetch
+    pub quota: Option<u64>,
+}
+
+impl Usage {
+    pub fn total(&self) -> u64 {
+        self.pinned + self.cached + self.orphaned
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct GcReport {
+    pub removed: Vec<Cid>,
+    pub orphans: usize,
+    pub freed: u64,
+    pub remaining: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub checked: usize,
+    pub corrupt: Vec<Cid>, // Chunks, or whole blobs, whose data no longer matches
+    pub damaged: Vec<Cid>, // Content that cannot be read back in full
+}
+
+/// Everything stored, read in one pass for `ls`, `du` and GC.
+struct Inventory {
+    entries: Vec<Entry>,
+    chunks: HashMap<Cid, (u64, SystemTime)>, // Size and modification time of each chunk blob
+}
+
+/// The chunk, manifest and whole blob keys in the store, from one listing.
+#[derive(Default)]
+struct Listing {
+    chunks: Vec<(Cid, BlobMeta)>,
+    manifests: Vec<(Cid, BlobMeta)>,
+    legacy: Vec<(Cid, BlobMeta)>,
+}
+
+/// Where `cid` lives under a sharded prefix: `<dir>/<first digest byte>/<cid>`.
+pub fn shard_key(dir: &str, cid: &Cid) -> String {
+    format!("{}/{}/{}", dir, &cid.digest_hex()[..2], cid)
 }
 
 impl Storage {
+    /// Opens a plaintext store in the directory `root_dir`.
     pub async fn new(root_dir: impl AsRef<Path>) -> Result<Self> {
-        let root_dir = root_dir.as_ref().to_path_buf();
-        fs::create_dir_all(&root_dir).await?;
-        Ok(Self { root_dir })
+        Self::open(Arc::new(FsStore::open(root_dir).await?), None).await
+    }
+
+    /// Opens the content in `store`, unlocking it with `key` if it is
+    /// encrypted. A key given for an empty plaintext store encrypts it from
+    /// the start; one that already holds content needs `encrypt` first.
+    pub async fn open(store: Arc<dyn BlobStore>, key: Option<&KeySource>) -> Result<Self> {
+        let mut storage = Self { store, quota: None, lock: Arc::default(), cipher: None };
+        let store = &storage.store;
+        let keyring = match key {
+            Some(key) => Keyring::open(store, key).await?,
+            None if Keyring::exists(store.as_ref()).await? => {
+                return Err(anyhow!("{} is encrypted; set {} or configure a storage key file", store.describe(), PASSPHRASE_ENV));
+            }
+            None => return Ok(storage),
+        };
+        let keyring = match keyring {
+            Some(keyring) if keyring.migrating() => {
+                return Err(anyhow!("Encrypting {} was interrupted; run `storage encrypt` again", store.describe()));
+            }
+            Some(keyring) => keyring,
+            None if storage.blob_keys().await?.is_empty() => Keyring::create(store, key.unwrap(), false).await?,
+            None => return Err(anyhow!("{} holds unencrypted content; run `storage encrypt` to encrypt it", store.describe())),
+        };
+        storage.cipher = Some(Arc::new(keyring.cipher()?));
+        Ok(storage)
+    }
+
+    /// Encrypts a plaintext store in place and returns the number of blobs
+    /// sealed. Safe to run again after an interruption: blobs that are
+    /// already sealed are skipped. Run it with the agent stopped.
+    pub async fn encrypt(store: Arc<dyn BlobStore>, key: &KeySource) -> Result<usize> {
+        let mut keyring = match Keyring::open(&store, key).await? {
+            Some(keyring) if !keyring.migrating() => return Err(anyhow!("{} is already encrypted", store.describe())),
+            Some(keyring) => keyring,
+            None => Keyring::create(&store, key, true).await?,
+        };
+        let cipher = Arc::new(keyring.cipher()?);
+        let storage = Self { store: store.clone(), quota: None, lock: Arc::default(), cipher: None };
+        let mut sealed = 0;
+        for key in storage.blob_keys().await? {
+            if !cipher.is_sealed(store.as_ref(), &key).await? {
+                let Some(reader) = blobstore::range_reader(store.clone(), &key, 0, u64::MAX).await? else {
+                    continue;
+                };
+                cipher.seal_reader(reader, store.as_ref(), &key).await?;
+                sealed += 1;
+            }
+        }
+        keyring.set_migrating(false).await?;
+        info!("Encrypted {} blob(s) in {}", sealed, store.describe());
+        Ok(sealed)
+    }
+
+    /// Re-seals every blob with a fresh data key and drops the old ones; with
+    /// `new_key`, the keyring also moves to a new passphrase or key file.
+    /// Safe to run again after an interruption. Run it with the agent stopped.
+    pub async fn rotate_key(store: Arc<dyn BlobStore>, key: &KeySource, new_key: Option<&KeySource>) -> Result<usize> {
+        let mut keyring = Keyring::open(&store, key)
+            .await?
+            .ok_or_else(|| anyhow!("{} is not encrypted; run `storage encrypt` first", store.describe()))?;
+        if keyring.migrating() {
+            return Err(anyhow!("Encrypting {} was interrupted; run `storage encrypt` again", store.describe()));
+        }
+        keyring.add_key().await?;
+        let cipher = Arc::new(keyring.cipher()?);
+        let storage = Self { store: store.clone(), quota: None, lock: Arc::default(), cipher: None };
+        let mut resealed = 0;
+        for key in storage.blob_keys().await? {
+            let header = store.get_range(&key, 0, 64).await?.unwrap_or_default();
+            if Cipher::key_id(&header) != Some(cipher.current()) {
+                let reader = cipher.range_reader(store.clone(), &key, 0, u64::MAX).await?;
+                cipher.seal_reader(reader, store.as_ref(), &key).await?;
+                resealed += 1;
+            }
+        }
+        keyring.retire_old_keys().await?;
+        if let Some(new_key) = new_key {
+            keyring.change_secret(new_key).await?;
+        }
+        info!("Re-sealed {} blob(s) in {} with a new data key", resealed, store.describe());
+        Ok(resealed)
+    }
+
+    pub fn encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    /// Sorts every chunk, manifest and whole blob in the store by kind.
+    /// Temporary blobs and anything else that is not named by a CID are skipped.
+    async fn listing(&self) -> Result<Listing> {
+        let mut listing = Listing::default();
+        for (key, meta) in self.store.list("").await? {
+            let mut parts = key.split('/');
+            let kind = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                (Some("chunks"), Some(shard), Some(name), None) if shard.len() == 2 => Some((&mut listing.chunks, name)),
+                (Some("manifests"), Some(shard), Some(name), None) if shard.len() == 2 => Some((&mut listing.manifests, name)),
+                (Some(name), None, None, None) => Some((&mut listing.legacy, name)),
+                _ => None,
+            };
+            if let Some((list, name)) = kind {
+                if let Ok(cid) = name.parse::<Cid>() {
+                    list.push((cid, meta));
+                }
+            }
+        }
+        Ok(listing)
+    }
+
+    /// Every chunk and whole blob, the blobs encryption applies to.
+    async fn blob_keys(&self) -> Result<Vec<String>> {
+        let listing = self.listing().await?;
+        let chunks = listing.chunks.iter().map(|(hash, _)| self.chunk_key(hash));
+        Ok(chunks.chain(listing.legacy.iter().map(|(cid, _)| self.legacy_key(cid))).collect())
+    }
+
+    /// Content bytes in a chunk or whole blob of `blob_len` bytes.
+    fn content_len(&self, blob_len: u64) -> u64 {
+        if self.cipher.is_some() {
+            Cipher::plaintext_len(blob_len)
+        } else {
+            blob_len
+        }
+    }
+
+    /// Decrypts a chunk or whole blob as read, if the store is encrypted.
+    fn open_blob(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        match &self.cipher {
+            Some(cipher) => cipher.open(&data),
+            None => Ok(data),
+        }
+    }
+
+    /// Collects unpinned content whenever more than `quota` bytes are stored.
+    pub fn with_quota(mut self, quota: Option<u64>) -> Self {
+        self.quota = quota;
+        self
+    }
+
+    pub fn quota(&self) -> Option<u64> {
+        self.quota
+    }
+
+    fn chunk_key(&self, hash: &Cid) -> String {
+        shard_key("chunks", hash)
+    }
+
+    fn manifest_key(&self, cid: &Cid) -> String {
+        shard_key("manifests", cid)
+    }
+
+    /// Whole blobs written before content was chunked, kept readable. They
+    /// are named by the bare SHA-256 digest.
+    fn legacy_key(&self, cid: &Cid) -> String {
+        cid.digest_hex()
+    }
+
+    pub async fn store(&self, data: &[u8]) -> Result<Cid> {
+        self.store_reader(data).await
+    }
+
+    pub async fn store_file(&self, path: impl AsRef<Path>) -> Result<Cid> {
+        self.store_reader(fs::File::open(path).await?).await
+    }
+
+    /// Stores everything `reader` yields, one chunk in memory at a time, and
+    /// returns its CID.
+    pub async fn store_reader(&self, mut reader: impl AsyncRead + Unpin) -> Result<Cid> {
+        let mut manifest = Manifest { size: 0, chunk_size: CHUNK_SIZE, chunks: Vec::new() };
+        loop {
+            let mut data = Vec::with_capacity(CHUNK_SIZE as usize);
+            (&mut reader).take(CHUNK_SIZE).read_to_end(&mut data).await?;
+            let len = data.len() as u64;
+            // Empty content is a single empty chunk, so that every manifest has one
+            if len == 0 && !manifest.chunks.is_empty() {
+                break;
+            }
+            let chunk = Chunk::new(data);
+            self.put_chunk(&chunk).await?;
+            manifest.chunks.push(chunk.hash);
+            manifest.size += len;
+            if len < CHUNK_SIZE {
+                break;
+            }
+        }
+        self.put_manifest(&manifest).await
+    }
+
+    /// Makes stored blobs survive a crash after shutdown.
+    pub async fn flush(&self) -> Result<()> {
+        self.store.flush().await
+    }
+
+    pub async fn retrieve(&self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        self.read_range(cid, 0, u64::MAX).await
+    }
+
+    /// Streams stored content; see `range_reader`.
+    pub async fn reader(&self, cid: &Cid) -> Result<Option<ContentReader>> {
+        self.range_reader(cid, 0, u64::MAX).await
+    }
+
+    /// Streams up to `length` bytes of stored content starting at `offset`,
+    /// loading one chunk at a time. A chunk that goes missing mid-stream or no
+    /// longer matches its hash is reported as a read error.
+    pub async fn range_reader(&self, cid: &Cid, offset: u64, length: u64) -> Result<Option<ContentReader>> {
+        let Some(manifest) = self.manifest(cid).await? else {
+            let legacy = self.legacy_reader(cid, offset, length).await?;
+            if legacy.is_some() {
+                self.store.touch(&self.legacy_key(cid)).await;
+            }
+            return Ok(legacy);
+        };
+        self.store.touch(&self.manifest_key(cid)).await;
+        let end = manifest.size.min(offset.saturating_add(length));
+        let mut pieces = Vec::new();
+        let mut chunk_start = 0;
+        for hash in manifest.chunks {
+            let chunk_end = (chunk_start + manifest.chunk_size).min(manifest.size);
+            if chunk_end > offset && chunk_start < end {
+                pieces.push((hash, offset.saturating_sub(chunk_start) as usize, (end - chunk_start) as usize));
+            }
+            chunk_start = chunk_end;
+        }
+        let storage = self.clone();
+        let stream = futures::stream::iter(pieces).then(move |(hash, from, to)| {
+            let storage = storage.clone();
+            async move {
+                let chunk = storage.chunk(&hash).await.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+                let chunk = chunk.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("Chunk {} is missing", hash)))?;
+                let to = to.min(chunk.len());
+                Ok::<_, std::io::Error>(bytes::Bytes::from(chunk).slice(from..to))
+            }
+        });
+        Ok(Some(Box::new(tokio_util::io::StreamReader::new(Box::pin(stream)))))
+    }
+
+    /// Streams part of a whole blob without marking it as used.
+    async fn legacy_reader(&self, cid: &Cid, offset: u64, length: u64) -> Result<Option<ContentReader>> {
+        let key = self.legacy_key(cid);
+        match &self.cipher {
+            Some(cipher) => match cipher.range_reader(self.store.clone(), &key, offset, length).await {
+                Ok(reader) => Ok(Some(reader)),
+                Err(_) if self.store.stat(&key).await?.is_none() => Ok(None),
+                Err(e) => Err(e),
+            },
+            None => blobstore::range_reader(self.store.clone(), &key, offset, length).await,
+        }
+    }
+
+    /// Size in bytes of stored content.
+    pub async fn size(&self, cid: &Cid) -> Result<Option<u64>> {
+        if let Some(manifest) = self.manifest(cid).await? {
+            return Ok(Some(manifest.size));
+        }
+        Ok(self.store.stat(&self.legacy_key(cid)).await?.map(|meta| self.content_len(meta.len)))
+    }
+
+    /// Up to `length` bytes of stored content starting at `offset`, reading
+    /// only the chunks that cover the range. `None` if the content or one of
+    /// its chunks is missing.
+    pub async fn read_range(&self, cid: &Cid, offset: u64, length: u64) -> Result<Option<Vec<u8>>> {
+        let Some(mut reader) = self.range_reader(cid, offset, length).await? else {
+            return Ok(None);
+        };
+        let mut data = Vec::new();
+        match reader.read_to_end(&mut data).await {
+            Ok(_) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Manifests stored before Merkle roots had their own multihash code are
+    /// found under the plain SHA2-256 form of the root.
+    pub async fn manifest(&self, cid: &Cid) -> Result<Option<Manifest>> {
+        let mut data = self.store.get(&self.manifest_key(cid)).await?;
+        if data.is_none() && cid.code() == MERKLE_SHA2_256 {
+            data = self.store.get(&self.manifest_key(&cid.as_sha256())).await?;
+        }
+        match data {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
+        }
     }
 
-    pub async fn store(&self, data: &[u8]) -> Result<String> {
-        let mut hasher = Sha256::new();
-        hasher.update(data);
-        let hash = hex::encode(hasher.finalize());
+    /// Records a manifest once all of its chunks are stored, making the
+    /// content retrievable under the returned CID.
+    pub async fn put_manifest(&self, manifest: &Manifest) -> Result<Cid> {
+        // The CID covers the chunk hashes only, so the sizes are checked here
+        manifest.validate()?;
+        let mut size = 0;
+        for (i, hash) in manifest.chunks.iter().enumerate() {
+            let len = match self.store.stat(&self.chunk_key(hash)).await? {
+                Some(meta) => self.content_len(meta.len),
+                None => return Err(anyhow!("Chunk {} of the manifest is missing", hash)),
+            };
+            let last = i + 1 == manifest.chunks.len();
+            if len > manifest.chunk_size || (!last && len != manifest.chunk_size) {
+                return Err(anyhow!("Chunk {} does not fit a chunk size of {}", hash, manifest.chunk_size));
+            }
+            size += len;
+        }
+        if size != manifest.size {
+            return Err(anyhow!("Manifest claims {} bytes but its chunks hold {}", manifest.size, size));
+        }
+        let cid = manifest.cid();
+        let key = self.manifest_key(&cid);
+        if self.store.stat(&key).await?.is_some() {
+            self.store.touch(&key).await;
+        } else {
+            self.store.put(&key, serde_json::to_vec(manifest)?).await?;
+        }
+        if let Some(quota) = self.quota {
+            let report = self.collect_garbage(quota, Some(&cid)).await?;
+            if report.remaining > quota {
+                info!("Storage holds {} bytes, over its quota of {}, and nothing more can be evicted", report.remaining, quota);
+            }
+        }
+        Ok(cid)
+    }
+
+    pub async fn has_chunk(&self, hash: &Cid) -> Result<bool> {
+        Ok(self.store.stat(&self.chunk_key(hash)).await?.is_some())
+    }
+
+    /// Reads a chunk and checks it against its hash, so corruption in the
+    /// backend is an error rather than wrong data.
+    pub async fn chunk(&self, hash: &Cid) -> Result<Option<Vec<u8>>> {
+        let Some(data) = self.store.get(&self.chunk_key(hash)).await? else {
+            return Ok(None);
+        };
+        match self.open_blob(data).map(|data| Chunk { data, hash: hash.clone() }) {
+            Ok(chunk) if chunk.verify() => Ok(Some(chunk.data)),
+            _ => Err(anyhow!("Chunk {} is corrupt; run `storage verify --repair`", hash)),
+        }
+    }
 
-        let path = self.root_dir.join(&hash);
-        if !path.exists() {
-            let mut file = fs::File::create(&path).await?;
-            file.write_all(data).await?;
+    /// Stores a chunk unless an identical one is already there.
+    pub async fn put_chunk(&self, chunk: &Chunk) -> Result<()> {
+        if !chunk.verify() {
+            return Err(anyhow!("Chunk data does not match hash {}", chunk.hash));
         }
+        let key = self.chunk_key(&chunk.hash);
+        if !self.has_chunk(&chunk.hash).await? {
+            match &self.cipher {
+                Some(cipher) => self.store.put(&key, cipher.seal(&chunk.data)?).await?,
+                None => self.store.put(&key, chunk.data.clone()).await?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Pin counts by CID. Pinned content is never collected.
+    pub async fn pins(&self) -> Result<BTreeMap<Cid, u64>> {
+        match self.store.get(PINS).await? {
+            Some(data) => Ok(serde_json::from_slice(&data)?),
+            None => Ok(BTreeMap::new()),
+        }
+    }
+
+    /// Adds a pin to stored content and returns its new pin count. Every pin
+    /// needs its own unpin before the content can be collected.
+    pub async fn pin(&self, cid: &Cid) -> Result<u64> {
+        let _guard = self.lock.lock().await;
+        if self.size(cid).await?.is_none() {
+            return Err(anyhow!("{} is not stored", cid));
+        }
+        let mut pins = self.pins().await?;
+        let count = pins.entry(cid.clone()).or_insert(0);
+        *count += 1;
+        let count = *count;
+        self.store.put(PINS, serde_json::to_vec_pretty(&pins)?).await?;
+        Ok(count)
+    }
+
+    /// Removes one pin and returns the pins left.
+    pub async fn unpin(&self, cid: &Cid) -> Result<u64> {
+        let _guard = self.lock.lock().await;
+        let mut pins = self.pins().await?;
+        let Some(count) = pins.get_mut(cid) else {
+            return Err(anyhow!("{} is not pinned", cid));
+        };
+        *count -= 1;
+        let count = *count;
+        if count == 0 {
+            pins.remove(cid);
+        }
+        self.store.put(PINS, serde_json::to_vec_pretty(&pins)?).await?;
+        Ok(count)
+    }
 
-        Ok(hash)
+    /// Replication factors by CID: how many nodes, this one included, should
+    /// pin a copy.
+    pub async fn replication(&self) -> Result<BTreeMap<Cid, u32>> {
+        match self.store.get(REPLICATION).await? {
+            Some(data) => Ok(serde_json::from_slice(&data)?),
+            None => Ok(BTreeMap::new()),
+        }
     }
 
-    pub async fn retrieve(&self, hash: &str) -> Result<Option<Vec<u8>>> {
-        let path = self.root_dir.join(hash);
-        if path.exists() {
-            let data = fs::read(path).await?;
-            Ok(Some(data))
+    /// Sets the replication factor of stored content and returns the previous
+    /// one; 0 clears it. Content with a factor is held like pinned content,
+    /// apart from the pin count, so unpinning it leaves the replica in place.
+    pub async fn set_replication(&self, cid: &Cid, factor: u32) -> Result<Option<u32>> {
+        let _guard = self.lock.lock().await;
+        let mut factors = self.replication().await?;
+        let previous = factors.get(cid).copied();
+        if previous.is_none() && factor == 0 {
+            return Ok(None);
+        }
+        if self.size(cid).await?.is_none() {
+            return Err(anyhow!("{} is not stored", cid));
+        }
+        if factor == 0 {
+            factors.remove(cid);
         } else {
-            Ok(None)
+            factors.insert(cid.clone(), factor);
+        }
+        self.store.put(REPLICATION, serde_json::to_vec_pretty(&factors)?).await?;
+        Ok(previous)
+    }
+
+    async fn inventory(&self, pins: &BTreeMap<Cid, u64>, factors: &BTreeMap<Cid, u32>) -> Result<Inventory> {
+        let listing = self.listing().await?;
+        let chunks = listing.chunks.into_iter().map(|(hash, meta)| (hash, (meta.len, meta.modified))).collect();
+
+        let mut entries = Vec::new();
+        for (cid, meta) in listing.manifests {
+            let Some(manifest) = self.manifest(&cid).await? else {
+                continue;
+            };
+            let mut hashes = manifest.chunks;
+            hashes.sort();
+            hashes.dedup();
+            entries.push(Entry {
+                pins: pins.get(&cid).copied().unwrap_or(0),
+                replication: factors.get(&cid).copied().unwrap_or(0),
+                last_used: meta.modified,
+                size: manifest.size,
+                chunks: hashes,
+                cid,
+            });
+        }
+        for (cid, meta) in listing.legacy {
+            entries.push(Entry {
+                pins: pins.get(&cid).copied().unwrap_or(0),
+                replication: factors.get(&cid).copied().unwrap_or(0),
+                last_used: meta.modified,
+                size: self.content_len(meta.len),
+                chunks: Vec::new(),
+                cid,
+            });
+        }
+        entries.sort_by(|a, b| a.cid.cmp(&b.cid));
+        Ok(Inventory { entries, chunks })
+    }
+
+    /// Stored content, with pin counts, replication factors and when it was
+    /// last read or stored.
+    pub async fn list(&self) -> Result<Vec<Entry>> {
+        Ok(self.inventory(&self.pins().await?, &self.replication().await?).await?.entries)
+    }
+
+    pub async fn usage(&self) -> Result<Usage> {
+        let inventory = self.inventory(&self.pins().await?, &self.replication().await?).await?;
+        let mut usage = Usage { quota: self.quota, ..Usage::default() };
+        let mut kept: HashMap<&Cid, bool> = HashMap::new(); // Chunk hash to whether it is pinned
+        for entry in &inventory.entries {
+            if entry.chunks.is_empty() {
+                *if entry.held() { &mut usage.pinned } else { &mut usage.cached } += entry.size;
+            }
+            for hash in &entry.chunks {
+                *kept.entry(hash).or_default() |= entry.held();
+            }
+        }
+        for (hash, (len, _)) in &inventory.chunks {
+            match kept.get(hash) {
+                Some(true) => usage.pinned += len,
+                Some(false) => usage.cached += len,
+                None => usage.orphaned += len,
+            }
+        }
+        Ok(usage)
+    }
+
+    /// Evicts content that is neither pinned nor replicated, least recently
+    /// used first, until at most `quota` bytes remain. `keep` is spared, being
+    /// the content just stored.
+    pub async fn collect_garbage(&self, quota: u64, keep: Option<&Cid>) -> Result<GcReport> {
+        let _guard = self.lock.lock().await;
+        let inventory = self.inventory(&self.pins().await?, &self.replication().await?).await?;
+        let mut used: u64 = inventory.chunks.values().map(|(len, _)| len).sum::<u64>()
+            + inventory.entries.iter().filter(|e| e.chunks.is_empty()).map(|e| e.size).sum::<u64>();
+        let mut report = GcReport { remaining: used, ..GcReport::default() };
+        if used <= quota {
+            return Ok(report);
+        }
+
+        let mut refs: HashMap<&Cid, usize> = HashMap::new();
+        for hash in inventory.entries.iter().flat_map(|e| &e.chunks) {
+            *refs.entry(hash).or_default() += 1;
+        }
+        // Unpinned content and stale orphans, oldest first; `None` marks an orphan
+        let now = SystemTime::now();
+        let mut victims: Vec<(SystemTime, Option<&Entry>, &Cid)> = inventory
+            .entries
+            .iter()
+            .filter(|e| !e.held() && keep != Some(&e.cid))
+            .map(|e| (e.last_used, Some(e), &e.cid))
+            .collect();
+        for (hash, (_, modified)) in &inventory.chunks {
+            let stale = now.duration_since(*modified).is_ok_and(|age| age >= ORPHAN_GRACE);
+            if stale && !refs.contains_key(hash) {
+                victims.push((*modified, None, hash));
+            }
+        }
+        victims.sort_by_key(|(time, _, _)| *time);
+
+        for (_, entry, name) in victims {
+            if used <= quota {
+                break;
+            }
+            match entry {
+                None => {
+                    self.store.delete(&self.chunk_key(name)).await?;
+                    used -= inventory.chunks[name].0;
+                    report.orphans += 1;
+                }
+                Some(entry) if entry.chunks.is_empty() => {
+                    self.store.delete(&self.legacy_key(name)).await?;
+                    used -= entry.size;
+                    report.removed.push(entry.cid.clone());
+                }
+                Some(entry) => {
+                    // The manifest goes first, so a crash never leaves content with missing chunks
+                    self.store.delete(&self.manifest_key(name)).await?;
+                    for hash in &entry.chunks {
+                        let count = refs.get_mut(hash).expect("counted above");
+                        *count -= 1;
+                        if *count == 0 {
+                            if let Some((len, _)) = inventory.chunks.get(hash) {
+                                self.store.delete(&self.chunk_key(hash)).await?;
+                                used -= len;
+                            }
+                        }
+                    }
+                    report.removed.push(entry.cid.clone());
+                }
+            }
         }
+        report.freed = report.remaining - used;
+        report.remaining = used;
+        if report.freed > 0 {
+            info!("Collected {} item(s) and {} orphaned chunk(s), freeing {} bytes", report.removed.len(), report.orphans, report.freed);
+        }
+        Ok(report)
+    }
+
+    /// Re-hashes every chunk and whole blob and checks that every manifest
+    /// can be read back. With `repair`, corrupt data and the manifests that
+    /// depend on it are deleted so that the content can be fetched again.
+    pub async fn verify(&self, repair: bool) -> Result<VerifyReport> {
+        let inventory = self.inventory(&BTreeMap::new(), &BTreeMap::new()).await?;
+        let mut report = VerifyReport::default();
+        let mut bad = std::collections::HashSet::new();
+        for hash in inventory.chunks.keys() {
+            report.checked += 1;
+            let data = self.store.get(&self.chunk_key(hash)).await?;
+            let intact = data.is_some_and(|data| self.open_blob(data).is_ok_and(|data| Chunk { data, hash: hash.clone() }.verify()));
+            if !intact {
+                bad.insert(hash.clone());
+                report.corrupt.push(hash.clone());
+            }
+        }
+        for entry in &inventory.entries {
+            let damaged = if entry.chunks.is_empty() {
+                report.checked += 1;
+                // A blob that fails to decrypt is as corrupt as one that hashes wrong,
+                // whether it fails on its header or on its data
+                let intact = match self.legacy_reader(&entry.cid, 0, u64::MAX).await {
+                    Ok(Some(reader)) => hash_reader(reader).await.is_ok_and(|digest| digest == entry.cid.digest_hex()),
+                    Ok(None) | Err(_) => false,
+                };
+                if !intact {
+                    report.corrupt.push(entry.cid.clone());
+                    if repair {
+                        self.store.delete(&self.legacy_key(&entry.cid)).await?;
+                    }
+                }
+                !intact
+            } else {
+                let damaged = entry.chunks.iter().any(|hash| bad.contains(hash) || !inventory.chunks.contains_key(hash));
+                if damaged && repair {
+                    self.store.delete(&self.manifest_key(&entry.cid)).await?;
+                }
+                damaged
+            };
+            if damaged {
+                report.damaged.push(entry.cid.clone());
+            }
+        }
+        if repair {
+            for hash in &bad {
+                self.store.delete(&self.chunk_key(hash)).await?;
+            }
+        }
+        report.corrupt.sort();
+        Ok(report)
+    }
+}
+
+/// The CID `Storage::store_reader` gives everything `reader` yields,
+/// computed without storing anything.
+pub async fn content_cid(mut reader: impl AsyncRead + Unpin) -> Result<Cid> {
+    let mut chunks = Vec::new();
+    loop {
+        let mut data = Vec::with_capacity(CHUNK_SIZE as usize);
+        (&mut reader).take(CHUNK_SIZE).read_to_end(&mut data).await?;
+        let len = data.len() as u64;
+        if len == 0 && !chunks.is_empty() {
+            break;
+        }
+        chunks.push(Cid::sha256(&data));
+        if len < CHUNK_SIZE {
+            break;
+        }
+    }
+    Ok(hive_core::merkle_root(&chunks))
+}
+
+/// Hex SHA-256 of everything `reader` yields, read a chunk at a time.
+async fn hash_reader(mut reader: impl AsyncRead + Unpin) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; CHUNK_SIZE as usize];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(hex::encode(hasher.finalize()));
+        }
+        hasher.update(&buf[..n]);
+    }
+}
+
+/// Writes to a temporary file next to `path` and renames it into place, so a
+/// crash never leaves a truncated chunk or manifest under its final name.
+/// Creates the parent directory if needed.
+pub async fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    let tmp = path.with_extension(format!("tmp-{}", uuid::Uuid::new_v4()));
+    let mut file = fs::File::create(&tmp).await?;
+    file.write_all(data).await?;
+    file.flush().await?;
+    drop(file);
+    fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blobstore::MemoryStore;
+
+    async fn fs_store(root: &Path) -> Result<Arc<dyn BlobStore>> {
+        Ok(Arc::new(FsStore::open(root).await?))
+    }
+
+    #[tokio::test]
+    async fn chunked_content_round_trips_and_dedups() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        for store in [fs_store(dir.path()).await?, Arc::new(MemoryStore::default())] {
+            let storage = Storage::open(store, None).await?;
+
+            // Even one-chunk content is addressed by its root, never by the chunk hash
+            let small = b"hello hive".to_vec();
+            assert_eq!(storage.store(&small).await?, hive_core::merkle_root(&[Cid::sha256(&small)]));
+
+            let large: Vec<u8> = (0..(2 * CHUNK_SIZE + 10) as usize).map(|i| (i % 251) as u8).collect();
+            let cid = storage.store_reader(&large[..]).await?;
+            assert_eq!(storage.retrieve(&cid).await?, Some(large.clone()));
+            let mut streamed = Vec::new();
+            storage.reader(&cid).await?.unwrap().read_to_end(&mut streamed).await?;
+            assert_eq!(streamed, large);
+            let middle = CHUNK_SIZE - 5..CHUNK_SIZE + 5;
+            assert_eq!(storage.read_range(&cid, middle.start, 10).await?, Some(large[middle.start as usize..middle.end as usize].to_vec()));
+
+            // A copy with one byte changed adds one chunk, not three
+            let mut edited = large.clone();
+            edited[0] ^= 1;
+            storage.store(&edited).await?;
+            assert_eq!(storage.store.list("chunks/").await?.len(), 1 + 3 + 1);
+            assert_eq!(storage.list().await?.len(), 3);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn corruption_is_caught_and_unpinned_content_is_collected() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let storage = Storage::new(dir.path()).await?;
+        let content = |seed: u8| -> Vec<u8> { (0..CHUNK_SIZE as usize).map(|i| (i as u8).wrapping_add(seed)).collect() };
+
+        let pinned = storage.store(&content(1)).await?;
+        let old = storage.store(&content(2)).await?;
+        let recent = storage.store(&content(3)).await?;
+        assert_eq!(storage.pin(&pinned).await?, 1);
+        assert_eq!(storage.pin(&pinned).await?, 2);
+        assert_eq!(storage.unpin(&pinned).await?, 1);
+        // Reading marks content as used, so `old` is now the least recent
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        storage.retrieve(&recent).await?;
+
+        let report = storage.collect_garbage(2 * CHUNK_SIZE, None).await?;
+        assert_eq!(report.removed, vec![old.clone()]);
+        assert_eq!(storage.size(&old).await?, None);
+        assert!(storage.size(&pinned).await?.is_some() && storage.size(&recent).await?.is_some());
+        let usage = storage.usage().await?;
+        assert_eq!((usage.pinned, usage.cached, usage.orphaned), (CHUNK_SIZE, CHUNK_SIZE, 0));
+
+        // A replica is held apart from the pins: unpinning leaves it, clearing the factor frees it
+        storage.set_replication(&recent, 2).await?;
+        assert!(storage.unpin(&recent).await.is_err());
+        assert_eq!(storage.pin(&recent).await?, 1);
+        assert_eq!(storage.unpin(&recent).await?, 0);
+        assert_eq!(storage.collect_garbage(0, None).await?.removed, Vec::<Cid>::new());
+        assert_eq!(storage.usage().await?.pinned, 2 * CHUNK_SIZE);
+        storage.set_replication(&recent, 0).await?;
+        assert!(!storage.list().await?.iter().any(|e| e.held() && e.cid == recent));
+
+        // Flip a byte on disk: reads fail, the scrub finds it and repair drops it
+        let chunk = storage.manifest(&recent).await?.unwrap().chunks.remove(0);
+        let path = dir.path().join(shard_key("chunks", &chunk));
+        let mut data = std::fs::read(&path)?;
+        data[0] ^= 1;
+        std::fs::write(&path, data)?;
+        assert!(storage.retrieve(&recent).await.is_err());
+        let report = storage.verify(true).await?;
+        assert_eq!((report.corrupt, report.damaged), (vec![chunk], vec![recent.clone()]));
+        assert_eq!(storage.size(&recent).await?, None);
+        assert!(storage.verify(false).await?.corrupt.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn flat_layouts_are_sharded() -> Result<()> {
+        // A chunk left in the flat layout moves into its shard on open
+        let dir = tempfile::tempdir()?;
+        let data = b"flat".to_vec();
+        let hash = Cid::sha256(&data);
+        std::fs::create_dir_all(dir.path().join("chunks"))?;
+        std::fs::write(dir.path().join("chunks").join(hash.digest_hex()), &data)?;
+        let storage = Storage::new(dir.path()).await?;
+        assert_eq!(storage.chunk(&hash).await?, Some(data.clone()));
+        assert!(dir.path().join("chunks").join(&hash.digest_hex()[..2]).join(hash.to_string()).exists());
+
+        // So is a manifest filed under a root written as a plain SHA2-256 digest
+        let manifest = Manifest { size: data.len() as u64, chunk_size: CHUNK_SIZE, chunks: vec![hash] };
+        let old = manifest.cid().as_sha256();
+        std::fs::write(dir.path().join("manifests").join(old.to_string()), serde_json::to_vec(&manifest)?)?;
+        let storage = Storage::new(dir.path()).await?;
+        assert_eq!(storage.retrieve(&manifest.cid()).await?, Some(data));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stores_are_encrypted_in_place_and_keys_rotate() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let key = KeySource::Passphrase("correct horse".to_string());
+        let large: Vec<u8> = (0..(CHUNK_SIZE + 100_000) as usize).map(|i| (i % 251) as u8).collect();
+        let legacy: Vec<u8> = (0..200_000).map(|i| (i % 13) as u8).collect();
+        let legacy_cid = Cid::sha256(&legacy);
+        let cid = {
+            let storage = Storage::new(dir.path()).await?;
+            std::fs::write(dir.path().join(legacy_cid.digest_hex()), &legacy)?;
+            storage.store(&large).await?
+        };
+        assert!(Storage::open(fs_store(dir.path()).await?, Some(&key)).await.is_err(), "plaintext content needs migrating");
+
+        assert_eq!(Storage::encrypt(fs_store(dir.path()).await?, &key).await?, 3);
+        assert!(Storage::new(dir.path()).await.is_err());
+        assert!(Storage::open(fs_store(dir.path()).await?, Some(&KeySource::Passphrase("wrong".to_string()))).await.is_err());
+        let storage = Storage::open(fs_store(dir.path()).await?, Some(&key)).await?;
+        let first_chunk = storage.manifest(&cid).await?.unwrap().chunks[0].clone();
+        let on_disk = std::fs::read(dir.path().join(storage.chunk_key(&first_chunk)))?;
+        assert!(!on_disk.windows(64).any(|w| w == &large[..64]), "chunk stored in the clear");
+        assert!(storage.retrieve(&cid).await? == Some(large.clone()));
+        assert_eq!(storage.size(&legacy_cid).await?, Some(legacy.len() as u64));
+        assert!(storage.read_range(&legacy_cid, 65_530, 70_000).await? == Some(legacy[65_530..135_530].to_vec()));
+        assert!(storage.verify(false).await?.corrupt.is_empty());
+
+        // A new passphrase unlocks the re-sealed store and the old one stops working
+        let new_key = KeySource::Passphrase("battery staple".to_string());
+        assert_eq!(Storage::rotate_key(fs_store(dir.path()).await?, &key, Some(&new_key)).await?, 3);
+        assert!(Storage::open(fs_store(dir.path()).await?, Some(&key)).await.is_err());
+        let storage = Storage::open(fs_store(dir.path()).await?, Some(&new_key)).await?;
+        assert!(storage.retrieve(&legacy_cid).await? == Some(legacy));
+
+        // Tampering fails authentication instead of returning altered data
+        let path = dir.path().join(storage.chunk_key(&first_chunk));
+        let mut sealed = std::fs::read(&path)?;
+        sealed[100] ^= 1;
+        std::fs::write(&path, sealed)?;
+        assert!(storage.chunk(&first_chunk).await.is_err());
+        assert_eq!(storage.verify(false).await?.corrupt, vec![first_chunk.clone()]);
+
+        // A legacy blob with a damaged header is reported, not fatal to the scrub
+        let path = dir.path().join(storage.legacy_key(&legacy_cid));
+        let mut sealed = std::fs::read(&path)?;
+        sealed[0] ^= 1;
+        std::fs::write(&path, sealed)?;
+        let report = storage.verify(true).await?;
+        assert_eq!(report.corrupt.len(), 2);
+        assert!(report.corrupt.contains(&first_chunk) && report.corrupt.contains(&legacy_cid));
+        assert!(!path.exists());
+        Ok(())
     }
 }
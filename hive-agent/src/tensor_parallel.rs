@@ -0,0 +1,343 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use candle_core::{DType, Tensor};
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use libp2p::{request_response, PeerId, StreamProtocol};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Barrier, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use crate::inference::InferenceEngine;
+use crate::message::{Message, TaskError, TaskReply};
+use crate::model::sharded_llama::{AllReduce, TensorSplit};
+use crate::node::{send_task, NodeCommand};
+use crate::verification::TaskSpec;
+
+/// How a deployment spreads a model across peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ParallelMode {
+    /// Each peer runs a contiguous range of layers (`layer_range`)
+    Pipeline,
+    /// Each peer runs a slice of every layer's heads and FFN columns
+    Tensor,
+}
+
+/// One rank's partial output for an all-reduce step, as sent to the other
+/// ranks of the session over `PARTIAL_PROTOCOL`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TensorPartial {
+    pub session_id: String,
+    pub step: u64,
+    pub rank: usize,
+    pub data: Vec<f32>,
+}
+
+/// Largest partial a rank accepts. Partials carry hidden states, so long
+/// prompts on wide models run to megabytes.
+const MAX_PARTIAL_SIZE: u64 = 64 * 1024 * 1024;
+
+/// CBOR codec for `PARTIAL_PROTOCOL`. libp2p's own CBOR codec caps requests
+/// at 1 MiB, too small for hidden states. The response is an empty ack.
+#[derive(Debug, Clone, Default)]
+pub struct PartialCodec;
+
+#[async_trait]
+impl request_response::Codec for PartialCodec {
+    type Protocol = StreamProtocol;
+    type Request = TensorPartial;
+    type Response = ();
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> std::io::Result<TensorPartial>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut buf = Vec::new();
+        io.take(MAX_PARTIAL_SIZE).read_to_end(&mut buf).await?;
+        ciborium::from_reader(buf.as_slice()).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))
+    }
+
+    async fn read_response<T>(&mut self, _: &StreamProtocol, _: &mut T) -> std::io::Result<()>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        Ok(())
+    }
+
+    async fn write_request<T>(&mut self, _: &StreamProtocol, io: &mut T, partial: TensorPartial) -> std::io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let mut buf = Vec::new();
+        ciborium::into_writer(&partial, &mut buf).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+        io.write_all(&buf).await
+    }
+
+    async fn write_response<T>(&mut self, _: &StreamProtocol, _: &mut T, _: ()) -> std::io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        Ok(())
+    }
+}
+
+/// A partial result from another rank, routed here by the swarm loop.
+#[derive(Debug)]
+pub struct Partial {
+    pub from: PeerId, // Sender on the partial protocol; only the peer holding `rank` may send it
+    pub step: u64,
+    pub rank: usize,
+    pub data: Vec<f32>,
+}
+
+/// Open tensor-parallel sessions on this node, keyed by task id.
+pub type TpSessions = Arc<Mutex<HashMap<String, std::sync::mpsc::Sender<Partial>>>>;
+
+/// How long a rank waits for the partials of a step, and for a partial to be sent.
+pub const PARTIAL_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// All-reduce over the P2P link. Each partial is sent straight to the other
+/// ranks of the session, and theirs arrive through the session's channel.
+pub struct PeerAllReduce {
+    session_id: String,
+    split: TensorSplit,
+    ranks: Vec<PeerId>, // Who holds each rank, as named by the task
+    step: u64,
+    commands: mpsc::Sender<NodeCommand>,
+    inbound: std::sync::mpsc::Receiver<Partial>,
+    early: HashMap<(u64, usize), Vec<f32>>, // Partials for steps we have not reached yet
+}
+
+impl PeerAllReduce {
+    pub fn new(
+        session_id: String,
+        split: TensorSplit,
+        ranks: Vec<PeerId>,
+        commands: mpsc::Sender<NodeCommand>,
+        inbound: std::sync::mpsc::Receiver<Partial>,
+    ) -> Self {
+        Self {
+            session_id,
+            split,
+            ranks,
+            step: 0,
+            commands,
+            inbound,
+            early: HashMap::new(),
+        }
+    }
+}
+
+impl AllReduce for PeerAllReduce {
+    fn all_reduce(&mut self, partial: &Tensor) -> candle_core::Result<Tensor> {
+        self.step += 1;
+        let step = self.step;
+        let local = partial.to_dtype(DType::F32)?.flatten_all()?.to_vec1::<f32>()?;
+
+        for (rank, peer) in self.ranks.iter().enumerate() {
+            if rank == self.split.rank {
+                continue;
+            }
+            let partial = TensorPartial {
+                session_id: self.session_id.clone(),
+                step,
+                rank: self.split.rank,
+                data: local.clone(),
+            };
+            if self.commands.blocking_send(NodeCommand::Partial { peer: *peer, partial }).is_err() {
+                candle_core::bail!("P2P loop closed during all-reduce");
+            }
+        }
+
+        let mut slots: Vec<Option<Vec<f32>>> = vec![None; self.split.world_size];
+        slots[self.split.rank] = Some(local);
+        for (rank, slot) in slots.iter_mut().enumerate() {
+            if slot.is_none() {
+                *slot = self.early.remove(&(step, rank));
+            }
+        }
+
+        while slots.iter().any(|s| s.is_none()) {
+            let p = match self.inbound.recv_timeout(PARTIAL_TIMEOUT) {
+                Ok(p) => p,
+                Err(_) => candle_core::bail!(
+                    "timed out waiting for partials of step {step} in session {}",
+                    self.session_id
+                ),
+            };
+            // Anyone who learns the session id could otherwise corrupt the sum
+            if p.rank >= self.split.world_size || self.ranks.get(p.rank) != Some(&p.from) {
+                continue;
+            }
+            if p.step == step {
+                slots[p.rank] = Some(p.data);
+            } else if p.step > step {
+                self.early.insert((p.step, p.rank), p.data);
+            }
+        }
+
+        // Sum in rank order so that every rank ends up with bit-identical results.
+        let mut sum = vec![0f32; slots[0].as_ref().map(|s| s.len()).unwrap_or(0)];
+        for slot in slots.into_iter().flatten() {
+            if slot.len() != sum.len() {
+                candle_core::bail!("partial size mismatch in session {}", self.session_id);
+            }
+            for (acc, v) in sum.iter_mut().zip(slot) {
+                *acc += v;
+            }
+        }
+        Tensor::from_vec(sum, partial.shape(), partial.device())?.to_dtype(partial.dtype())
+    }
+}
+
+struct LocalShared {
+    barrier: Barrier,
+    slots: Mutex<Vec<Option<Tensor>>>,
+}
+
+/// In-process all-reduce between threads. Used to validate a tensor-parallel
+/// split against the single-node model without any networking.
+pub struct LocalAllReduce {
+    rank: usize,
+    shared: Arc<LocalShared>,
+}
+
+impl LocalAllReduce {
+    pub fn group(world_size: usize) -> Vec<Self> {
+        let shared = Arc::new(LocalShared {
+            barrier: Barrier::new(world_size),
+            slots: Mutex::new(vec![None; world_size]),
+        });
+        (0..world_size)
+            .map(|rank| Self { rank, shared: shared.clone() })
+            .collect()
+    }
+}
+
+impl AllReduce for LocalAllReduce {
+    fn all_reduce(&mut self, partial: &Tensor) -> candle_core::Result<Tensor> {
+        self.shared.slots.lock().unwrap()[self.rank] = Some(partial.clone());
+        self.shared.barrier.wait();
+        let sum = {
+            let slots = self.shared.slots.lock().unwrap();
+            let mut acc = slots[0].clone().unwrap();
+            for t in slots.iter().skip(1).flatten() {
+                acc = (acc + t)?;
+            }
+            acc
+        };
+        // Nobody may overwrite a slot before every rank has read it.
+        self.shared.barrier.wait();
+        Ok(sum)
+    }
+}
+
+/// Loads the model once whole and once split across `world_size` in-process
+/// ranks, and returns the largest absolute difference between their
+/// next-token logits for `prompt`.
+pub fn validate(model_path: &str, tokenizer_path: &str, prompt: &str, world_size: usize) -> Result<f32> {
+    let reference = InferenceEngine::load(model_path, tokenizer_path, None, None)?.prompt_logits(prompt)?;
+
+    // Load every rank up front so a bad split fails here instead of leaving
+    // the other ranks blocked on the barrier.
+    let mut engines = Vec::with_capacity(world_size);
+    for (rank, reducer) in LocalAllReduce::group(world_size).into_iter().enumerate() {
+        let split = TensorSplit { rank, world_size };
+        let mut engine = InferenceEngine::load(model_path, tokenizer_path, None, Some(split))?;
+        engine.set_all_reduce(Box::new(reducer));
+        engines.push(engine);
+    }
+
+    let handles: Vec<_> = engines
+        .into_iter()
+        .map(|mut engine| {
+            let prompt = prompt.to_string();
+            std::thread::spawn(move || engine.prompt_logits(&prompt))
+        })
+        .collect();
+
+    let mut outputs = Vec::with_capacity(world_size);
+    for handle in handles {
+        outputs.push(handle.join().map_err(|_| anyhow!("tensor-parallel rank panicked"))??);
+    }
+
+    if outputs.iter().any(|o| o != &outputs[0]) {
+        return Err(anyhow!("tensor-parallel ranks disagree with each other"));
+    }
+    max_abs_diff(&reference, &outputs[0])
+}
+
+pub fn max_abs_diff(a: &[f32], b: &[f32]) -> Result<f32> {
+    if a.len() != b.len() {
+        return Err(anyhow!("logit length mismatch: {} vs {}", a.len(), b.len()));
+    }
+    Ok(a.iter().zip(b).map(|(x, y)| (x - y).abs()).fold(0f32, f32::max))
+}
+
+/// Loads this node's slice of the model and runs its part of a
+/// tensor-parallel generation. Every rank samples the same tokens because
+/// the reduced logits and the sampler seed are identical on all of them.
+pub fn run_rank(
+    model_path: &str,
+    tokenizer_path: &str,
+    prompt: &str,
+    seed: u64,
+    split: TensorSplit,
+    reducer: PeerAllReduce,
+) -> Result<String> {
+    let mut engine = InferenceEngine::load(model_path, tokenizer_path, None, Some(split))?;
+    engine.set_all_reduce(Box::new(reducer));
+    engine.generate_seeded(prompt, 50, seed)
+}
+
+/// Sends a tensor-parallel task to every rank, in rank order, and returns the
+/// output of rank 0. All ranks produce the same text, so any rank's error
+/// fails the whole task.
+pub async fn dispatch(commands: &mpsc::Sender<NodeCommand>, ranks: &[PeerId], task_id: String, spec: TaskSpec) -> TaskReply {
+    let tp_ranks: Vec<String> = ranks.iter().map(|p| p.to_string()).collect();
+    let requests = ranks.iter().map(|peer| {
+        let msg = Message::TaskRequest {
+            task_id: task_id.clone(),
+            prompt: spec.prompt.clone(),
+            model_name: spec.model_name.clone(),
+            download_url: spec.download_url.clone(),
+            model: spec.model.clone(),
+            layer_range: None,
+            tp_ranks: Some(tp_ranks.clone()),
+            seed: None,
+        };
+        send_task(commands, *peer, msg)
+    });
+    let replies = futures::future::join_all(requests).await;
+    let mut replies = replies.into_iter();
+    let Some(first) = replies.next() else {
+        return Err(TaskError::Failed("Tensor-parallel task needs at least one rank".to_string()));
+    };
+    match replies.find(|r| r.is_err()) {
+        Some(err) if first.is_ok() => err,
+        _ => first,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+
+    #[test]
+    fn partials_from_other_peers_are_ignored() {
+        let (a, b, mallory) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let (commands, _sent) = mpsc::channel(4);
+        let (inbound_tx, inbound) = std::sync::mpsc::channel();
+        let split = TensorSplit { rank: 0, world_size: 2 };
+        let mut reducer = PeerAllReduce::new("session".to_string(), split, vec![a, b], commands, inbound);
+
+        // Claims rank 1 but is not the peer holding it
+        inbound_tx.send(Partial { from: mallory, step: 1, rank: 1, data: vec![100.0] }).unwrap();
+        inbound_tx.send(Partial { from: b, step: 1, rank: 1, data: vec![1.0] }).unwrap();
+
+        let local = Tensor::new(&[2f32], &Device::Cpu).unwrap();
+        let sum = reducer.all_reduce(&local).unwrap().to_vec1::<f32>().unwrap();
+        assert_eq!(sum, vec![3.0]);
+    }
+}
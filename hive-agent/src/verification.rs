@@ -0,0 +1,154 @@
+use libp2p::PeerId;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::info;
+use crate::inference::DEFAULT_SEED;
+use crate::message::{Message, TaskError};
+use crate::node::{send_task, NodeCommand};
+use crate::registry::ModelRef;
+use crate::scheduler::Scheduler;
+
+/// What to do when the replicas of a verified task disagree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum MismatchPolicy {
+    /// Fail the request
+    Fail,
+    /// Run the task again on other trusted peers
+    Retry,
+    /// Accept the output a strict majority of replicas agree on
+    Majority,
+}
+
+/// Opt-in redundant execution for untrusted workers.
+#[derive(Debug, Clone, Copy)]
+pub struct VerificationConfig {
+    pub replicas: usize,
+    pub policy: MismatchPolicy,
+    pub max_attempts: usize,
+    pub timeout: Duration,
+}
+
+impl Default for VerificationConfig {
+    fn default() -> Self {
+        Self {
+            replicas: 2,
+            policy: MismatchPolicy::Retry,
+            max_attempts: 3,
+            timeout: Duration::from_secs(1200),
+        }
+    }
+}
+
+/// The parts of a `TaskRequest` that are the same for every replica.
+#[derive(Debug, Clone)]
+pub struct TaskSpec {
+    pub prompt: String,
+    pub model_name: String,
+    pub download_url: Option<String>,
+    pub model: Option<ModelRef>,
+}
+
+/// Groups replica outputs and returns the groups, largest first.
+fn group_results(outputs: &[(PeerId, String)]) -> Vec<(String, Vec<PeerId>)> {
+    let mut groups: Vec<(String, Vec<PeerId>)> = Vec::new();
+    for (peer, output) in outputs {
+        match groups.iter_mut().find(|(o, _)| o == output) {
+            Some((_, peers)) => peers.push(*peer),
+            None => groups.push((output.clone(), vec![*peer])),
+        }
+    }
+    groups.sort_by(|a, b| b.1.len().cmp(&a.1.len()));
+    groups
+}
+
+/// Sends the task to `config.replicas` distinct peers with the same seed and
+/// compares their outputs. Only outputs are compared: a replica that fails,
+/// is busy or does not answer is replaced by another peer and is not held
+/// against anyone. When a strict majority agrees, the peers outside it are
+/// flagged in the scheduler and those inside it lose a flag; peers with too
+/// many flags are not picked for verified tasks. A tie flags nobody, since
+/// it does not tell who is wrong.
+pub async fn run_verified(
+    scheduler: &Arc<Mutex<Scheduler>>,
+    commands: &mpsc::Sender<NodeCommand>,
+    task_id: &str,
+    spec: TaskSpec,
+    config: VerificationConfig,
+) -> Result<String, String> {
+    if config.replicas == 0 {
+        return Err("Verification needs at least one replica".to_string());
+    }
+    let mut used = Vec::new();
+    let mut sent = 0;
+
+    for attempt in 0..config.max_attempts.max(1) {
+        let mut outputs: Vec<(PeerId, String)> = Vec::new();
+        while outputs.len() < config.replicas {
+            let peers = scheduler.lock().unwrap().get_trusted_peers(config.replicas - outputs.len(), &used);
+            if peers.is_empty() {
+                return Err(format!(
+                    "Verification needs {} trusted peers that answer, only {} did",
+                    config.replicas,
+                    outputs.len()
+                ));
+            }
+            used.extend(peers.iter().cloned());
+            info!("Verified task {} attempt {} on {:?}", task_id, attempt, peers);
+
+            let requests = peers.iter().map(|peer| {
+                let replica_id = format!("{}-r{}", task_id, sent);
+                sent += 1;
+                let msg = Message::TaskRequest {
+                    task_id: replica_id.clone(),
+                    prompt: spec.prompt.clone(),
+                    model_name: spec.model_name.clone(),
+                    download_url: spec.download_url.clone(),
+                    model: spec.model.clone(),
+                    layer_range: None,
+                    tp_ranks: None,
+                    seed: Some(DEFAULT_SEED),
+                };
+                async move {
+                    let result = match tokio::time::timeout(config.timeout, send_task(commands, *peer, msg)).await {
+                        Ok(reply) => reply,
+                        Err(_) => Err(TaskError::Timeout),
+                    };
+                    (*peer, replica_id, result)
+                }
+            });
+            for (peer, replica_id, result) in futures::future::join_all(requests).await {
+                match result {
+                    Ok(output) => outputs.push((peer, output)),
+                    Err(e) => info!("Replica {} on {} gave no output, asking another peer: {}", replica_id, peer, e),
+                }
+            }
+        }
+
+        let groups = group_results(&outputs);
+        // Only a strict majority of several replicas tells us who is wrong
+        let majority = outputs.len() > 1 && groups[0].1.len() * 2 > outputs.len();
+        if majority {
+            let mut scheduler = scheduler.lock().unwrap();
+            for (peer, _) in &outputs {
+                if groups[0].1.contains(peer) {
+                    scheduler.record_agreement(peer);
+                } else {
+                    info!("Flagging peer {} for disagreeing on task {}", peer, task_id);
+                    scheduler.flag_peer(peer);
+                }
+            }
+        }
+        if groups.len() == 1 {
+            return Ok(groups.into_iter().next().unwrap().0);
+        }
+
+        match config.policy {
+            MismatchPolicy::Majority if majority => return Ok(groups[0].0.clone()),
+            MismatchPolicy::Retry => continue,
+            _ => return Err(format!("Replicas disagreed on task {}", task_id)),
+        }
+    }
+
+    Err(format!("Replicas disagreed on task {} after {} attempts", task_id, config.max_attempts))
+}
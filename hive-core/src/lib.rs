@@ -1,31 +1,303 @@
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct NodeCapability {
     pub device_type: String, // "mobile", "gpu_server"
     pub available_vram: u64,
     pub flops_score: f32,
     pub can_run_docker: bool,
+    pub cpu_cores: usize,
+    pub total_ram: u64, // Bytes
+    pub available_ram: u64, // Bytes
+    pub memory_limit: Option<u64>, // cgroup memory limit in bytes, if any
+    pub cpu_limit: Option<f32>, // cgroup CPU quota in cores, if any
+    pub accelerators: Vec<String>, // Compiled-in backends, e.g. "cuda", "mkl"
+    pub models: Vec<String>, // Model files on disk
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TaskPayload {
     pub task_id: String,
-    pub model_shard_cid: String,
+    pub model_shard_cid: Cid,
     // Add more fields as needed
 }
 
+/// Multihash code of SHA2-256, for digests of raw data such as chunks.
+pub const SHA2_256: u8 = 0x12;
+
+/// Code for the Merkle roots of chunked content (see `merkle_root`), also
+/// over SHA2-256. It is our own and unassigned in the multicodec table, so
+/// that a root never reads as the plain digest of some data.
+pub const MERKLE_SHA2_256: u8 = 0x6d;
+
+/// Content identifier: a multihash, written as the hex of
+/// `<function code><digest length><digest>`. SHA-256 CIDs therefore read
+/// `1220` followed by the digest and Merkle roots `6d20`, and a new hash
+/// function gets a new prefix instead of colliding with old identifiers.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cid {
+    code: u8,
+    digest: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidCid(pub String);
+
+impl fmt::Display for InvalidCid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid CID: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidCid {}
+
+impl Cid {
+    /// The CID of `data` under SHA2-256.
+    pub fn sha256(data: &[u8]) -> Self {
+        Self { code: SHA2_256, digest: Sha256::digest(data).to_vec() }
+    }
+
+    /// True if `data` hashes to this CID; for a Merkle root, once split
+    /// into chunks.
+    pub fn matches(&self, data: &[u8]) -> bool {
+        match self.code {
+            SHA2_256 => Sha256::digest(data).as_slice() == self.digest,
+            MERKLE_SHA2_256 => {
+                let mut chunks: Vec<Cid> = data.chunks(CHUNK_SIZE as usize).map(Cid::sha256).collect();
+                if chunks.is_empty() {
+                    chunks.push(Cid::sha256(b"")); // Empty content is a single empty chunk
+                }
+                merkle_root(&chunks) == *self
+            }
+            _ => false,
+        }
+    }
+
+    /// The same digest under the plain SHA2-256 code, which Merkle roots
+    /// were written with before they had their own.
+    pub fn as_sha256(&self) -> Self {
+        Self { code: SHA2_256, digest: self.digest.clone() }
+    }
+
+    pub fn code(&self) -> u8 {
+        self.code
+    }
+
+    pub fn digest(&self) -> &[u8] {
+        &self.digest
+    }
+
+    /// The digest in hex, without the multihash prefix.
+    pub fn digest_hex(&self) -> String {
+        hex::encode(&self.digest)
+    }
+
+    /// A few digest characters, enough to tell CIDs apart in file names and logs.
+    pub fn short(&self) -> String {
+        hex::encode(&self.digest[..4])
+    }
+}
+
+impl FromStr for Cid {
+    type Err = InvalidCid;
+
+    /// Accepts the prefixed form, and bare 64-digit hex from before CIDs had
+    /// a prefix, which is read as SHA2-256.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |why: &str| InvalidCid(format!("{:?} {}", s.chars().take(80).collect::<String>(), why));
+        let bytes = hex::decode(s).map_err(|_| invalid("is not hex"))?;
+        if bytes.len() == 32 && s.len() == 64 {
+            return Ok(Self { code: SHA2_256, digest: bytes });
+        }
+        let [code, length, digest @ ..] = bytes.as_slice() else {
+            return Err(invalid("is too short"));
+        };
+        if *code != SHA2_256 && *code != MERKLE_SHA2_256 {
+            return Err(invalid("uses an unsupported hash function"));
+        }
+        if *length as usize != digest.len() || digest.len() != 32 {
+            return Err(invalid("has the wrong digest length"));
+        }
+        Ok(Self { code: *code, digest: digest.to_vec() })
+    }
+}
+
+impl TryFrom<String> for Cid {
+    type Error = InvalidCid;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Cid> for String {
+    fn from(cid: Cid) -> Self {
+        cid.to_string()
+    }
+}
+
+impl fmt::Display for Cid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x}{:02x}{}", self.code, self.digest.len(), hex::encode(&self.digest))
+    }
+}
+
+/// A piece of stored content, addressed by the CID of its data.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Chunk {
     pub data: Vec<u8>,
-    pub hash: String,
+    pub hash: Cid,
+}
+
+impl Chunk {
+    pub fn new(data: Vec<u8>) -> Self {
+        let hash = Cid::sha256(&data);
+        Self { data, hash }
+    }
+
+    /// True if `hash` matches `data`; chunks from peers are checked before use.
+    pub fn verify(&self) -> bool {
+        self.hash.matches(&self.data)
+    }
+}
+
+/// Content is split into chunks of this size. Changing it changes the CIDs of
+/// anything larger than one chunk.
+pub const CHUNK_SIZE: u64 = 256 * 1024;
+
+/// How a piece of content was split into chunks. The content's CID is the
+/// Merkle root of the chunk hashes, so it can be checked chunk by chunk.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub size: u64, // Bytes of content
+    pub chunk_size: u64, // Every chunk but the last has this size
+    pub chunks: Vec<Cid>, // Chunk hashes in order
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidManifest(pub String);
+
+impl fmt::Display for InvalidManifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid manifest: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidManifest {}
+
+impl Manifest {
+    pub fn cid(&self) -> Cid {
+        merkle_root(&self.chunks)
+    }
+
+    /// Checks that the manifest splits its content the way every node does:
+    /// the CID covers the chunk hashes only, so the layout must be fixed.
+    /// Empty content is a single empty chunk.
+    pub fn validate(&self) -> Result<(), InvalidManifest> {
+        if self.chunk_size != CHUNK_SIZE {
+            return Err(InvalidManifest(format!("chunk size {} is not {}", self.chunk_size, CHUNK_SIZE)));
+        }
+        let expected = self.size.div_ceil(CHUNK_SIZE).max(1);
+        if self.chunks.len() as u64 != expected {
+            return Err(InvalidManifest(format!("{} bytes take {} chunks, not {}", self.size, expected, self.chunks.len())));
+        }
+        Ok(())
+    }
+}
+
+/// Root of a binary SHA-256 tree over the chunk digests; an odd node is
+/// carried up unchanged. Leaves are hashed behind a 0x00 byte and inner
+/// nodes behind 0x01, so no node can pass for a leaf or for another tree's
+/// root (without this, the content `a || b` would share its CID with the
+/// two chunks `a` and `b`).
+pub fn merkle_root(leaves: &[Cid]) -> Cid {
+    let mut level: Vec<Vec<u8>> = leaves
+        .iter()
+        .map(|cid| Sha256::new().chain_update([0x00]).chain_update(&cid.digest).finalize().to_vec())
+        .collect();
+    if level.is_empty() {
+        return Cid { code: MERKLE_SHA2_256, digest: Sha256::digest(b"").to_vec() };
+    }
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => Sha256::new().chain_update([0x01]).chain_update(left).chain_update(right).finalize().to_vec(),
+                [single] => single.clone(),
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+    Cid { code: MERKLE_SHA2_256, digest: level.swap_remove(0) }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ComputeTask {
+    /// Multiplies two stored matrices. The worker stores the product and
+    /// answers with its CID, which only the content can determine.
     MatrixMul {
-        matrix_a_cid: String,
-        matrix_b_cid: String,
-        result_cid: String, // Where to store the result
+        matrix_a_cid: Cid,
+        matrix_b_cid: Cid,
     },
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cids_parse_strictly() {
+        let digest = "ab".repeat(32);
+        let cid: Cid = format!("1220{}", digest).parse().unwrap();
+        assert_eq!(digest.parse::<Cid>().unwrap(), cid, "bare digests are read as SHA2-256");
+        assert_eq!(cid.to_string(), format!("1220{}", digest));
+        assert_eq!(cid.short(), "abababab");
+        for bad in ["", "abc", "../../etc/passwd", &digest[..62], &format!("1320{}", digest), &format!("1210{}", digest)] {
+            assert!(bad.parse::<Cid>().is_err(), "{:?} parsed", bad);
+        }
+        let root: Cid = format!("6d20{}", digest).parse().unwrap();
+        assert_eq!(root.code(), MERKLE_SHA2_256);
+        assert_ne!(root, cid);
+        assert_eq!(root.as_sha256(), cid);
+        let data = b"hello";
+        assert!(Cid::sha256(data).matches(data));
+        assert!(!Cid::sha256(data).matches(b"hellp"));
+        let large: Vec<u8> = (0..CHUNK_SIZE + 10).map(|i| i as u8).collect();
+        let root = merkle_root(&large.chunks(CHUNK_SIZE as usize).map(Cid::sha256).collect::<Vec<_>>());
+        assert!(root.matches(&large) && !root.matches(&large[1..]));
+        assert!(merkle_root(&[Cid::sha256(b"")]).matches(b""));
+    }
+
+    #[test]
+    fn merkle_roots_cannot_be_forged() {
+        let (a, b, c) = (Cid::sha256(b"a"), Cid::sha256(b"b"), Cid::sha256(b"c"));
+        let concat = [a.digest(), b.digest()].concat();
+        // An inner node must not pass for a leaf or a leaf for a one-chunk root
+        assert_ne!(Cid::sha256(&concat), merkle_root(&[a.clone(), b.clone()]));
+        assert_ne!(merkle_root(&[Cid::sha256(&concat)]), merkle_root(&[a.clone(), b.clone()]));
+        assert_ne!(merkle_root(std::slice::from_ref(&a)), a);
+        // Nor a root for a raw digest
+        assert_eq!(merkle_root(&[a.clone(), b.clone()]).code(), MERKLE_SHA2_256);
+        assert_ne!(merkle_root(&[merkle_root(&[a.clone(), b.clone()]), c.clone()]), merkle_root(&[a.clone(), b.clone(), c.clone()]));
+        assert_ne!(merkle_root(&[a.clone(), b.clone()]), merkle_root(&[b.clone(), a.clone()]));
+        assert_eq!(merkle_root(&[a.clone(), b.clone(), c.clone()]), merkle_root(&[a, b, c]));
+    }
+
+    #[test]
+    fn manifests_must_use_the_standard_layout() {
+        let chunks = |n| (0..n).map(|i: u8| Cid::sha256(&[i])).collect::<Vec<_>>();
+        let manifest = |size, chunk_size, n| Manifest { size, chunk_size, chunks: chunks(n) };
+        assert!(manifest(0, CHUNK_SIZE, 1).validate().is_ok());
+        assert!(manifest(CHUNK_SIZE, CHUNK_SIZE, 1).validate().is_ok());
+        assert!(manifest(CHUNK_SIZE + 1, CHUNK_SIZE, 2).validate().is_ok());
+        assert!(manifest(0, CHUNK_SIZE, 0).validate().is_err());
+        assert!(manifest(CHUNK_SIZE + 1, CHUNK_SIZE, 1).validate().is_err());
+        assert!(manifest(10, CHUNK_SIZE, 2).validate().is_err());
+        assert!(manifest(10, 5, 2).validate().is_err());
+        assert_eq!(manifest(10, CHUNK_SIZE, 1).cid(), merkle_root(&chunks(1)));
+    }
+}
@@ -21,11 +21,41 @@ import {
 interface Peer {
     id: string;
     address: string;
-    role: 'Queen' | 'Drone';
-    latency: number;
+    role: string | null; // Device type, e.g. 'gpu_server'
+    latency: number | null;
+    gossip_score: number | null;
     status: 'active' | 'syncing' | 'computing';
+    agent_version: string | null;
+    protocols: string[];
+    observed_addr: string | null;
 }
 
+interface ModelEntry {
+    cid: string;
+    file_name: string;
+    size: number;
+    tokenizer: string | null;
+    aliases: string[];
+    has_tokenizer: boolean;
+    metadata: {
+        format: string;
+        architecture: string | null;
+        parameters: number | null;
+        quantization: string | null;
+        context_length: number | null;
+        layers: number | null;
+        embedded_tokenizer: boolean;
+    };
+}
+
+const formatBytes = (bytes: number) => {
+    if (bytes >= 1024 ** 3) return `${(bytes / 1024 ** 3).toFixed(1)} GB`;
+    if (bytes >= 1024 ** 2) return `${(bytes / 1024 ** 2).toFixed(1)} MB`;
+    return `${(bytes / 1024).toFixed(1)} KB`;
+};
+
+const modelLabel = (m: ModelEntry) => m.aliases[0] ?? m.file_name;
+
 interface Metrics {
     cpu_usage: number;
     total_mem: number;
@@ -45,15 +75,16 @@ const HiveDashboard = () => {
     const [nodeId] = useState<string>('12D3Koo...8jL2');
     const [peers, setPeers] = useState<Peer[]>([]); // Start empty, wait for sync
 
-    // Real state for selected model
+    // Selected model, by CID
     const [selectedModel, setSelectedModel] = useState<string | null>(null);
-    const [availableModels, setAvailableModels] = useState<string[]>([]);
+    const [availableModels, setAvailableModels] = useState<ModelEntry[]>([]);
 
     // Derived state for the UI's "activeModel" object
-    const activeModel = selectedModel ? {
-        name: selectedModel,
-        cid: "Qm" + btoa(selectedModel).substring(0, 16), // Mock CID based on filename
-        size: "Unknown"
+    const selectedEntry = availableModels.find(m => m.cid === selectedModel);
+    const activeModel = selectedEntry ? {
+        name: modelLabel(selectedEntry),
+        cid: selectedEntry.cid,
+        size: formatBytes(selectedEntry.size)
     } : null;
 
     const [uploadState, setUploadState] = useState<'idle' | 'uploading' | 'hashing' | 'complete'>('idle');
@@ -168,9 +199,10 @@ const HiveDashboard = () => {
                 setUploadState('hashing'); // Visual step
                 setTimeout(() => {
                     setUploadState('complete');
-                    setSelectedModel(data.filename);
-                    setAvailableModels(prev => [...prev, data.filename]);
-                    addLog(`[CAS] Upload Complete. Indexed as ${data.filename}`);
+                    const model: ModelEntry = { ...data.model, has_tokenizer: data.model.metadata.embedded_tokenizer || !!data.model.tokenizer };
+                    setAvailableModels(prev => [...prev.filter(m => m.cid !== model.cid), model]);
+                    setSelectedModel(data.cid);
+                    addLog(`[CAS] Upload Complete. Indexed as ${data.cid}`);
                     setChatHistory(prev => [...prev, {
                         id: Date.now().toString(),
                         role: 'system',
@@ -424,6 +456,10 @@ const HiveDashboard = () => {
                                             <div>
                                                 <div className="text-sm font-medium text-slate-300 max-w-[150px] truncate" title={peer.id}>{peer.id}</div>
                                                 <div className="text-[10px] text-slate-500 font-mono">{peer.address}</div>
+                                                <div className="text-[10px] text-slate-500 font-mono">
+                                                    {peer.latency !== null ? `${peer.latency.toFixed(1)} ms` : '-- ms'}
+                                                    {peer.agent_version && ` · ${peer.agent_version}`}
+                                                </div>
                                             </div>
                                         </div>
                                         {peer.status === 'computing' && (
@@ -456,7 +492,7 @@ const HiveDashboard = () => {
                             >
                                 <option value="">Select Model...</option>
                                 {availableModels.map(m => (
-                                    <option key={m} value={m}>{m}</option>
+                                    <option key={m.cid} value={m.cid}>{modelLabel(m)}</option>
                                 ))}
                             </select>
 